@@ -0,0 +1,229 @@
+//! Processing of documents with an asynchronous `ExecutionContext`, for use in services built on
+//! async runtimes such as tokio, where blocking a thread on every fragment request isn't
+//! acceptable. Enabled by the `async` feature.
+//!
+//! The executor itself is synchronous, so documents are executed in rounds. Each round executes
+//! the document with the responses received so far, and stops at the first fragment that hasn't
+//! been fetched yet. The fragments that were requested in that round are then fetched
+//! concurrently, and the next round starts. Independent includes are requested together, so most
+//! documents only take a round or two.
+
+use crate::{transform_esi_string_with_config, Configuration, ExecutionContext, ExecutionError, PendingRequest};
+use crate::{cache, Request, RequestKey, Response, Result, Variables};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+/// Handles requests to backends without blocking, as an alternative to `ExecutionContext`. Use
+/// `DynAsyncExecutionContext` to choose the context at runtime.
+pub trait AsyncExecutionContext {
+    /// Sends a request to the given URL and returns either an error or the response.
+    fn send_request(&self, req: Request) -> impl Future<Output = Result<Response>> + Send;
+}
+
+/// An object-safe form of `AsyncExecutionContext`, whose futures are boxed, so that the context
+/// can be chosen at runtime as a `Box<dyn DynAsyncExecutionContext>`. It is implemented for every
+/// `AsyncExecutionContext`, and `dyn DynAsyncExecutionContext` implements `AsyncExecutionContext`
+/// in turn.
+pub trait DynAsyncExecutionContext {
+    /// Sends a request to the given URL and returns either an error or the response.
+    fn send_request_boxed(&self, req: Request) -> BoxedResponse<'_>;
+}
+
+/// The boxed future of a `DynAsyncExecutionContext`.
+pub type BoxedResponse<'a> = Pin<Box<dyn Future<Output = Result<Response>> + Send + 'a>>;
+
+impl<C: AsyncExecutionContext> DynAsyncExecutionContext for C {
+    fn send_request_boxed(&self, req: Request) -> BoxedResponse<'_> {
+        Box::pin(self.send_request(req))
+    }
+}
+
+impl AsyncExecutionContext for dyn DynAsyncExecutionContext + '_ {
+    fn send_request(&self, req: Request) -> impl Future<Output = Result<Response>> + Send {
+        self.send_request_boxed(req)
+    }
+}
+
+impl AsyncExecutionContext for dyn DynAsyncExecutionContext + Send + Sync + '_ {
+    fn send_request(&self, req: Request) -> impl Future<Output = Result<Response>> + Send {
+        self.send_request_boxed(req)
+    }
+}
+
+impl<C: AsyncExecutionContext + ?Sized> AsyncExecutionContext for &C {
+    fn send_request(&self, req: Request) -> impl Future<Output = Result<Response>> + Send {
+        (**self).send_request(req)
+    }
+}
+
+impl<C: AsyncExecutionContext + ?Sized> AsyncExecutionContext for Box<C> {
+    fn send_request(&self, req: Request) -> impl Future<Output = Result<Response>> + Send {
+        (**self).send_request(req)
+    }
+}
+
+impl<C: AsyncExecutionContext + ?Sized> AsyncExecutionContext for std::sync::Arc<C> {
+    fn send_request(&self, req: Request) -> impl Future<Output = Result<Response>> + Send {
+        (**self).send_request(req)
+    }
+}
+
+/// Processes a given ESI response body using an asynchronous `AsyncExecutionContext`, and returns
+/// the transformed body after all ESI instructions have been executed.
+///
+/// Requests are retried according to the configured `RetryPolicy`, but without its backoff, as
+/// waiting would depend on the async runtime. Stale fragments from the fragment cache are
+/// refreshed once the document has been processed, before it is returned. The configured time limit applies to the document
+/// as a whole, but requests that are in flight aren't abandoned when it passes.
+pub async fn transform_esi_string_async(
+    body: &[u8],
+    client: &(impl AsyncExecutionContext + ?Sized),
+    variables: &Variables,
+    config: &Configuration,
+) -> Result<Vec<u8>> {
+    // Retries are made here, as the executor would block the thread during the backoff
+    let retry = config.retry.clone();
+    let mut config = Configuration {
+        retry: None,
+        ..config.clone()
+    };
+    let time_limit = config.time_limit;
+    let started = Instant::now();
+    let mut responses = HashMap::new();
+
+    loop {
+        // Each round only has the time that previous rounds left over
+        config.time_limit = time_limit.map(|time_limit| time_limit.saturating_sub(started.elapsed()));
+        let config = &config;
+        let replay = Replay {
+            responses: &responses,
+            missing: RefCell::new(Vec::new()),
+        };
+        match transform_esi_string_with_config(body, &replay, variables, config) {
+            Err(err) if err.is_suspended() => {}
+            // The requests that refresh stale responses from the fragment cache weren't waited for
+            Ok(output) => {
+                refresh(client, replay.missing.into_inner(), config).await;
+                return Ok(output);
+            }
+            Err(err) => return Err(err),
+        }
+
+        let missing = replay.missing.into_inner();
+        let mut results = join_all(missing.iter().map(|req| client.send_request(req.clone()))).await;
+        if let Some(policy) = &retry {
+            for attempt in 1..policy.max_attempts {
+                let failed: Vec<usize> = (0..results.len()).filter(|&i| policy.retries(&results[i])).collect();
+                if failed.is_empty() {
+                    break;
+                }
+                for &i in &failed {
+                    config.observer.0.include_retried(&missing[i].url, attempt);
+                }
+                let retried = join_all(failed.iter().map(|&i| client.send_request(missing[i].clone()))).await;
+                for (i, result) in failed.into_iter().zip(retried) {
+                    results[i] = result;
+                }
+            }
+        }
+        for (req, result) in missing.iter().zip(results) {
+            responses.insert(RequestKey::from(req), result);
+        }
+    }
+}
+
+// Sends the requests whose responses are stale in the fragment cache, out of those that the last
+// round didn't wait for, and stores their responses in the cache.
+async fn refresh(client: &(impl AsyncExecutionContext + ?Sized), requests: Vec<Request>, config: &Configuration) {
+    let cache = match &config.cache {
+        Some(cache) => cache,
+        None => return,
+    };
+
+    let stale = |req: &Request| cache::cache_key(req).and_then(|key| cache.0.get(&key)).is_some_and(|cached| cached.stale);
+    let requests: Vec<Request> = requests.into_iter().filter(stale).collect();
+    let results = join_all(requests.iter().map(|req| client.send_request(req.clone()))).await;
+    for (req, result) in requests.iter().zip(results) {
+        if let Ok(resp) = result.and_then(|resp| config.hooks.after_response(req, resp)) {
+            if let (Some(key), Some(freshness)) = (cache::cache_key(req), cache::freshness(&resp)) {
+                cache.0.put(&key, &resp, freshness);
+            }
+        }
+    }
+}
+
+// Answers requests from the responses of previous rounds, and suspends execution at requests that
+// haven't been sent yet, recording them to be sent before the next round.
+struct Replay<'a> {
+    responses: &'a HashMap<RequestKey, Result<Response>>,
+    missing: RefCell<Vec<Request>>,
+}
+
+impl ExecutionContext for Replay<'_> {
+    fn send_request(&self, req: Request) -> Result<Response> {
+        self.send_request_async(req).wait()
+    }
+
+    fn send_request_async(&self, req: Request) -> PendingRequest<'_> {
+        let result = match self.responses.get(&RequestKey::from(&req)) {
+            Some(Ok(resp)) => Ok(resp.clone()),
+            Some(Err(err)) => Err(err.duplicate()),
+            None => {
+                let mut missing = self.missing.borrow_mut();
+                if !missing.iter().any(|missing| RequestKey::from(missing) == RequestKey::from(&req)) {
+                    missing.push(req);
+                }
+                Err(ExecutionError::Suspended)
+            }
+        };
+
+        PendingRequest::ready(result)
+    }
+}
+
+// Polls all of the futures until every one of them is done, returning their outputs in order.
+async fn join_all<F: Future>(futures: impl IntoIterator<Item = F>) -> Vec<F::Output> {
+    let futures: Vec<Pin<Box<F>>> = futures.into_iter().map(Box::pin).collect();
+    JoinAll {
+        outputs: futures.iter().map(|_| None).collect(),
+        futures,
+    }
+    .await
+}
+
+struct JoinAll<F: Future> {
+    futures: Vec<Pin<Box<F>>>,
+    outputs: Vec<Option<F::Output>>,
+}
+
+// The futures are boxed, and the outputs are never pinned
+impl<F: Future> Unpin for JoinAll<F> {}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let mut done = true;
+        for (future, output) in this.futures.iter_mut().zip(this.outputs.iter_mut()) {
+            if output.is_none() {
+                match future.as_mut().poll(cx) {
+                    Poll::Ready(result) => *output = Some(result),
+                    Poll::Pending => done = false,
+                }
+            }
+        }
+
+        if !done {
+            return Poll::Pending;
+        }
+        Poll::Ready(this.outputs.iter_mut().filter_map(Option::take).collect())
+    }
+}
@@ -0,0 +1,165 @@
+//! A builder for processing documents with a `Configuration`, as an alternative to passing the
+//! configuration to each of the `transform_esi_string` functions.
+
+use crate::{transform_esi_string_to_writer, transform_esi_string_with_config, Configuration, ConformanceMode};
+use crate::{ExecutionContext, Limits, Observer, OutputReader, Processor, Result, Template, UrlRewriter};
+use crate::{OnErrorPolicy, Variables};
+#[cfg(feature = "cache")]
+use crate::FragmentCache;
+use std::io::{BufRead, Write};
+
+impl Processor<'static, dyn ExecutionContext, Vec<u8>> {
+    /// Returns a builder for the options that documents are processed with, starting from the
+    /// default `Configuration`.
+    ///
+    /// # Examples
+    /// ```
+    /// use esi::{ConformanceMode, ExecutionContext, Processor, Request, Response, Result, Variables};
+    ///
+    /// struct Backend;
+    ///
+    /// impl ExecutionContext for Backend {
+    ///     fn send_request(&self, req: Request) -> Result<Response> {
+    ///         Ok(Response { body: format!("[{}]", req.url).into_bytes(), status_code: 200, headers: Vec::new() })
+    ///     }
+    /// }
+    ///
+    /// let esi = Processor::builder().with_conformance(ConformanceMode::Strict).with_recursion(2);
+    /// let output = esi.transform(&b"<esi:include src=\"/a\"/>"[..], &Backend, &Variables::new()).unwrap();
+    /// assert_eq!(output, b"[/a]");
+    /// ```
+    pub fn builder() -> ProcessorBuilder {
+        ProcessorBuilder::default()
+    }
+}
+
+/// The options that documents are processed with, created by `Processor::builder()`. The most
+/// common options can be set on the builder directly, and the rest through `with_config`. A
+/// builder may be kept and used for any number of documents, as it isn't consumed by processing
+/// them.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessorBuilder {
+    config: Configuration,
+}
+
+impl ProcessorBuilder {
+    /// Replaces the options with the given `Configuration`.
+    pub fn with_config(mut self, config: Configuration) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// See `Configuration::with_namespace_prefixes`.
+    pub fn with_namespace_prefixes(mut self, prefixes: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config = self.config.with_namespace_prefixes(prefixes);
+        self
+    }
+
+    /// See `Configuration::with_conformance`.
+    pub fn with_conformance(mut self, mode: ConformanceMode) -> Self {
+        self.config = self.config.with_conformance(mode);
+        self
+    }
+
+    /// See `Configuration::with_limits`.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.config = self.config.with_limits(limits);
+        self
+    }
+
+    /// See `Configuration::with_recursion`.
+    pub fn with_recursion(mut self, max_depth: usize) -> Self {
+        self.config = self.config.with_recursion(max_depth);
+        self
+    }
+
+    /// See `Configuration::with_fragment_cache`.
+    #[cfg(feature = "cache")]
+    pub fn with_fragment_cache(mut self, cache: impl FragmentCache + 'static) -> Self {
+        self.config = self.config.with_fragment_cache(cache);
+        self
+    }
+
+    /// See `Configuration::with_url_rewriter`.
+    pub fn with_url_rewriter(mut self, rewriter: impl UrlRewriter + 'static) -> Self {
+        self.config = self.config.with_url_rewriter(rewriter);
+        self
+    }
+
+    /// See `Configuration::with_default_onerror`.
+    pub fn with_default_onerror(mut self, policy: OnErrorPolicy) -> Self {
+        self.config = self.config.with_default_onerror(policy);
+        self
+    }
+
+    /// See `Configuration::with_fail_open`.
+    pub fn with_fail_open(mut self) -> Self {
+        self.config = self.config.with_fail_open();
+        self
+    }
+
+    /// See `Configuration::with_observer`.
+    pub fn with_observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.config = self.config.with_observer(observer);
+        self
+    }
+
+    /// See `Configuration::with_debug`.
+    pub fn with_debug(mut self) -> Self {
+        self.config = self.config.with_debug();
+        self
+    }
+
+    /// Returns the configuration that documents are processed with.
+    pub fn config(&self) -> &Configuration {
+        &self.config
+    }
+
+    /// Processes a whole document, like `transform_esi_string_with_config`.
+    pub fn transform(
+        &self,
+        body: impl BufRead,
+        client: &(impl ExecutionContext + ?Sized),
+        variables: &Variables,
+    ) -> Result<Vec<u8>> {
+        transform_esi_string_with_config(body, client, variables, &self.config)
+    }
+
+    /// Processes a whole document, writing its output as it is produced, like
+    /// `transform_esi_string_to_writer`.
+    pub fn transform_to_writer(
+        &self,
+        body: impl BufRead,
+        client: &(impl ExecutionContext + ?Sized),
+        variables: &Variables,
+        output: impl Write,
+    ) -> Result<()> {
+        transform_esi_string_to_writer(body, client, variables, &self.config, output)
+    }
+
+    /// Parses a document to be executed any number of times, like `Template::compile_with_config`.
+    pub fn compile(&self, body: impl BufRead) -> Result<Template> {
+        Template::compile_with_config(body, &self.config)
+    }
+
+    /// Creates a `Processor` for a document that is received in chunks.
+    pub fn build<'a, C: ExecutionContext + ?Sized, W: Write>(
+        &'a self,
+        client: &'a C,
+        variables: &Variables,
+        output: W,
+    ) -> Processor<'a, C, W> {
+        Processor::new(client, variables, &self.config, output)
+    }
+
+    /// Creates an `OutputReader` that processes the document read from `input` as its output is
+    /// read.
+    pub fn reader<'a, C: ExecutionContext + ?Sized, R: BufRead>(
+        &'a self,
+        input: R,
+        client: &'a C,
+        variables: &Variables,
+    ) -> OutputReader<'a, C, R> {
+        OutputReader::new(input, client, variables, &self.config)
+    }
+}
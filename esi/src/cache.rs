@@ -0,0 +1,240 @@
+//! Caching of fragment responses across documents.
+//!
+//! A `FragmentCache` is consulted before an include's request is sent to the `ExecutionContext`,
+//! and successful responses are stored in it for as long as their `Cache-Control` header allows.
+//! Responses that have expired within their `stale-while-revalidate` period are still used, while
+//! a request to refresh them is sent alongside the document.
+//! `MemoryCache` is a simple implementation that keeps responses in memory, and adapters may
+//! provide caches backed by their platform instead.
+//!
+//! Fragments are only cached with the `cache` feature enabled, which it is by default.
+
+use crate::{Request, Response};
+use std::{fmt, sync::Arc, time::Duration};
+#[cfg(feature = "cache")]
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+/// A cache of fragment responses, shared by the documents that are processed with a
+/// `Configuration`.
+pub trait FragmentCache: Send + Sync {
+    /// Returns the cached response for the given key, if it is fresh or may still be served while
+    /// it is revalidated.
+    fn get(&self, key: &str) -> Option<CachedResponse>;
+
+    /// Stores a response under the given key, to be kept for as long as `freshness` allows.
+    fn put(&self, key: &str, response: &Response, freshness: Freshness);
+}
+
+/// A response returned by a `FragmentCache`.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub response: Response,
+    /// Whether the response has expired, and should be refreshed.
+    pub stale: bool,
+}
+
+/// How long a response may be cached for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Freshness {
+    /// How long the response is fresh for.
+    pub ttl: Duration,
+    /// How long the response may be served for once it has expired, while it is refreshed.
+    pub stale_while_revalidate: Duration,
+}
+
+/// How an assembled page may be cached, combined from the `Cache-Control` headers of the template
+/// and the fragments that were included in it, as returned by
+/// `transform_esi_string_with_cache_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachePolicy {
+    /// How long the page is fresh for, which is the shortest lifetime of its parts, or `None` if
+    /// any of them may not be reused without revalidation.
+    pub freshness: Option<Freshness>,
+    /// Whether any part is marked `private`, so the page may only be cached by the client.
+    pub private: bool,
+    /// Whether any part is marked `no-store`, so the page may not be cached at all.
+    pub no_store: bool,
+}
+
+impl CachePolicy {
+    /// Returns the policy of a response with the given `Cache-Control` header, from its
+    /// `s-maxage` or `max-age`, `stale-while-revalidate`, `no-cache`, `private` and `no-store`
+    /// directives. Responses without a positive lifetime, including those without the header,
+    /// may not be reused without revalidation.
+    pub fn from_cache_control(cache_control: Option<&str>) -> Self {
+        let directives = cache_control.map(directives).unwrap_or_default();
+        let lifetime = directives.lifetime.filter(|seconds| *seconds > 0 && !directives.no_cache);
+        Self {
+            freshness: lifetime.map(|ttl| Freshness {
+                ttl: Duration::from_secs(ttl.min(MAX_DELTA_SECONDS)),
+                stale_while_revalidate: Duration::from_secs(directives.stale_while_revalidate.min(MAX_DELTA_SECONDS)),
+            }),
+            private: directives.private,
+            no_store: directives.no_store,
+        }
+    }
+
+    /// Returns the policy of a page made of parts with this policy and `other`: the shorter of
+    /// their lifetimes, which is private or not stored if either of them is.
+    pub fn combine(self, other: CachePolicy) -> Self {
+        let freshness = match (self.freshness, other.freshness) {
+            (Some(a), Some(b)) => Some(Freshness {
+                ttl: a.ttl.min(b.ttl),
+                stale_while_revalidate: a.stale_while_revalidate.min(b.stale_while_revalidate),
+            }),
+            _ => None,
+        };
+        Self {
+            freshness,
+            private: self.private || other.private,
+            no_store: self.no_store || other.no_store,
+        }
+    }
+
+    /// Returns the `Cache-Control` header for the page, e.g. `max-age=60` or `private, no-cache`.
+    pub fn cache_control(&self) -> String {
+        if self.no_store {
+            return "no-store".to_string();
+        }
+
+        let mut directives = Vec::new();
+        if self.private {
+            directives.push("private".to_string());
+        }
+        match self.freshness {
+            Some(freshness) => {
+                directives.push(format!("max-age={}", freshness.ttl.as_secs()));
+                if !freshness.stale_while_revalidate.is_zero() {
+                    directives.push(format!("stale-while-revalidate={}", freshness.stale_while_revalidate.as_secs()));
+                }
+            }
+            None => directives.push("no-cache".to_string()),
+        }
+        directives.join(", ")
+    }
+}
+
+/// A `FragmentCache` that keeps responses in memory until they expire.
+#[cfg(feature = "cache")]
+#[derive(Default)]
+pub struct MemoryCache {
+    // The time each response expires, the time it may no longer be served, and the response
+    entries: Mutex<HashMap<String, (Instant, Instant, Response)>>,
+}
+
+#[cfg(feature = "cache")]
+impl MemoryCache {
+    /// Creates an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(feature = "cache")]
+impl FragmentCache for MemoryCache {
+    fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().ok()?;
+        let now = Instant::now();
+        match entries.get(key) {
+            Some((expires, stale_until, response)) if now < *stale_until => Some(CachedResponse {
+                response: response.clone(),
+                stale: now >= *expires,
+            }),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put(&self, key: &str, response: &Response, freshness: Freshness) {
+        if let Ok(mut entries) = self.entries.lock() {
+            let now = Instant::now();
+            // Lifetimes too long to be represented are cut short rather than overflowing
+            let later = |duration| now.checked_add(duration).unwrap_or(now + Duration::from_secs(MAX_DELTA_SECONDS));
+            let expires = later(freshness.ttl);
+            let stale_until = later(freshness.ttl.saturating_add(freshness.stale_while_revalidate));
+            entries.retain(|_, (_, stale_until, _)| now < *stale_until);
+            entries.insert(key.to_string(), (expires, stale_until, response.clone()));
+        }
+    }
+}
+
+/// Returns the key that a request's response is cached under, or `None` if it shouldn't be
+/// cached. Only `GET` and `HEAD` requests are cached, keyed by their method, URL and headers.
+pub fn cache_key(req: &Request) -> Option<String> {
+    if !matches!(req.method.as_str(), "GET" | "HEAD") {
+        return None;
+    }
+
+    let mut key = format!("{} {}", req.method, req.url);
+    for (name, value) in &req.headers {
+        key.push_str(&format!("\n{}: {}", name.to_ascii_lowercase(), value));
+    }
+    Some(key)
+}
+
+// The largest lifetime a response is cached for, as RFC 9111 allows for larger values.
+const MAX_DELTA_SECONDS: u64 = 2_147_483_648;
+
+/// Returns how long a response may be cached for, from the `s-maxage` or `max-age` and
+/// `stale-while-revalidate` directives of its `Cache-Control` header. Returns `None` for
+/// unsuccessful responses, and for responses that are marked `no-store`, `no-cache` or `private`
+/// or don't have a positive lifetime.
+pub fn freshness(response: &Response) -> Option<Freshness> {
+    if !(200..=299).contains(&response.status_code) {
+        return None;
+    }
+
+    let policy = CachePolicy::from_cache_control(Some(response.cache_control()?));
+    if policy.private || policy.no_store {
+        return None;
+    }
+    policy.freshness
+}
+
+// The directives of a `Cache-Control` header that decide how long a response is cached for.
+#[derive(Default)]
+struct Directives {
+    // The `s-maxage`, or otherwise the `max-age`, in seconds
+    lifetime: Option<u64>,
+    stale_while_revalidate: u64,
+    no_cache: bool,
+    private: bool,
+    no_store: bool,
+}
+
+fn directives(cache_control: &str) -> Directives {
+    let mut directives = Directives::default();
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for directive in cache_control.split(',') {
+        let (name, value) = match directive.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (directive.trim(), None),
+        };
+        let seconds = || value.and_then(|value| value.parse().ok());
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" => directives.no_store = true,
+            "no-cache" => directives.no_cache = true,
+            "private" => directives.private = true,
+            "max-age" => max_age = seconds(),
+            "s-maxage" => s_maxage = seconds(),
+            "stale-while-revalidate" => directives.stale_while_revalidate = seconds().unwrap_or(0),
+            _ => {}
+        }
+    }
+    directives.lifetime = s_maxage.or(max_age);
+    directives
+}
+
+// Holds the fragment cache of a `Configuration`, which can't derive `Debug` through it.
+#[derive(Clone)]
+pub(crate) struct SharedCache(pub(crate) Arc<dyn FragmentCache>);
+
+impl fmt::Debug for SharedCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FragmentCache")
+    }
+}
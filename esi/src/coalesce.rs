@@ -0,0 +1,157 @@
+//! Coalescing of identical fragment requests that are in flight at the same time, for adapters
+//! that process many documents concurrently.
+
+use crate::{cache, ExecutionContext, ExecutionError, PendingRequest, Request, Response, Result};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+/// An `ExecutionContext` that collapses identical requests from concurrently processed documents
+/// into one, so that a popular fragment is only fetched once while a request for it is in flight.
+/// Requests are identified by their cache key, so only `GET` and `HEAD` requests are coalesced.
+///
+/// A request joins an identical request that was sent before it, and shares its response if that
+/// response is already being waited for by the time it is needed. Otherwise, the request is sent
+/// separately, so that documents never wait on each other's progress. A request that shares a
+/// response still times out at its own deadline.
+pub struct CoalescingContext<C: ExecutionContext> {
+    inner: C,
+    in_flight: Mutex<HashMap<String, Arc<Flight>>>,
+}
+
+impl<C: ExecutionContext> CoalescingContext<C> {
+    /// Wraps an `ExecutionContext`, which sends the requests that aren't coalesced.
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the wrapped `ExecutionContext`.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C: ExecutionContext> ExecutionContext for CoalescingContext<C> {
+    fn send_request(&self, req: Request) -> Result<Response> {
+        self.send_request_async(req).wait()
+    }
+
+    fn send_request_async(&self, req: Request) -> PendingRequest<'_> {
+        let key = match cache::cache_key(&req) {
+            Some(key) => key,
+            None => return self.inner.send_request_async(req),
+        };
+
+        let mut in_flight = lock(&self.in_flight);
+        if let Some(flight) = in_flight.get(&key).cloned() {
+            drop(in_flight);
+            return PendingRequest::new(move || match flight.wait(&req) {
+                Some(result) => result,
+                None => self.inner.send_request(req),
+            });
+        }
+
+        let flight = Arc::new(Flight::default());
+        in_flight.insert(key.clone(), flight.clone());
+        drop(in_flight);
+
+        let leader = Leader {
+            key,
+            flight,
+            in_flight: &self.in_flight,
+        };
+        let pending = self.inner.send_request_async(req);
+        PendingRequest::new(move || {
+            leader.flight.set(State::Receiving);
+            let result = pending.wait();
+            leader.flight.set(State::Done(match &result {
+                Ok(resp) => Ok(resp.clone()),
+                Err(err) => Err(err.duplicate()),
+            }));
+            result
+        })
+    }
+}
+
+// The progress of the request that identical requests are coalesced into.
+#[derive(Default)]
+enum State {
+    // The request has been sent, but its response isn't being waited for yet
+    #[default]
+    Sent,
+    // The response is being waited for
+    Receiving,
+    Done(Result<Response>),
+    // The request was dropped without its response being waited for
+    Abandoned,
+}
+
+#[derive(Default)]
+struct Flight {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Flight {
+    fn set(&self, state: State) {
+        *lock(&self.state) = state;
+        self.changed.notify_all();
+    }
+
+    // Waits for the response while it is being received, until the deadline of `req`. Returns
+    // `None` if the response isn't being received, so `req` needs to be sent separately.
+    fn wait(&self, req: &Request) -> Option<Result<Response>> {
+        let mut state = lock(&self.state);
+        loop {
+            match &*state {
+                State::Done(Ok(resp)) => return Some(Ok(resp.clone())),
+                State::Done(Err(err)) => return Some(Err(err.duplicate())),
+                State::Sent | State::Abandoned => return None,
+                State::Receiving => {}
+            }
+
+            state = match req.deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Some(Err(ExecutionError::Timeout(req.url.clone())));
+                    }
+                    self.changed
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self.changed.wait(state).unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+}
+
+// Removes the leading request of a flight once it is done or dropped, so that later requests are
+// sent afresh.
+struct Leader<'a> {
+    key: String,
+    flight: Arc<Flight>,
+    in_flight: &'a Mutex<HashMap<String, Arc<Flight>>>,
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        lock(self.in_flight).remove(&self.key);
+
+        let mut state = lock(&self.flight.state);
+        if !matches!(*state, State::Done(_)) {
+            *state = State::Abandoned;
+            self.flight.changed.notify_all();
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,823 @@
+#[cfg(feature = "cache")]
+use crate::cache::FragmentCache;
+use crate::cache::SharedCache;
+use crate::functions::{Function, Functions};
+use crate::hooks::{Hooks, RequestHook, ResponseHook, UrlPolicy, UrlRewriter};
+use crate::metrics::{Observer, SharedObserver};
+use crate::tags::{TagHandler, TagHandlers};
+use crate::{ExecutionError, Response, Result};
+use crate::surrogate::{self, SurrogateControl};
+use crate::url;
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// How strictly documents are expected to conform to the ESI specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ConformanceMode {
+    /// Unknown `esi:` tags and malformed attributes on ESI tags are errors.
+    Strict,
+    /// Unknown `esi:` tags are passed through to the output untouched, and malformed attributes
+    /// on ESI tags are ignored.
+    Lenient,
+    /// Unknown `esi:` tags are removed from the output, leaving their content in place, and
+    /// malformed attributes on ESI tags are ignored. This is the default.
+    #[default]
+    Permissive,
+}
+
+/// How `esi:` tags that aren't part of the supported tag set are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum UnknownTagPolicy {
+    /// Unknown tags are errors.
+    Error,
+    /// Unknown tags are removed from the output, leaving their content in place.
+    Strip,
+    /// Unknown tags are passed through to the output untouched.
+    Passthrough,
+}
+
+/// Limits on the resources used to process a document, which protect against hostile documents and
+/// misbehaving backends. Entities declared in a DOCTYPE are never expanded, so they aren't limited
+/// separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Limits {
+    /// The maximum number of attributes on an ESI tag. Defaults to 32.
+    pub max_attributes: usize,
+    /// The maximum length in bytes of an attribute value on an ESI tag. Defaults to 16 KiB.
+    pub max_attribute_length: usize,
+    /// The maximum number of ESI tags in a document. Defaults to 10,000.
+    pub max_tags: usize,
+    /// The maximum number of bytes of a document that are buffered to be parsed at once. This is
+    /// the whole document, or the input that `Processor` holds back until an ESI block is
+    /// complete. Defaults to 16 MiB.
+    pub max_buffered_bytes: usize,
+    /// The maximum size in bytes of a fragment's response body. Larger fragments are handled like
+    /// failed requests, using the include's fallback content or `onerror` attribute. Defaults to
+    /// 16 MiB.
+    pub max_fragment_bytes: usize,
+    /// The maximum size in bytes of a document's output, including its fragments. Defaults to
+    /// 64 MiB.
+    pub max_output_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_attributes: 32,
+            max_attribute_length: 16 * 1024,
+            max_tags: 10_000,
+            max_buffered_bytes: 16 * 1024 * 1024,
+            max_fragment_bytes: 16 * 1024 * 1024,
+            max_output_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// What happens to the includes of a document beyond the configured maximum number of includes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum IncludeLimitAction {
+    /// The document fails with `ExecutionError::LimitExceeded`.
+    Error,
+    /// Nothing more is fetched, and each further include is replaced by the given markup.
+    Placeholder(String),
+}
+
+/// How an include whose fragment can't be rendered is handled when it has no `onerror` attribute
+/// or fallback content.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum OnErrorPolicy {
+    /// The document fails with the include's error. This is the default.
+    #[default]
+    Fail,
+    /// The include is removed from the output, as if it had `onerror="continue"`.
+    Continue,
+    /// The include is replaced by the given markup.
+    Placeholder(String),
+}
+
+/// Which headers of the client request are copied onto fragment requests. The headers are those
+/// that the document's `Variables` were built from with `Variables::from_request`. Headers that
+/// are set by an include's `headers` attribute or by request hooks are added after them.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum HeaderForwarding {
+    /// Every header is forwarded except for credentials and headers that describe the client
+    /// request's own connection or body, which are listed in `HeaderForwarding::SENSITIVE`. This
+    /// is the default.
+    #[default]
+    AllExceptSensitive,
+    /// Every header is forwarded except for the named ones.
+    AllExcept(Vec<String>),
+    /// Only the named headers are forwarded, e.g. `Cookie` and `Accept-Language`.
+    Only(Vec<String>),
+    /// No headers are forwarded.
+    None,
+}
+
+impl HeaderForwarding {
+    /// The headers that `HeaderForwarding::AllExceptSensitive` doesn't forward.
+    pub const SENSITIVE: &'static [&'static str] = &[
+        "authorization",
+        "proxy-authorization",
+        "host",
+        "connection",
+        "keep-alive",
+        "te",
+        "upgrade",
+        "content-length",
+        "content-type",
+        "transfer-encoding",
+        "expect",
+        "surrogate-capability",
+    ];
+
+    /// Returns whether the header with the given name is forwarded. Names are compared
+    /// case-insensitively.
+    pub fn forwards(&self, name: &str) -> bool {
+        let listed = |names: &[String]| names.iter().any(|listed| listed.eq_ignore_ascii_case(name));
+        match self {
+            HeaderForwarding::AllExceptSensitive => {
+                !HeaderForwarding::SENSITIVE.iter().any(|listed| listed.eq_ignore_ascii_case(name))
+            }
+            HeaderForwarding::AllExcept(names) => !listed(names),
+            HeaderForwarding::Only(names) => listed(names),
+            HeaderForwarding::None => false,
+        }
+    }
+}
+
+/// How failed fragment requests are retried before falling back to an include's `alt` URLs or
+/// `onerror` attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct RetryPolicy {
+    /// The maximum number of times a request is sent, including the first attempt. Defaults to 3.
+    pub max_attempts: usize,
+    /// The time to wait before the first retry, which doubles for each further retry. Defaults to
+    /// 100 milliseconds.
+    pub backoff: Duration,
+    /// The response statuses that are retried. Defaults to 502, 503 and 504.
+    pub retry_on_status: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+            retry_on_status: vec![502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns whether a request with the given result should be retried. Responses are retried by
+    /// status, whether the `ExecutionContext` returns them or fails with
+    /// `ExecutionError::UnexpectedStatus`.
+    pub(crate) fn retries(&self, result: &Result<Response>) -> bool {
+        let status = match result {
+            Ok(resp) => resp.status_code,
+            Err(ExecutionError::UnexpectedStatus(_, status)) => *status,
+            Err(_) => return false,
+        };
+        self.retry_on_status.contains(&status)
+    }
+}
+
+/// The priority of an include, from its `priority` attribute of `high` or `low`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum IncludePriority {
+    /// Requested before other includes.
+    High,
+    #[default]
+    Normal,
+    /// Requested after other includes, and dropped once the configured low-priority time limit
+    /// has passed.
+    Low,
+}
+
+impl IncludePriority {
+    /// Parses the value of a `priority` attribute, which is `Normal` unless it is `high` or `low`.
+    pub fn parse(value: &str) -> Self {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "high" => IncludePriority::High,
+            "low" => IncludePriority::Low,
+            _ => IncludePriority::Normal,
+        }
+    }
+}
+
+/// A handle that abandons the processing of documents when cancelled, for example once the
+/// client has disconnected. Clones of a token share its state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a token that hasn't been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the processing of the documents that use this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Options that control how ESI documents are processed.
+///
+/// With the `serde` feature, configurations can be serialized and deserialized, e.g. to load
+/// processing profiles from JSON or TOML. Options that aren't set take their default values.
+/// Functions, tag handlers, hooks, the observer, the fragment cache and the cancellation token
+/// aren't serialized, and are added with the builder methods after deserialization.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Configuration {
+    /// The maximum depth to which fetched fragments are themselves processed as ESI.
+    pub(crate) recursion_depth: usize,
+    /// The functions available to expressions.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) functions: Functions,
+    /// The handlers of custom tags.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) tag_handlers: TagHandlers,
+    /// The hooks that are called around fragment requests.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) hooks: Hooks,
+    /// The observer that receives metrics events.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) observer: SharedObserver,
+    /// Whether to append an `<esi:debug>` block to the end of every document.
+    pub(crate) debug: bool,
+    /// Whether includes that fail and are continued past leave a comment in their place.
+    pub(crate) error_markers: bool,
+    /// Whether the bodies of included fragments are HTML-escaped, unless their tag says otherwise.
+    pub(crate) escape_fragments: bool,
+    /// Whether documents are only processed when their `Surrogate-Control` header requests it.
+    pub(crate) require_surrogate_control: bool,
+    /// The device token advertised in the `Surrogate-Capability` header of fragment requests.
+    pub(crate) surrogate_device: Option<String>,
+    /// Whether processing is left to downstream surrogates that advertise ESI capability.
+    pub(crate) delegate: bool,
+    /// How unknown tags and malformed attributes are handled.
+    pub(crate) conformance: ConformanceMode,
+    /// How unknown tags are handled, if different to the conformance mode's default.
+    pub(crate) unknown_tags: Option<UnknownTagPolicy>,
+    /// The names of the ESI tags that are honored, without their prefix, or `None` for all tags.
+    pub(crate) allowed_tags: Option<HashSet<String>>,
+    /// The methods that fragments may be requested with, or `None` for any method.
+    pub(crate) allowed_methods: Option<Vec<String>>,
+    /// The hosts that fragments may be requested from, or `None` for any host.
+    pub(crate) allowed_hosts: Option<Vec<String>>,
+    /// Whether documents are parsed as HTML, only interpreting ESI constructs.
+    pub(crate) html: bool,
+    /// Whether ESI markup is interpreted within `<script>` and `<style>` elements.
+    pub(crate) esi_in_scripts: bool,
+    /// Limits on the resources used to parse documents.
+    pub(crate) limits: Limits,
+    /// The maximum number of fragment requests that are pending at once, or `None` for the default.
+    pub(crate) max_concurrent_requests: Option<usize>,
+    /// The timeout for fragment requests whose tag doesn't set `maxwait` or `timeout`.
+    pub(crate) default_timeout: Option<Duration>,
+    /// The cache that fragment responses are stored in, shared by clones of the configuration.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) cache: Option<SharedCache>,
+    /// How long fetchable inline fragments are kept in the fragment cache, or `None` if they aren't.
+    pub(crate) inline_fragment_ttl: Option<Duration>,
+    /// How includes without an `onerror` attribute or fallback content are handled when they fail.
+    pub(crate) onerror: OnErrorPolicy,
+    /// The maximum number of includes per document, and what happens to includes beyond it.
+    pub(crate) include_limit: Option<(usize, IncludeLimitAction)>,
+    /// How failed fragment requests are retried, or `None` if they aren't.
+    pub(crate) retry: Option<RetryPolicy>,
+    /// Which headers of the client request are copied onto fragment requests.
+    pub(crate) header_forwarding: HeaderForwarding,
+    /// The names of the fragment response headers that are collected for the client response.
+    pub(crate) aggregated_headers: Vec<String>,
+    /// The maximum time spent processing each document, or `None` if it isn't limited.
+    pub(crate) time_limit: Option<Duration>,
+    /// The time after which low-priority includes are dropped, or `None` if they never are.
+    pub(crate) low_priority_time_limit: Option<Duration>,
+    /// The token that abandons processing when cancelled.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) cancellation: Option<CancellationToken>,
+    /// Whether identical include requests within a document are sent separately.
+    pub(crate) no_include_deduplication: bool,
+    /// Whether lines that only contain removed ESI constructs are removed from the output.
+    pub(crate) trim_whitespace: bool,
+    /// Whether ESI elements that are still open at the end of a document are closed automatically.
+    pub(crate) auto_close: bool,
+    /// Whether attribute values on ESI tags may be unquoted.
+    pub(crate) unquoted_attributes: bool,
+    /// Whether documents that fail to be processed are returned as they were received.
+    pub(crate) fail_open: bool,
+    /// The maximum number of ESI blocks that may be nested within each other, or `None` for the
+    /// default.
+    pub(crate) max_nesting_depth: Option<usize>,
+    /// The namespace prefixes of ESI tags, or `None` for the `esi` prefix.
+    pub(crate) namespace_prefixes: Option<Vec<String>>,
+}
+
+impl Configuration {
+    /// Enables ESI processing of fetched fragments, so that fragments may include further
+    /// fragments. Processing stops once `max_depth` levels of fragments have been processed, and
+    /// deeper fragments are inserted as-is. A fragment that includes itself, directly or through
+    /// other fragments, fails its include with `ExecutionError::IncludeCycle`. Recursion is
+    /// disabled by default.
+    ///
+    /// `<esi:eval>` fragments count as a level as well, but are executed at the top level even
+    /// when recursion is disabled. An `<esi:eval>` that would go deeper fails with
+    /// `ExecutionError::LimitExceeded`, and one that evaluates itself with
+    /// `ExecutionError::IncludeCycle`.
+    pub fn with_recursion(mut self, max_depth: usize) -> Self {
+        self.recursion_depth = max_depth;
+        self
+    }
+
+    /// Sets how unknown ESI tags and malformed attributes are handled. Defaults to
+    /// `ConformanceMode::Permissive`.
+    pub fn with_conformance(mut self, mode: ConformanceMode) -> Self {
+        self.conformance = mode;
+        self
+    }
+
+    /// Sets the maximum number of ESI blocks such as `<esi:choose>`, `<esi:try>` and `<esi:vars>`
+    /// that may be nested within each other. Documents that nest blocks more deeply fail with
+    /// `ExecutionError::NestingTooDeep`. Defaults to 64. Blocks are parsed recursively, so very
+    /// large limits may exhaust the stack.
+    pub fn with_max_nesting_depth(mut self, max_depth: usize) -> Self {
+        self.max_nesting_depth = Some(max_depth);
+        self
+    }
+
+    /// Sets how unknown ESI tags are handled, regardless of the conformance mode. By default, they
+    /// are errors in `ConformanceMode::Strict`, passed through in `ConformanceMode::Lenient` and
+    /// stripped in `ConformanceMode::Permissive`. Tags that need a feature that isn't enabled, such
+    /// as `<esi:choose>` without the `expressions` feature, are handled in the same way, failing with
+    /// `ExecutionError::FeatureNotEnabled` where unknown tags are errors.
+    pub fn with_unknown_tags(mut self, policy: UnknownTagPolicy) -> Self {
+        self.unknown_tags = Some(policy);
+        self
+    }
+
+    /// Restricts the ESI tags that are honored to those named, without their prefix, e.g.
+    /// `["include", "remove"]`, so that documents can't use instructions such as `<esi:eval>`.
+    /// Allowing `choose` allows its `when` and `otherwise` tags, and allowing `try` allows its
+    /// `attempt` and `except` tags. Other ESI tags, including custom tags, are handled like
+    /// unknown tags, so they fail with `ExecutionError::TagNotAllowed` where unknown tags are
+    /// errors. By default, every tag is honored.
+    pub fn with_allowed_tags(mut self, tags: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.allowed_tags = Some(tags.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Restricts the methods that includes and `<esi:eval>` tags may request their fragments
+    /// with, e.g. `["GET"]` to disallow `POST` includes. Methods are compared case-insensitively,
+    /// and requests with other methods aren't sent, failing with
+    /// `ExecutionError::MethodNotAllowed`. By default, any method is allowed.
+    pub fn with_allowed_methods(mut self, methods: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.allowed_methods = Some(methods.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Returns whether the tag with the given name is honored. Tags outside the `esi:` namespace
+    /// always are.
+    pub(crate) fn allows_tag(&self, name: &[u8]) -> bool {
+        let (allowed, name) = match (&self.allowed_tags, name.strip_prefix(b"esi:")) {
+            (Some(allowed), Some(name)) => (allowed, name),
+            _ => return true,
+        };
+        let name: &[u8] = match name {
+            b"when" | b"otherwise" => b"choose",
+            b"attempt" | b"except" => b"try",
+            name => name,
+        };
+        std::str::from_utf8(name).is_ok_and(|name| allowed.contains(name))
+    }
+
+    /// Returns whether fragments may be requested with the given method.
+    pub(crate) fn allows_method(&self, method: &str) -> bool {
+        match &self.allowed_methods {
+            Some(allowed) => allowed.iter().any(|allowed| allowed.eq_ignore_ascii_case(method)),
+            None => true,
+        }
+    }
+
+    /// Restricts the hosts that fragments may be requested from, so that documents can't make
+    /// the edge request internal endpoints such as cloud metadata services. Each entry is either
+    /// a host, which only matches itself, or a suffix such as `*.example.com`, which matches the
+    /// subdomains of `example.com` but not `example.com` itself. Hosts are compared
+    /// case-insensitively, and ports are ignored.
+    ///
+    /// The URL that is checked is the one that would be requested, after it has been rewritten,
+    /// resolved against the document URL and passed to request hooks, and each `alt` URL is
+    /// checked before it is tried. URLs without a host, such as relative URLs when no document
+    /// URL is set, and URLs containing backslashes, whitespace or control characters, aren't
+    /// allowed. Requests that aren't allowed aren't sent, and fail with
+    /// `ExecutionError::UrlNotAllowed` unless one of their `alt` URLs succeeds. Requests that
+    /// custom tag handlers send themselves aren't checked. By default, fragments may be requested
+    /// from any host.
+    pub fn with_allowed_hosts(mut self, hosts: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.allowed_hosts = Some(hosts.into_iter().map(|host| host.into().to_ascii_lowercase()).collect());
+        self
+    }
+
+    /// Sets a policy that decides whether fragments may be requested from a URL, for rules that
+    /// `with_allowed_hosts` can't express, e.g. allowing only `https` URLs. URLs are checked in
+    /// the same way as by `with_allowed_hosts`, and must be allowed by both where both are set.
+    /// Replaces any policy that was set before.
+    pub fn with_url_policy(mut self, policy: impl UrlPolicy + 'static) -> Self {
+        self.hooks.url_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Sets which headers of the client request are copied onto the requests for includes and
+    /// `<esi:eval>` tags, e.g. `HeaderForwarding::Only` with `Cookie` and `Accept-Language` to
+    /// keep other headers from reaching fragment backends. Adapters send fragment requests with
+    /// only the headers of the `Request` they are given, so the policy applies to them too.
+    /// Defaults to `HeaderForwarding::AllExceptSensitive`.
+    pub fn with_header_forwarding(mut self, policy: HeaderForwarding) -> Self {
+        self.header_forwarding = policy;
+        self
+    }
+
+    /// Sets the names of fragment response headers, such as `Set-Cookie`, `Vary` and
+    /// `Surrogate-Key`, that are collected so that adapters can merge them into the client
+    /// response. Names are matched case-insensitively. The headers are returned by
+    /// `transform_esi_string_with_headers`, in the order the fragments were included, and
+    /// headers that are repeated exactly are only returned once. By default, no headers are
+    /// collected.
+    pub fn with_aggregated_headers(mut self, names: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.aggregated_headers = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Returns whether the fragment response header with the given name is collected.
+    pub(crate) fn aggregates_header(&self, name: &str) -> bool {
+        self.aggregated_headers.iter().any(|header| header.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns whether fragments may be requested from the given URL.
+    pub(crate) fn allows_url(&self, url: &str) -> bool {
+        if let Some(allowed) = &self.allowed_hosts {
+            let host = match url::host(url) {
+                Some(host) => host,
+                None => return false,
+            };
+            let matches = |allowed: &String| match allowed.strip_prefix("*.") {
+                Some(suffix) => host.strip_suffix(suffix).is_some_and(|subdomain| subdomain.ends_with('.')),
+                None => host == *allowed,
+            };
+            if !allowed.iter().any(matches) {
+                return false;
+            }
+        }
+        self.hooks.url_policy.as_ref().is_none_or(|policy| policy.allows(url))
+    }
+
+    /// Returns how unknown ESI tags are handled.
+    pub(crate) fn unknown_tag_policy(&self) -> UnknownTagPolicy {
+        self.unknown_tags.unwrap_or(match self.conformance {
+            ConformanceMode::Strict => UnknownTagPolicy::Error,
+            ConformanceMode::Lenient => UnknownTagPolicy::Passthrough,
+            ConformanceMode::Permissive => UnknownTagPolicy::Strip,
+        })
+    }
+
+    /// Sets limits on the resources used to parse each document. Documents that exceed them fail
+    /// with `ExecutionError::LimitExceeded`.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets the maximum number of fragment requests that are sent concurrently, using
+    /// `ExecutionContext::send_request_async`. Defaults to 8. Setting this to 1 fetches fragments
+    /// one at a time.
+    pub fn with_max_concurrent_requests(mut self, max_requests: usize) -> Self {
+        self.max_concurrent_requests = Some(max_requests);
+        self
+    }
+
+    /// Returns the maximum number of fragment requests that are sent concurrently.
+    pub(crate) fn max_concurrent_requests(&self) -> usize {
+        self.max_concurrent_requests.unwrap_or(8).max(1)
+    }
+
+    /// Sets the timeout for fragment requests whose tag doesn't set a `maxwait` or `timeout`
+    /// attribute, which is passed to the `ExecutionContext` as `Request::deadline`. A fragment that
+    /// times out is handled like any other failed request, using the tag's fallback content or
+    /// `onerror` attribute. By default, requests don't time out.
+    pub fn with_default_timeout(mut self, timeout: Duration) -> Self {
+        self.default_timeout = Some(timeout);
+        self
+    }
+
+    /// Looks up includes in the given cache before sending their requests to the
+    /// `ExecutionContext`, and stores successful responses in it for as long as their
+    /// `Cache-Control` header allows. Only `GET` and `HEAD` requests are cached. The cache is shared
+    /// by all documents that are processed with this configuration or its clones.
+    ///
+    /// Responses within their `stale-while-revalidate` period are used immediately, and refreshed
+    /// by a request that is waited for once the document's output has been written.
+    #[cfg(feature = "cache")]
+    pub fn with_fragment_cache(mut self, cache: impl FragmentCache + 'static) -> Self {
+        self.cache = Some(SharedCache(Arc::new(cache)));
+        self
+    }
+
+    /// Stores the `<esi:inline>` fragments that have `fetchable="yes"` in the fragment cache,
+    /// fresh for `ttl`, so that includes of their names in later documents are served from the
+    /// cache rather than requested. Each fragment is stored unprocessed, like a fetched fragment,
+    /// under the request that an include of its name would make. Fragments that aren't fetchable
+    /// are only rendered within their own document. Has no effect without a fragment cache.
+    #[cfg(feature = "cache")]
+    pub fn with_inline_fragment_ttl(mut self, ttl: Duration) -> Self {
+        self.inline_fragment_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets how includes that have neither an `onerror` attribute nor fallback content are handled
+    /// when their fragment can't be rendered, e.g. `OnErrorPolicy::Continue` so that a single
+    /// failing fragment never fails the page. Includes with `onerror="continue"` are always
+    /// removed, and includes with any other `onerror` value fail the document. `<esi:eval>` tags
+    /// are handled in the same way, except that their placeholder isn't output. Defaults to
+    /// `OnErrorPolicy::Fail`.
+    pub fn with_default_onerror(mut self, policy: OnErrorPolicy) -> Self {
+        self.onerror = policy;
+        self
+    }
+
+    /// Limits the number of `<esi:include>` and `<esi:eval>` tags that a document may execute,
+    /// counting those of its fragments as well, to protect backends from pathological or malicious
+    /// templates. Includes of `<esi:inline>` fragments aren't counted. By default, there is no
+    /// limit.
+    pub fn with_max_includes(mut self, max_includes: usize, action: IncludeLimitAction) -> Self {
+        self.include_limit = Some((max_includes, action));
+        self
+    }
+
+    /// Returns the maximum number of includes per document, if any.
+    pub(crate) fn max_includes(&self) -> Option<usize> {
+        self.include_limit.as_ref().map(|(max, _)| *max)
+    }
+
+    /// Retries fragment requests that fail with one of the policy's statuses, such as transient
+    /// 502s from fragment services, before an include's `alt` URLs or `onerror` attribute are
+    /// used. By default, requests aren't retried.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Limits the time spent processing each document, counting from when processing starts, so
+    /// that a slow page is abandoned rather than holding on to its resources. Processing fails
+    /// with `ExecutionError::TimeLimitExceeded` once the time has passed, and fragment requests
+    /// time out when the time runs out. Content that has already been written by a `Processor` or
+    /// `transform_esi_string_to_writer` is kept, so clients receive a partial document. By
+    /// default, processing time isn't limited.
+    pub fn with_time_limit(mut self, time_limit: Duration) -> Self {
+        self.time_limit = Some(time_limit);
+        self
+    }
+
+    /// Drops the includes with `priority="low"` once the document has been processed for longer
+    /// than `time_limit`, counting from when processing starts. A dropped include renders its
+    /// fallback content, or nothing if it has none, and the requests of low-priority includes time
+    /// out when the time runs out. This keeps optional fragments from delaying the rest of a slow
+    /// page. By default, low-priority includes are never dropped, but they are still requested
+    /// after other includes.
+    pub fn with_low_priority_time_limit(mut self, time_limit: Duration) -> Self {
+        self.low_priority_time_limit = Some(time_limit);
+        self
+    }
+
+    /// Abandons processing once the given token is cancelled, failing with
+    /// `ExecutionError::Cancelled`. The token is checked between ESI constructs, so a fragment
+    /// request that is in progress is waited for. As the token is shared by clones of the
+    /// configuration, a configuration with its own token should be used for each document that may
+    /// be cancelled separately.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Sends a separate request for every include, even if the same `src` is included several
+    /// times within a document. By default, the response to a `GET` or `HEAD` include is reused by
+    /// later includes of the same request, which avoids refetching repeated fragments such as
+    /// those in lists. Use this if fragments aren't idempotent.
+    pub fn without_include_deduplication(mut self) -> Self {
+        self.no_include_deduplication = true;
+        self
+    }
+
+    /// Removes lines that only contain an `<esi:remove>` block or an `<esi:comment>` tag, along
+    /// with their indentation and line break, instead of leaving blank lines in the output.
+    /// Whitespace around constructs that share a line with other content is kept.
+    pub fn with_whitespace_trimming(mut self) -> Self {
+        self.trim_whitespace = true;
+        self
+    }
+
+    /// Closes ESI elements such as `<esi:remove>` and `<esi:choose>` that are still open at the
+    /// end of a document, as if their closing tags were at the end. By default, such documents
+    /// fail with `ExecutionError::UnclosedTag`.
+    pub fn with_auto_close(mut self) -> Self {
+        self.auto_close = true;
+        self
+    }
+
+    /// Accepts unquoted attribute values on ESI tags, such as `<esi:include src=/a.html />`, as
+    /// well as attributes without values. Single and double quoted values are always accepted.
+    /// Unquoted values are still rejected in `ConformanceMode::Strict`.
+    pub fn with_unquoted_attributes(mut self) -> Self {
+        self.unquoted_attributes = true;
+        self
+    }
+
+    /// Returns documents as they were received when they fail to be parsed or executed, rather
+    /// than failing with the error, as a page with raw ESI tags is usually better than an error
+    /// page. The error is passed to `Observer::document_failed`. Functions that stream their
+    /// output, such as `transform_esi_string_to_writer`, only fail open when documents fail to
+    /// parse, as part of the output may already have been sent by the time execution fails, and
+    /// `Processor` never does. Documents that can't be read still fail.
+    pub fn with_fail_open(mut self) -> Self {
+        self.fail_open = true;
+        self
+    }
+
+    /// Parses documents as HTML rather than XML, so that they don't need to be well-formed. Only
+    /// ESI tags and `<!--esi ... -->` comments are interpreted, and everything else is passed
+    /// through exactly as it was received.
+    pub fn with_html_parsing(mut self) -> Self {
+        self.html = true;
+        self
+    }
+
+    /// Interprets ESI markup within `<script>` and `<style>` elements. By default, their content is
+    /// passed through without interpreting any ESI tags, although variables are still substituted
+    /// within `<esi:vars>` blocks. CDATA sections are never interpreted.
+    pub fn with_esi_in_scripts(mut self) -> Self {
+        self.esi_in_scripts = true;
+        self
+    }
+
+    /// Recognises ESI tags by the given namespace prefixes instead of `esi`, e.g. `myesi` for
+    /// `<myesi:include>`. In XML mode, prefixes that a document binds to the ESI namespace with
+    /// `xmlns:prefix="http://www.edge-delivery.org/esi/1.0"` are recognised as well.
+    pub fn with_namespace_prefixes(mut self, prefixes: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.namespace_prefixes = Some(prefixes.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Appends the output of an `<esi:debug/>` tag to the end of every processed document, as if
+    /// the tag were present in it.
+    pub fn with_debug(mut self) -> Self {
+        self.debug = true;
+        self
+    }
+
+    /// Writes an HTML comment such as `<!-- esi:error src="/ads" status="503" -->` in place of
+    /// every include that fails and is continued past, because of its `onerror="continue"`
+    /// attribute or `OnErrorPolicy::Continue`, so that QA and synthetic monitoring can detect
+    /// degraded pages. The status is left out when the request failed without a response.
+    pub fn with_error_markers(mut self) -> Self {
+        self.error_markers = true;
+        self
+    }
+
+    /// HTML-escapes the bodies of included fragments, so that `<`, `>`, `&`, `'` and `"` are
+    /// inserted as character references, for includes that are used as data rather than markup.
+    /// This keeps untrusted fragment services from injecting script into the page. Escaped
+    /// fragments aren't processed as ESI. Includes with `escape="html"` are escaped whether this is
+    /// set or not, and includes with `escape="none"` never are. Fallback content, placeholders and
+    /// `<esi:inline>` fragments aren't escaped.
+    pub fn with_fragment_escaping(mut self) -> Self {
+        self.escape_fragments = true;
+        self
+    }
+
+    /// Only processes documents whose `Surrogate-Control` header contains `content="ESI/1.0"`.
+    /// By default, every document is processed. Adapters apply this using `should_process`.
+    pub fn with_surrogate_control_required(mut self) -> Self {
+        self.require_surrogate_control = true;
+        self
+    }
+
+    /// Returns whether a document should be processed, given the value of the `Surrogate-Control`
+    /// header it was served with.
+    pub fn should_process(&self, surrogate_control: Option<&str>) -> bool {
+        if !self.require_surrogate_control {
+            return true;
+        }
+
+        surrogate_control.is_some_and(|header| SurrogateControl::parse(header).requests_esi(None))
+    }
+
+    /// Advertises ESI capability under the given device token by adding a `Surrogate-Capability`
+    /// header to every fragment request. Adapters should also add it to the request for the
+    /// document itself, using `surrogate_capability_header`.
+    pub fn with_surrogate_capability(mut self, device: impl Into<String>) -> Self {
+        self.surrogate_device = Some(device.into());
+        self
+    }
+
+    /// Skips processing of documents whose client request advertises that a downstream surrogate
+    /// is capable of ESI processing, leaving the processing to that surrogate instead. Adapters
+    /// apply this using `should_delegate`.
+    pub fn with_delegation(mut self) -> Self {
+        self.delegate = true;
+        self
+    }
+
+    /// Returns the `Surrogate-Capability` header to send to backends, given the header received on
+    /// the incoming request, if any. Returns `None` if no device token is configured.
+    pub fn surrogate_capability_header(&self, existing: Option<&str>) -> Option<String> {
+        self.surrogate_device
+            .as_deref()
+            .map(|device| surrogate::append_capability(existing, device))
+    }
+
+    /// Returns whether processing should be left to a downstream surrogate, given the value of the
+    /// `Surrogate-Capability` header on the client request.
+    pub fn should_delegate(&self, surrogate_capability: Option<&str>) -> bool {
+        self.delegate && surrogate_capability.is_some_and(surrogate::advertises_esi)
+    }
+
+    /// Registers a function that can be called from expressions as `$name(...)`, in addition to
+    /// the built-in functions. Registering a function with the same name as a built-in function
+    /// replaces it.
+    pub fn with_function(mut self, name: impl Into<String>, function: impl Function + 'static) -> Self {
+        self.functions.register(name, function);
+        self
+    }
+
+    /// Registers a handler for a custom tag with the given full name, e.g. `app:widget`. Tags
+    /// with that name are rendered by the handler rather than passed through, whether or not
+    /// their prefix is one of the ESI namespace prefixes.
+    pub fn with_tag_handler(mut self, name: impl Into<String>, handler: impl TagHandler + 'static) -> Self {
+        self.tag_handlers.register(name, handler);
+        self
+    }
+
+    /// Sets the function that maps the `src` and `alt` URLs of includes and `src` URLs of
+    /// `<esi:eval>` tags to the URLs that are requested, once variables have been expanded in
+    /// them. Relative URLs are resolved against `Variables::document_url` after they have been
+    /// rewritten. `src` values that name an `<esi:inline>` fragment aren't rewritten. Replaces any
+    /// rewriter that was set before.
+    pub fn with_url_rewriter(mut self, rewriter: impl UrlRewriter + 'static) -> Self {
+        self.hooks.rewriter = Some(Arc::new(rewriter));
+        self
+    }
+
+    /// Adds a hook that is called with the request for each include and `<esi:eval>` before it is
+    /// sent, after its attributes have been applied, e.g. to add an authentication header. Hooks
+    /// are called in the order they were added.
+    pub fn with_request_hook(mut self, hook: impl RequestHook + 'static) -> Self {
+        self.hooks.before.push(Arc::new(hook));
+        self
+    }
+
+    /// Adds a hook that is called with each fragment response that is received, before it is
+    /// processed or stored in the fragment cache. The hook may change the response, or reject it
+    /// by returning an error such as `ExecutionError::ResponseRejected`, which fails the include.
+    /// Responses that are reused from the cache or an identical include aren't passed to hooks
+    /// again. Hooks are called in the order they were added.
+    pub fn with_response_hook(mut self, hook: impl ResponseHook + 'static) -> Self {
+        self.hooks.after.push(Arc::new(hook));
+        self
+    }
+
+    /// Reports events about includes and documents to the given observer, e.g. to count failed
+    /// includes or record fragment latencies. By default, events are ignored.
+    pub fn with_observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.observer = SharedObserver(Arc::new(observer));
+        self
+    }
+}
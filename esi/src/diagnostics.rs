@@ -0,0 +1,39 @@
+//! Warnings about the parts of a document that were degraded without failing it, such as includes
+//! that were skipped or rendered with their fallback content.
+
+use crate::parse::Position;
+use thiserror::Error;
+
+/// A problem with a document that didn't fail its processing, returned by
+/// `transform_esi_string_with_diagnostics`.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    #[error("unknown tag `{tag}` at {position} was removed")]
+    UnknownTagRemoved { tag: String, position: Position },
+    #[error("tag `{tag}` at {position} isn't available and was removed")]
+    DisallowedTagRemoved { tag: String, position: Position },
+    #[error("`{tag}` of `{src}` at {position} failed and was skipped: {error}")]
+    FragmentSkipped {
+        tag: String,
+        src: String,
+        position: Position,
+        error: String,
+    },
+    #[error("`{tag}` of `{src}` at {position} failed, and its fallback content was rendered: {error}")]
+    FallbackRendered {
+        tag: String,
+        src: String,
+        position: Position,
+        error: String,
+    },
+    #[error("`esi:include` of `{src}` at {position} failed, and its `alt` URL `{alt}` was fetched instead")]
+    AltFetched { src: String, alt: String, position: Position },
+    #[error("`esi:include` of `{src}` at {position} was dropped for its priority")]
+    IncludeDropped { src: String, position: Position },
+    #[error("`esi:include` of `{src}` at {position} was over the include limit, and replaced by a placeholder")]
+    IncludeLimitReached { src: String, position: Position },
+    #[error("`esi:attempt` failed, and `esi:except` was rendered instead: {0}")]
+    AttemptFailed(String),
+    #[error("refreshing the cached fragment `{src}` failed: {error}")]
+    RefreshFailed { src: String, error: String },
+}
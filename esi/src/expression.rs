@@ -0,0 +1,540 @@
+//! Evaluation of ESI test expressions, as used in the `test` attribute of `<esi:when>`.
+//!
+//! Supports string (`'abc'`, or `'''abc'''` without escapes) and numeric (`1`, `-2.5`) literals,
+//! the comparison operators `==`, `!=`, `<`, `>`, `<=` and `>=`, the logical operators `&`, `|`
+//! and `!`, and grouping with parentheses. `has` and `has_i` test whether a value contains a
+//! (case-insensitive) substring. With the `regex` feature enabled, `matches` and `matches_i` test
+//! a value against a (case-insensitive) regular expression. Variables can be referenced with
+//! `$(NAME)` and `$(NAME{key})`, and evaluate to an empty string when unset. Functions are called
+//! with `$name(arg, ...)`; see the [`functions`](crate::functions) module.
+//!
+//! Expressions are only evaluated with the `expressions` feature enabled, which it is by default.
+
+use crate::functions::Functions;
+#[cfg(feature = "expressions")]
+use crate::variables::{self, VariableReference};
+use crate::variables::Variables;
+use crate::{ExecutionError, Result};
+use std::fmt;
+
+/// The result of evaluating an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Number(f64),
+    Boolean(bool),
+}
+
+impl Value {
+    /// Returns the truthiness of the value. Empty strings and zero are falsy.
+    pub fn to_bool(&self) -> bool {
+        match self {
+            Value::String(s) => !s.is_empty(),
+            Value::Number(n) => *n != 0.0,
+            Value::Boolean(b) => *b,
+        }
+    }
+
+    // Returns the numeric representation of the value, if it has one.
+    #[cfg(feature = "expressions")]
+    fn to_number(&self) -> Option<f64> {
+        match self {
+            Value::String(s) => s.trim().parse().ok(),
+            Value::Number(n) => Some(*n),
+            Value::Boolean(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", s),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Boolean(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+#[cfg(feature = "expressions")]
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    String(String),
+    Number(f64),
+    Boolean(bool),
+    Variable(VariableReference),
+    Function(String),
+    Operator(Operator),
+    Not,
+    OpenParen,
+    CloseParen,
+    Comma,
+}
+
+#[cfg(feature = "expressions")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    LessThan,
+    GreaterThan,
+    LessThanOrEqual,
+    GreaterThanOrEqual,
+    Has,
+    HasInsensitive,
+    Matches,
+    MatchesInsensitive,
+    And,
+    Or,
+}
+
+#[cfg(feature = "expressions")]
+impl Operator {
+    // Higher binds tighter.
+    fn precedence(self) -> u8 {
+        match self {
+            Operator::Or => 1,
+            Operator::And => 2,
+            _ => 3,
+        }
+    }
+}
+
+#[cfg(feature = "expressions")]
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    Variable(VariableReference),
+    Call(String, Vec<Expr>),
+    Not(Box<Expr>),
+    Binary(Operator, Box<Expr>, Box<Expr>),
+}
+
+#[cfg(feature = "expressions")]
+fn tokenize(expression: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.char_indices().peekable();
+
+    while let Some(&(offset, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '\'' if expression[offset..].starts_with("'''") => {
+                let start = offset + 3;
+                let len = match expression[start..].find("'''") {
+                    Some(len) => len,
+                    None => return Err(ExecutionError::UnterminatedStringLiteral(expression.to_string())),
+                };
+                while chars.peek().is_some_and(|(i, _)| *i < start + len + 3) {
+                    chars.next();
+                }
+                tokens.push(Token::String(expression[start..start + len].to_string()));
+            }
+            '\'' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next().map(|(_, c)| c) {
+                        Some('\\') => match chars.next() {
+                            Some((_, escaped)) => value.push(escaped),
+                            None => return Err(ExecutionError::UnterminatedStringLiteral(expression.to_string())),
+                        },
+                        Some('\'') => break,
+                        Some(c) => value.push(c),
+                        None => return Err(ExecutionError::UnterminatedStringLiteral(expression.to_string())),
+                    }
+                }
+                tokens.push(Token::String(value));
+            }
+            '0'..='9' | '-' | '.' => {
+                let mut literal = String::new();
+                literal.push(c);
+                chars.next();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_ascii_digit() || c == '.' {
+                        literal.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                match literal.parse() {
+                    Ok(number) => tokens.push(Token::Number(number)),
+                    Err(_) => {
+                        return Err(ExecutionError::InvalidExpression(
+                            expression.to_string(),
+                            format!("`{}` is not a valid number", literal),
+                        ))
+                    }
+                }
+            }
+            'a'..='z' | 'A'..='Z' => {
+                let mut word = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' {
+                        word.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                match word.as_str() {
+                    "true" => tokens.push(Token::Boolean(true)),
+                    "false" => tokens.push(Token::Boolean(false)),
+                    "has" => tokens.push(Token::Operator(Operator::Has)),
+                    "has_i" => tokens.push(Token::Operator(Operator::HasInsensitive)),
+                    "matches" => tokens.push(Token::Operator(Operator::Matches)),
+                    "matches_i" => tokens.push(Token::Operator(Operator::MatchesInsensitive)),
+                    _ => {
+                        return Err(ExecutionError::InvalidExpression(
+                            expression.to_string(),
+                            format!("unknown identifier `{}`", word),
+                        ))
+                    }
+                }
+            }
+            '$' if !expression[offset + 1..].starts_with('(') => {
+                chars.next();
+                let mut name = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' {
+                        name.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                if name.is_empty() {
+                    return Err(ExecutionError::UnexpectedExpressionCharacter(expression.to_string(), c));
+                }
+                tokens.push(Token::Function(name));
+            }
+            '$' => match variables::parse_reference(&expression.as_bytes()[offset..]) {
+                Some((reference, len)) => {
+                    while chars.peek().is_some_and(|(i, _)| *i < offset + len) {
+                        chars.next();
+                    }
+                    tokens.push(Token::Variable(reference));
+                }
+                None => {
+                    return Err(ExecutionError::InvalidExpression(
+                        expression.to_string(),
+                        "invalid variable reference".to_string(),
+                    ))
+                }
+            },
+            '(' => {
+                chars.next();
+                tokens.push(Token::OpenParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::CloseParen);
+            }
+            ',' => {
+                chars.next();
+                tokens.push(Token::Comma);
+            }
+            '&' => {
+                chars.next();
+                tokens.push(Token::Operator(Operator::And));
+            }
+            '|' => {
+                chars.next();
+                tokens.push(Token::Operator(Operator::Or));
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let followed_by_equals = chars.peek().map(|(_, c)| *c) == Some('=');
+                if followed_by_equals {
+                    chars.next();
+                }
+                let token = match (c, followed_by_equals) {
+                    ('=', true) => Token::Operator(Operator::Equal),
+                    ('!', true) => Token::Operator(Operator::NotEqual),
+                    ('!', false) => Token::Not,
+                    ('<', true) => Token::Operator(Operator::LessThanOrEqual),
+                    ('<', false) => Token::Operator(Operator::LessThan),
+                    ('>', true) => Token::Operator(Operator::GreaterThanOrEqual),
+                    ('>', false) => Token::Operator(Operator::GreaterThan),
+                    _ => return Err(ExecutionError::UnexpectedExpressionCharacter(expression.to_string(), c)),
+                };
+                tokens.push(token);
+            }
+            _ => return Err(ExecutionError::UnexpectedExpressionCharacter(expression.to_string(), c)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(feature = "expressions")]
+struct Parser<'a> {
+    expression: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+#[cfg(feature = "expressions")]
+impl<'a> Parser<'a> {
+    fn error(&self, reason: &str) -> ExecutionError {
+        ExecutionError::InvalidExpression(self.expression.to_string(), reason.to_string())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    // Precedence climbing over binary operators.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr> {
+        let mut left = self.parse_unary()?;
+
+        while let Some(Token::Operator(op)) = self.peek() {
+            let op = *op;
+            if op.precedence() < min_precedence {
+                break;
+            }
+            self.next();
+            let right = self.parse_binary(op.precedence() + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::OpenParen) => {
+                let inner = self.parse_binary(0)?;
+                match self.next() {
+                    Some(Token::CloseParen) => Ok(inner),
+                    _ => Err(self.error("missing closing parenthesis")),
+                }
+            }
+            Some(Token::String(s)) => Ok(Expr::Literal(Value::String(s))),
+            Some(Token::Number(n)) => Ok(Expr::Literal(Value::Number(n))),
+            Some(Token::Boolean(b)) => Ok(Expr::Literal(Value::Boolean(b))),
+            Some(Token::Variable(reference)) => Ok(Expr::Variable(reference)),
+            Some(Token::Function(name)) => {
+                if self.next() != Some(Token::OpenParen) {
+                    return Err(self.error("expected `(` after function name"));
+                }
+
+                let mut args = Vec::new();
+                if self.peek() == Some(&Token::CloseParen) {
+                    self.next();
+                    return Ok(Expr::Call(name, args));
+                }
+
+                loop {
+                    args.push(self.parse_binary(0)?);
+                    match self.next() {
+                        Some(Token::Comma) => continue,
+                        Some(Token::CloseParen) => break,
+                        _ => return Err(self.error("missing closing parenthesis")),
+                    }
+                }
+
+                Ok(Expr::Call(name, args))
+            }
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end of expression")),
+        }
+    }
+}
+
+#[cfg(feature = "expressions")]
+fn parse(expression: &str) -> Result<Expr> {
+    let mut parser = Parser {
+        expression,
+        tokens: tokenize(expression)?,
+        position: 0,
+    };
+
+    let expr = parser.parse_binary(0)?;
+    if parser.peek().is_some() {
+        return Err(parser.error("unexpected trailing tokens"));
+    }
+
+    Ok(expr)
+}
+
+#[cfg(feature = "expressions")]
+// Compares two values numerically if both have a numeric representation, and as strings otherwise.
+fn compare(op: Operator, left: &Value, right: &Value) -> bool {
+    let ordering = match (left.to_number(), right.to_number()) {
+        (Some(l), Some(r)) => l.partial_cmp(&r),
+        _ => Some(left.to_string().cmp(&right.to_string())),
+    };
+
+    match ordering {
+        Some(ordering) => match op {
+            Operator::Equal => ordering.is_eq(),
+            Operator::NotEqual => ordering.is_ne(),
+            Operator::LessThan => ordering.is_lt(),
+            Operator::GreaterThan => ordering.is_gt(),
+            Operator::LessThanOrEqual => ordering.is_le(),
+            Operator::GreaterThanOrEqual => ordering.is_ge(),
+            // Only comparison operators are compared
+            _ => false,
+        },
+        // NaN never compares equal to anything
+        None => op == Operator::NotEqual,
+    }
+}
+
+// Tests whether `value` matches the regular expression `pattern`.
+#[cfg(all(feature = "expressions", feature = "regex"))]
+fn regex_match(value: &Value, pattern: &Value, case_insensitive: bool) -> Result<bool> {
+    let pattern = pattern.to_string();
+    let regex = regex::RegexBuilder::new(&pattern)
+        .case_insensitive(case_insensitive)
+        .build()
+        .map_err(|err| ExecutionError::InvalidRegex(pattern.clone(), err.to_string()))?;
+
+    Ok(regex.is_match(&value.to_string()))
+}
+
+#[cfg(all(feature = "expressions", not(feature = "regex")))]
+fn regex_match(_value: &Value, _pattern: &Value, case_insensitive: bool) -> Result<bool> {
+    let operator = if case_insensitive { "matches_i" } else { "matches" };
+    Err(ExecutionError::FeatureNotEnabled(format!("the `{}` operator", operator), "regex"))
+}
+
+#[cfg(feature = "expressions")]
+fn eval(expr: &Expr, variables: &Variables, functions: &Functions) -> Result<Value> {
+    let value = match expr {
+        Expr::Literal(value) => value.clone(),
+        Expr::Variable(reference) => reference
+            .resolve(variables)
+            .unwrap_or_else(|| Value::String(String::new())),
+        Expr::Call(name, args) => {
+            let args = args
+                .iter()
+                .map(|arg| eval(arg, variables, functions))
+                .collect::<Result<Vec<Value>>>()?;
+            functions.call(name, &args)?
+        }
+        Expr::Not(inner) => Value::Boolean(!eval(inner, variables, functions)?.to_bool()),
+        Expr::Binary(Operator::And, left, right) => Value::Boolean(
+            eval(left, variables, functions)?.to_bool() && eval(right, variables, functions)?.to_bool(),
+        ),
+        Expr::Binary(Operator::Or, left, right) => Value::Boolean(
+            eval(left, variables, functions)?.to_bool() || eval(right, variables, functions)?.to_bool(),
+        ),
+        Expr::Binary(Operator::Has, left, right) => Value::Boolean(
+            eval(left, variables, functions)?
+                .to_string()
+                .contains(&eval(right, variables, functions)?.to_string()),
+        ),
+        Expr::Binary(Operator::HasInsensitive, left, right) => Value::Boolean(
+            eval(left, variables, functions)?
+                .to_string()
+                .to_lowercase()
+                .contains(&eval(right, variables, functions)?.to_string().to_lowercase()),
+        ),
+        Expr::Binary(op @ (Operator::Matches | Operator::MatchesInsensitive), left, right) => {
+            Value::Boolean(regex_match(
+                &eval(left, variables, functions)?,
+                &eval(right, variables, functions)?,
+                *op == Operator::MatchesInsensitive,
+            )?)
+        }
+        Expr::Binary(op, left, right) => Value::Boolean(compare(
+            *op,
+            &eval(left, variables, functions)?,
+            &eval(right, variables, functions)?,
+        )),
+    };
+
+    Ok(value)
+}
+
+/// Parses and evaluates the given ESI expression against a set of variables and functions.
+#[cfg(feature = "expressions")]
+pub fn evaluate(expression: &str, variables: &Variables, functions: &Functions) -> Result<Value> {
+    eval(&parse(expression)?, variables, functions)
+}
+
+#[cfg(not(feature = "expressions"))]
+pub fn evaluate(expression: &str, _variables: &Variables, _functions: &Functions) -> Result<Value> {
+    Err(ExecutionError::FeatureNotEnabled(format!("the expression `{}`", expression), "expressions"))
+}
+
+#[cfg(all(test, feature = "expressions"))]
+mod tests {
+    use super::*;
+
+    fn evaluate_with(expression: &str, variables: &Variables) -> Result<Value> {
+        evaluate(expression, variables, &Functions::default())
+    }
+
+    fn test(expression: &str) -> bool {
+        evaluate_with(expression, &Variables::new()).unwrap().to_bool()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert!(test("1 == 1 | 1 == 2 & 1 == 2"));
+        assert!(!test("(1 == 1 | 1 == 2) & 1 == 2"));
+        assert!(!test("!1 == 1 & 1 == 1"));
+        assert!(test("!(1 == 2) & 1 == 1"));
+    }
+
+    #[test]
+    fn numbers_compare_numerically_and_other_values_as_strings() {
+        assert!(test("10 > 9"));
+        assert!(test("'10' > '9'"));
+        assert!(test("'2.0' == 2"));
+        assert!(test("'abc' < 'abd'"));
+        assert!(test("'10' < '9a'"));
+        assert!(test("'a' != 'b'"));
+    }
+
+    #[test]
+    fn unterminated_literals_and_parentheses_are_errors() {
+        for expression in ["'abc", "'''abc''", "(1 == 1", "1 == (2", "$lower('a'"] {
+            let result = evaluate_with(expression, &Variables::new());
+            assert!(result.is_err(), "{} evaluated to {:?}", expression, result);
+        }
+        assert!(matches!(
+            evaluate_with("'abc", &Variables::new()),
+            Err(ExecutionError::UnterminatedStringLiteral(_))
+        ));
+    }
+
+    #[test]
+    fn missing_variables_are_empty_strings() {
+        let mut variables = Variables::new();
+        variables.insert("SET", "value");
+
+        assert_eq!(evaluate_with("$(UNSET)", &variables).unwrap(), Value::String(String::new()));
+        assert!(test("$(UNSET) == ''"));
+        assert!(!test("$(UNSET)"));
+        assert!(evaluate_with("$(SET) == 'value'", &variables).unwrap().to_bool());
+        assert!(evaluate_with("$(UNSET|'fallback') == 'fallback'", &variables).unwrap().to_bool());
+    }
+
+    #[test]
+    fn has_tests_for_substrings() {
+        let mut variables = Variables::new();
+        variables.insert("HTTP_USER_AGENT", "Mozilla/5.0 (iPhone) Mobile Safari");
+        let test = |expression| evaluate_with(expression, &variables).unwrap().to_bool();
+
+        assert!(test("$(HTTP_USER_AGENT) has 'Mobile'"));
+        assert!(!test("$(HTTP_USER_AGENT) has 'mobile'"));
+        assert!(test("$(HTTP_USER_AGENT) has_i 'mobile'"));
+        assert!(test("$(HTTP_USER_AGENT) has ''"));
+        assert!(!test("$(UNSET) has 'Mobile'"));
+        assert!(test("!($(HTTP_USER_AGENT) has 'Android') & $(HTTP_USER_AGENT) has_i 'IPHONE'"));
+        assert!(test("12345 has 234"));
+    }
+}
@@ -0,0 +1,157 @@
+//! Functions that can be called from ESI expressions using the `$name(arg, ...)` syntax.
+//!
+//! The following functions are built in:
+//!
+//! - `$lower(string)`: converts a string to lowercase
+//! - `$len(string)`: returns the number of characters in a string
+//! - `$index(string, substring)`: returns the character index of `substring`, or `-1`
+//! - `$substr(string, start[, length])`: returns part of a string
+//! - `$url_encode(string)`: percent-encodes a string for use in a URL
+
+use crate::{ExecutionError, Result, Value};
+use std::{collections::HashMap, fmt, sync::Arc};
+
+/// A function that can be called from ESI expressions.
+pub trait Function: Send + Sync {
+    /// Calls the function with the evaluated arguments.
+    fn call(&self, args: &[Value]) -> Result<Value>;
+}
+
+impl<F> Function for F
+where
+    F: Fn(&[Value]) -> Result<Value> + Send + Sync,
+{
+    fn call(&self, args: &[Value]) -> Result<Value> {
+        self(args)
+    }
+}
+
+/// The set of functions available to expressions, keyed by name without the leading `$`.
+#[derive(Clone)]
+pub struct Functions {
+    functions: HashMap<String, Arc<dyn Function>>,
+}
+
+impl Functions {
+    /// Returns an empty set of functions, without any of the built-in functions.
+    pub fn empty() -> Self {
+        Self {
+            functions: HashMap::new(),
+        }
+    }
+
+    /// Registers a function under the given name, replacing any existing function of that name.
+    pub fn register(&mut self, name: impl Into<String>, function: impl Function + 'static) {
+        self.functions.insert(name.into(), Arc::new(function));
+    }
+
+    /// Calls the function with the given name.
+    pub fn call(&self, name: &str, args: &[Value]) -> Result<Value> {
+        match self.functions.get(name) {
+            Some(function) => function.call(args),
+            None => Err(ExecutionError::UnknownFunction(name.to_string())),
+        }
+    }
+}
+
+impl Default for Functions {
+    fn default() -> Self {
+        let mut functions = Self::empty();
+        functions.register("lower", lower);
+        functions.register("len", len);
+        functions.register("index", index);
+        functions.register("substr", substr);
+        functions.register("url_encode", url_encode);
+        functions
+    }
+}
+
+impl fmt::Debug for Functions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<&String> = self.functions.keys().collect();
+        names.sort();
+        f.debug_set().entries(names).finish()
+    }
+}
+
+fn invalid_arguments(name: &str, reason: &str) -> ExecutionError {
+    ExecutionError::InvalidFunctionArguments(name.to_string(), reason.to_string())
+}
+
+fn string_arg(name: &str, args: &[Value], index: usize) -> Result<String> {
+    match args.get(index) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(invalid_arguments(name, &format!("missing argument {}", index + 1))),
+    }
+}
+
+fn integer_arg(name: &str, args: &[Value], index: usize) -> Result<i64> {
+    match args.get(index).map(|value| value.to_string().trim().parse::<f64>()) {
+        Some(Ok(number)) => Ok(number as i64),
+        Some(Err(_)) => Err(invalid_arguments(name, &format!("argument {} must be a number", index + 1))),
+        None => Err(invalid_arguments(name, &format!("missing argument {}", index + 1))),
+    }
+}
+
+fn expect_arguments(name: &str, args: &[Value], min: usize, max: usize) -> Result<()> {
+    if args.len() < min || args.len() > max {
+        return Err(invalid_arguments(name, &format!("expected {} to {} arguments, got {}", min, max, args.len())));
+    }
+    Ok(())
+}
+
+fn lower(args: &[Value]) -> Result<Value> {
+    expect_arguments("lower", args, 1, 1)?;
+    Ok(Value::String(string_arg("lower", args, 0)?.to_lowercase()))
+}
+
+fn len(args: &[Value]) -> Result<Value> {
+    expect_arguments("len", args, 1, 1)?;
+    Ok(Value::Number(string_arg("len", args, 0)?.chars().count() as f64))
+}
+
+fn index(args: &[Value]) -> Result<Value> {
+    expect_arguments("index", args, 2, 2)?;
+    let haystack = string_arg("index", args, 0)?;
+    let needle = string_arg("index", args, 1)?;
+
+    let position = match haystack.find(&needle) {
+        Some(byte_index) => haystack[..byte_index].chars().count() as f64,
+        None => -1.0,
+    };
+
+    Ok(Value::Number(position))
+}
+
+// Negative values for `start` count from the end of the string.
+fn substr(args: &[Value]) -> Result<Value> {
+    expect_arguments("substr", args, 2, 3)?;
+    let chars: Vec<char> = string_arg("substr", args, 0)?.chars().collect();
+    let start = integer_arg("substr", args, 1)?;
+
+    let start = if start < 0 {
+        chars.len().saturating_sub(start.unsigned_abs() as usize)
+    } else {
+        (start as usize).min(chars.len())
+    };
+    let end = match args.get(2) {
+        Some(_) => start + (integer_arg("substr", args, 2)?.max(0) as usize).min(chars.len() - start),
+        None => chars.len(),
+    };
+
+    Ok(Value::String(chars[start..end].iter().collect()))
+}
+
+fn url_encode(args: &[Value]) -> Result<Value> {
+    expect_arguments("url_encode", args, 1, 1)?;
+    let mut encoded = String::new();
+
+    for byte in string_arg("url_encode", args, 0)?.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    Ok(Value::String(encoded))
+}
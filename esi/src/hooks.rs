@@ -0,0 +1,111 @@
+//! Hooks that are called around the requests for fragments, for example to rewrite the URLs of
+//! fragments, add authentication headers to requests, or strip tracking pixels from fragments.
+
+use crate::{Request, Response, Result};
+use std::{fmt, sync::Arc};
+
+/// Maps the `src` or `alt` URL of an include to the URL that is requested, e.g. to add a version
+/// parameter, or to route some paths to an internal host.
+pub trait UrlRewriter: Send + Sync {
+    /// Returns the URL to request for the given URL, after variables have been expanded in it.
+    fn rewrite(&self, url: &str) -> String;
+}
+
+impl<F> UrlRewriter for F
+where
+    F: Fn(&str) -> String + Send + Sync,
+{
+    fn rewrite(&self, url: &str) -> String {
+        self(url)
+    }
+}
+
+/// Decides whether fragments may be requested from a URL, e.g. to keep documents from requesting
+/// internal endpoints. Requests to URLs that aren't allowed aren't sent.
+pub trait UrlPolicy: Send + Sync {
+    /// Returns whether a fragment may be requested from the given URL, which is the URL that
+    /// would be requested, after it has been rewritten and passed to request hooks.
+    fn allows(&self, url: &str) -> bool;
+}
+
+impl<F> UrlPolicy for F
+where
+    F: Fn(&str) -> bool + Send + Sync,
+{
+    fn allows(&self, url: &str) -> bool {
+        self(url)
+    }
+}
+
+/// A hook that is called with each fragment request before it is sent, and may change it.
+pub trait RequestHook: Send + Sync {
+    /// Changes the request before it is sent.
+    fn before_request(&self, req: &mut Request);
+}
+
+impl<F> RequestHook for F
+where
+    F: Fn(&mut Request) + Send + Sync,
+{
+    fn before_request(&self, req: &mut Request) {
+        self(req)
+    }
+}
+
+/// A hook that is called with each fragment response that is received, and may change it or
+/// reject it by returning an error. A rejected fragment fails its include, like a failed request.
+pub trait ResponseHook: Send + Sync {
+    /// Changes or rejects the response to the given request.
+    fn after_response(&self, req: &Request, resp: &mut Response) -> Result<()>;
+}
+
+impl<F> ResponseHook for F
+where
+    F: Fn(&Request, &mut Response) -> Result<()> + Send + Sync,
+{
+    fn after_response(&self, req: &Request, resp: &mut Response) -> Result<()> {
+        self(req, resp)
+    }
+}
+
+// The hooks of a `Configuration`, which are called in the order they were added.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    pub(crate) rewriter: Option<Arc<dyn UrlRewriter>>,
+    pub(crate) url_policy: Option<Arc<dyn UrlPolicy>>,
+    pub(crate) before: Vec<Arc<dyn RequestHook>>,
+    pub(crate) after: Vec<Arc<dyn ResponseHook>>,
+}
+
+impl Hooks {
+    pub(crate) fn rewrite_url(&self, url: &str) -> String {
+        match &self.rewriter {
+            Some(rewriter) => rewriter.rewrite(url),
+            None => url.to_string(),
+        }
+    }
+
+    pub(crate) fn before_request(&self, req: &mut Request) {
+        for hook in &self.before {
+            hook.before_request(req);
+        }
+    }
+
+    pub(crate) fn after_response(&self, req: &Request, mut resp: Response) -> Result<Response> {
+        for hook in &self.after {
+            hook.after_response(req, &mut resp)?;
+        }
+        Ok(resp)
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("rewriter", &self.rewriter.is_some())
+            .field("url_policy", &self.url_policy.is_some())
+            .field("before", &self.before.len())
+            .field("after", &self.after.len())
+            .finish()
+    }
+}
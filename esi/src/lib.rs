@@ -1,234 +1,2428 @@
+#[cfg(feature = "async")]
+mod asynchronous;
+mod builder;
+pub mod cache;
+mod coalesce;
+mod config;
+mod diagnostics;
+mod expression;
+pub mod functions;
+mod hooks;
+mod metrics;
+mod parse;
+#[cfg(feature = "thread-pool")]
+mod pool;
+mod reader;
+#[cfg(feature = "serde")]
+mod serialize;
+mod template;
+mod url;
+pub mod surrogate;
+pub mod tags;
+pub mod test;
+pub mod user_agent;
+mod variables;
+mod visitor;
+
+#[cfg(feature = "async")]
+pub use asynchronous::{transform_esi_string_async, AsyncExecutionContext, BoxedResponse, DynAsyncExecutionContext};
+pub use builder::ProcessorBuilder;
+pub use bytes::Bytes;
+pub use cache::{CachePolicy, CachedResponse, FragmentCache, Freshness};
+#[cfg(feature = "cache")]
+pub use cache::MemoryCache;
+pub use coalesce::CoalescingContext;
+pub use config::{
+    CancellationToken, ConformanceMode, Configuration, HeaderForwarding, IncludeLimitAction, IncludePriority, Limits,
+    OnErrorPolicy, RetryPolicy, UnknownTagPolicy,
+};
+pub use expression::Value;
+pub use functions::{Function, Functions};
+pub use hooks::{RequestHook, ResponseHook, UrlPolicy, UrlRewriter};
+pub use parse::Position;
+#[cfg(feature = "thread-pool")]
+pub use pool::ThreadPoolContext;
+pub use tags::{TagContext, TagHandler, TagHandlers};
+pub use template::Template;
+pub use diagnostics::Warning;
+pub use reader::OutputReader;
+pub use metrics::{NoopObserver, Observer};
+pub use variables::{VariableProvider, VariableValue, Variables};
+pub use visitor::{OutputEvent, Visitor};
+
 use quick_xml::{
-    events::{BytesStart, BytesText, Event},
-    Reader, Writer,
+    events::{BytesText, Event},
+    Writer,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use std::{collections::HashMap, io::BufRead};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum ExecutionError {
     #[error("xml parsing error: {0}")]
     XMLError(#[from] quick_xml::Error),
+    #[error("i/o error: {0}")]
+    IoError(#[from] std::io::Error),
     #[error("tag `{0}` is missing required parameter `{1}`")]
     MissingRequiredParameter(String, String),
+    #[error("tag `{0}` is missing required element `{1}`")]
+    MissingRequiredElement(String, String),
+    #[error("unknown tag `{0}`")]
+    UnknownTag(String),
+    #[error("tag `{0}` is not allowed")]
+    TagNotAllowed(String),
     #[error("unexpected `{0}` closing tag")]
     UnexpectedClosingTag(String),
+    #[error("tag `{0}` is never closed")]
+    UnclosedTag(String),
     #[error("duplicate attribute detected: {0}")]
     DuplicateTagAttribute(String),
-    #[error("unknown error")]
-    Unknown,
+    #[error("invalid expression `{0}`: {1}")]
+    InvalidExpression(String, String),
+    #[error("unexpected character `{1}` in expression `{0}`")]
+    UnexpectedExpressionCharacter(String, char),
+    #[error("unterminated string literal in expression `{0}`")]
+    UnterminatedStringLiteral(String),
+    #[error("unknown function `{0}`")]
+    UnknownFunction(String),
+    #[error("invalid arguments to function `{0}`: {1}")]
+    InvalidFunctionArguments(String, String),
+    #[error("invalid regular expression `{0}`: {1}")]
+    InvalidRegex(String, String),
+    #[error("{0} requires the `{1}` feature to be enabled")]
+    FeatureNotEnabled(String, &'static str),
+    #[error("request to `{0}` timed out")]
+    Timeout(String),
+    #[error("request to `{0}` failed with status {1}")]
+    UnexpectedStatus(String, u16),
+    #[error("include cycle detected: {0}")]
+    IncludeCycle(String),
+    #[error("response from `{0}` has unsupported content encoding `{1}`")]
+    UnsupportedContentEncoding(String, String),
+    #[error("response from `{0}` was rejected: {1}")]
+    ResponseRejected(String, String),
+    #[error("request to `{0}` with method `{1}` is not allowed")]
+    MethodNotAllowed(String, String),
+    #[error("request to `{0}` is not allowed")]
+    UrlNotAllowed(String),
+    #[error("esi blocks are nested more than {0} levels deep")]
+    NestingTooDeep(usize),
+    #[error("{0} exceeds the limit of {1}")]
+    LimitExceeded(&'static str, usize),
+    #[error("{source} at {position}")]
+    AtPosition {
+        source: Box<ExecutionError>,
+        position: Position,
+    },
+    #[error("`{tag}` of `{src}` at {position} failed: {source}")]
+    FragmentFailed {
+        tag: String,
+        src: String,
+        position: Position,
+        source: Box<ExecutionError>,
+    },
+    #[error("request to `{url}` failed: {source}")]
+    RequestError {
+        url: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("{0}")]
+    RequestFailed(String),
+    #[error("time limit for processing the document exceeded")]
+    TimeLimitExceeded,
+    #[error("processing of the document was cancelled")]
+    Cancelled,
+    // Stops execution at a request whose response isn't available yet, see `asynchronous`
+    #[error("execution suspended until a request completes")]
+    Suspended,
+}
+
+impl ExecutionError {
+    /// Creates the error for a request to `url` that failed with the given error, which is kept as
+    /// the error's `source()`. Meant for `ExecutionContext`s to report the errors of their HTTP
+    /// client.
+    pub fn request(url: impl Into<String>, source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        ExecutionError::RequestError {
+            url: url.into(),
+            source: source.into(),
+        }
+    }
+
+    /// Returns the position in the document of the tag that caused the error, if known.
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            ExecutionError::AtPosition { position, .. } | ExecutionError::FragmentFailed { position, .. } => Some(*position),
+            _ => None,
+        }
+    }
+
+    /// Returns the URL that the error is for, if it is the error of an include, an `<esi:eval>` or
+    /// a fragment request. For failed includes, this is the include's `src` after variables were
+    /// expanded in it.
+    pub fn url(&self) -> Option<&str> {
+        match self {
+            ExecutionError::FragmentFailed { src, .. } => Some(src),
+            ExecutionError::Timeout(url)
+            | ExecutionError::UnexpectedStatus(url, _)
+            | ExecutionError::UnsupportedContentEncoding(url, _)
+            | ExecutionError::ResponseRejected(url, _)
+            | ExecutionError::MethodNotAllowed(url, _)
+            | ExecutionError::UrlNotAllowed(url)
+            | ExecutionError::RequestError { url, .. } => Some(url),
+            ExecutionError::AtPosition { source, .. } => source.url(),
+            _ => None,
+        }
+    }
+
+    /// Returns the HTTP status of the response that failed a request, if the error was caused by
+    /// one.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ExecutionError::UnexpectedStatus(_, status) => Some(*status),
+            ExecutionError::AtPosition { source, .. } | ExecutionError::FragmentFailed { source, .. } => {
+                source.status()
+            }
+            _ => None,
+        }
+    }
+
+    // Returns whether execution was suspended at a request.
+    pub(crate) fn is_suspended(&self) -> bool {
+        match self {
+            ExecutionError::Suspended => true,
+            ExecutionError::AtPosition { source, .. } | ExecutionError::FragmentFailed { source, .. } => {
+                source.is_suspended()
+            }
+            _ => false,
+        }
+    }
+
+    // Returns whether the error stops the execution of the whole document, so it must never be
+    // handled as a failure by fallbacks such as `onerror` or `esi:except`.
+    pub(crate) fn aborts(&self) -> bool {
+        match self {
+            ExecutionError::Suspended | ExecutionError::TimeLimitExceeded | ExecutionError::Cancelled => true,
+            ExecutionError::AtPosition { source, .. } | ExecutionError::FragmentFailed { source, .. } => source.aborts(),
+            _ => false,
+        }
+    }
+
+    // Returns a copy of a request's error, for results that are shared by several requests. Errors
+    // that retries and fallbacks depend on are copied exactly, and others by their message.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            ExecutionError::Timeout(url) => ExecutionError::Timeout(url.clone()),
+            ExecutionError::UnexpectedStatus(url, status) => ExecutionError::UnexpectedStatus(url.clone(), *status),
+            err => ExecutionError::RequestFailed(err.to_string()),
+        }
+    }
+
+    // Attaches a position to the error, unless it already has one.
+    pub(crate) fn at(self, position: Position) -> Self {
+        match self {
+            ExecutionError::AtPosition { .. } | ExecutionError::FragmentFailed { .. } => self,
+            _ => ExecutionError::AtPosition {
+                source: Box::new(self),
+                position,
+            },
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, ExecutionError>;
 
+/// Response headers as name and value pairs, in the order they were received.
+pub type Headers = Vec<(String, String)>;
+
 /// A request initiated by the ESI executor.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Request {
+    /// The HTTP method, `GET` unless the include sets a `method` attribute.
+    pub method: String,
+    /// The URL of the fragment, from the `src` or `alt` attribute of an include.
     pub url: String,
+    /// Headers to add to the request, in addition to any the `ExecutionContext` sets itself. These
+    /// include the headers of the client request that are forwarded by the configured
+    /// `HeaderForwarding`, so contexts shouldn't copy headers from the client request themselves.
+    pub headers: Vec<(String, String)>,
+    /// The time by which the response must have been received, set by the `maxwait` or `timeout`
+    /// attribute of an include, or by the configured default timeout. Contexts should abandon the
+    /// request once it has passed.
+    pub deadline: Option<Instant>,
+    /// The request body, set by the `entity` attribute of an include.
+    pub body: Option<Vec<u8>>,
+    /// The tag and document that the request is sent for, or `None` if it wasn't sent for a tag.
+    pub context: Option<RequestContext>,
+}
+
+/// Information about the tag and document that a request is sent for, so that an
+/// `ExecutionContext` can route or authorize requests depending on where they come from.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    /// The name of the tag, such as `esi:include`.
+    pub tag: String,
+    /// The attributes of the tag as they appear in the document, without variables expanded,
+    /// sorted by name.
+    pub attributes: Vec<(String, String)>,
+    /// The number of fragments that the document containing the tag is nested within.
+    pub depth: usize,
+    /// The URL of the top-level document, if it was set with `Variables::set_document_url`.
+    pub document_url: Option<String>,
+    /// The variables of the document at the time of the request.
+    pub variables: Variables,
+}
+
+impl RequestContext {
+    /// Returns the value of the tag's attribute with the given name.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
 }
 
 impl Request {
-    fn from_url(url: &str) -> Self {
+    /// Creates a `GET` request for the given URL, without headers or a body.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            method: "GET".to_string(),
+            url: url.into(),
+            headers: Vec::new(),
+            deadline: None,
+            body: None,
+            context: None,
+        }
+    }
+
+    /// Sets the HTTP method, which is converted to uppercase.
+    pub fn with_method(mut self, method: impl Into<String>) -> Self {
+        self.method = method.into().to_ascii_uppercase();
+        self
+    }
+
+    /// Adds a header, keeping any other headers with the same name.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets the request body.
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Sets the time by which the response must have been received.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Returns the value of the first header with the given name, matched case-insensitively.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the values of all headers with the given name, matched case-insensitively.
+    pub fn get_header_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Replaces all headers with the given name, matched case-insensitively, with a single header.
+    pub fn set_header(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove_header(&name);
+        self.headers.push((name, value.into()));
+    }
+
+    /// Removes all headers with the given name, matched case-insensitively.
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+    }
+}
+
+// Identifies identical requests, so their responses can be reused. Deadlines are left out, as
+// they are computed afresh for each include.
+#[derive(PartialEq, Eq, Hash)]
+pub(crate) struct RequestKey {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+}
+
+impl From<&Request> for RequestKey {
+    fn from(req: &Request) -> Self {
         Self {
-            url: url.to_string(),
+            method: req.method.clone(),
+            url: req.url.clone(),
+            headers: req.headers.clone(),
+            body: req.body.clone(),
         }
     }
 }
 
 /// A response from the local `ExecutionContext` implementation.
 /// Usually the result of a `Request`.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Response {
     pub body: Vec<u8>,
     pub status_code: u16,
+    /// The response headers, such as the `Cache-Control` header that decides how long the
+    /// response is kept in the fragment cache.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Response {
+    /// Creates a response with the given status and body, without headers.
+    pub fn new(status_code: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            body: body.into(),
+            status_code,
+            headers: Vec::new(),
+        }
+    }
+
+    /// Adds a header, keeping any other headers with the same name.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Returns the value of the first header with the given name, matched case-insensitively.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the `Content-Type` header.
+    pub fn content_type(&self) -> Option<&str> {
+        self.get_header("content-type")
+    }
+
+    /// Returns the `Content-Encoding` header. Fragments with an encoding other than `identity`
+    /// fail to be included, as they can't be inserted into the document.
+    pub fn content_encoding(&self) -> Option<&str> {
+        self.get_header("content-encoding")
+    }
+
+    /// Returns the `Cache-Control` header.
+    pub fn cache_control(&self) -> Option<&str> {
+        self.get_header("cache-control")
+    }
+
+    /// Returns whether the body is text that may contain ESI markup, according to its
+    /// `Content-Type`. Bodies without a `Content-Type` are assumed to be text. Only text
+    /// fragments are processed when recursion is enabled.
+    pub fn is_text(&self) -> bool {
+        let media_type = match self.content_type() {
+            Some(content_type) => content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase(),
+            None => return true,
+        };
+        let (kind, subtype) = media_type.split_once('/').unwrap_or((media_type.as_str(), ""));
+        kind == "text"
+            || subtype.ends_with("xml")
+            || subtype.ends_with("html")
+            || subtype.ends_with("json")
+            || subtype.ends_with("javascript")
+    }
 }
 
 /// Handles requests to backends as part of the ESI execution process.
 /// Implemented by `esi_fastly::FastlyRequestHandler`.
+///
+/// The trait is object safe, and implemented for references, `Box` and `Arc`, so the context can
+/// be chosen at runtime and kept as a `Box<dyn ExecutionContext>`:
+///
+/// ```
+/// use esi::{transform_esi_string, ExecutionContext, Request, Response, Result, Variables};
+///
+/// struct Backend(&'static str);
+///
+/// impl ExecutionContext for Backend {
+///     fn send_request(&self, req: Request) -> Result<Response> {
+///         Ok(Response::new(200, format!("{}:{}", self.0, req.url)))
+///     }
+/// }
+///
+/// let live = true;
+/// let client: Box<dyn ExecutionContext> = if live { Box::new(Backend("live")) } else { Box::new(Backend("test")) };
+/// let output = transform_esi_string("<esi:include src=\"/a\"/>".as_bytes(), &*client, &Variables::new())?;
+/// assert_eq!(output, b"live:/a");
+/// # Ok::<(), esi::ExecutionError>(())
+/// ```
 pub trait ExecutionContext {
     /// Sends a request to the given URL and returns either an error or the response body.
     /// Returns response body.
     fn send_request(&self, req: Request) -> Result<Response>;
+
+    /// Starts sending a request without waiting for its response, so that the fragments of a
+    /// document can be fetched concurrently. The default implementation sends the request with
+    /// `send_request` once the response is waited for.
+    fn send_request_async(&self, req: Request) -> PendingRequest<'_> {
+        PendingRequest::new(move || self.send_request(req))
+    }
+}
+
+impl<C: ExecutionContext + ?Sized> ExecutionContext for &C {
+    fn send_request(&self, req: Request) -> Result<Response> {
+        (**self).send_request(req)
+    }
+
+    fn send_request_async(&self, req: Request) -> PendingRequest<'_> {
+        (**self).send_request_async(req)
+    }
+}
+
+impl<C: ExecutionContext + ?Sized> ExecutionContext for Box<C> {
+    fn send_request(&self, req: Request) -> Result<Response> {
+        (**self).send_request(req)
+    }
+
+    fn send_request_async(&self, req: Request) -> PendingRequest<'_> {
+        (**self).send_request_async(req)
+    }
+}
+
+impl<C: ExecutionContext + ?Sized> ExecutionContext for Arc<C> {
+    fn send_request(&self, req: Request) -> Result<Response> {
+        (**self).send_request(req)
+    }
+
+    fn send_request_async(&self, req: Request) -> PendingRequest<'_> {
+        (**self).send_request_async(req)
+    }
+}
+
+/// A request that has been sent by an `ExecutionContext`, but whose response may not have been
+/// received yet.
+pub struct PendingRequest<'a> {
+    wait: Box<dyn FnOnce() -> Result<Response> + 'a>,
+}
+
+impl<'a> PendingRequest<'a> {
+    /// Creates a pending request that waits for its response by calling `wait`.
+    pub fn new(wait: impl FnOnce() -> Result<Response> + 'a) -> Self {
+        Self { wait: Box::new(wait) }
+    }
+
+    /// Creates a pending request whose response has already been received.
+    pub fn ready(result: Result<Response>) -> Self {
+        Self::new(move || result)
+    }
+
+    /// Waits for the response to the request.
+    pub fn wait(self) -> Result<Response> {
+        (self.wait)()
+    }
 }
 
 /// Representation of an ESI tag from a source response.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Tag {
-    name: Vec<u8>,                         // "include"
-    content: Option<String>,               // "hello world"
-    parameters: HashMap<Vec<u8>, Vec<u8>>, // src = "/a.html"
+    name: String,                        // "esi:include"
+    content: Option<String>,             // "hello world"
+    parameters: HashMap<String, String>, // src = "/a.html"
+    // Entries within the tag, such as the fallback content of an `<esi:include>`
+    children: Vec<TagEntry>,
+    // The position of the tag in the document
+    position: Position,
 }
 
 impl Tag {
+    /// Creates a tag with the given full name, e.g. `esi:include`, and no attributes, content or
+    /// children, to be added to a parsed document. The position of the tag is the start of the
+    /// document unless it is set with `with_position`.
+    ///
+    /// # Examples
+    /// ```
+    /// use esi::{Tag, TagEntry};
+    ///
+    /// let tag = Tag::new("esi:include").with_attribute("src", "/nav").with_attribute("onerror", "continue");
+    /// assert_eq!(tag.name(), "esi:include");
+    /// assert_eq!(tag.attr("src"), Some("/nav"));
+    /// let entry = TagEntry::Tag(tag);
+    /// ```
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            content: None,
+            parameters: HashMap::new(),
+            children: Vec::new(),
+            position: Position::default(),
+        }
+    }
+
+    /// Sets the value of an attribute, replacing any value it already has.
+    pub fn with_attribute(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.parameters.insert(name.into(), value.into());
+        self
+    }
+
+    /// Sets the text content of the tag, which is the value of an `<esi:assign>` tag without a
+    /// `value` attribute.
+    pub fn with_content(mut self, content: impl Into<String>) -> Self {
+        self.content = Some(content.into());
+        self
+    }
+
+    /// Sets the entries within the tag, such as the fallback content of an `<esi:include>`.
+    pub fn with_children(mut self, children: Vec<TagEntry>) -> Self {
+        self.children = children;
+        self
+    }
+
+    /// Sets the position of the tag, which errors about it refer to.
+    pub fn with_position(mut self, position: Position) -> Self {
+        self.position = position;
+        self
+    }
+
+    /// Returns the full name of the tag, e.g. `app:widget`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the value of the attribute with the given name, without any variable references
+    /// it contains expanded.
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.parameters.get(name).map(String::as_str)
+    }
+
+    /// Returns an iterator over the names and values of the tag's attributes, in no particular
+    /// order.
+    pub fn attributes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.parameters.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Returns the position of the tag in the document.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Returns the text content of the tag, which only `<esi:assign>` tags without a `value`
+    /// attribute have.
+    pub fn content(&self) -> Option<&str> {
+        self.content.as_deref()
+    }
+
+    /// Returns the entries within the tag, such as the fallback content of an `<esi:include>`.
+    pub fn children(&self) -> &[TagEntry] {
+        &self.children
+    }
+
+    /// Returns the entries within the tag, to be rewritten.
+    pub fn children_mut(&mut self) -> &mut Vec<TagEntry> {
+        &mut self.children
+    }
+
     fn get_param(&self, key: &str) -> Option<String> {
-        self.parameters.get(key.as_bytes()).map(|value| String::from_utf8(value.to_owned()).unwrap())
+        self.parameters.get(key).cloned()
     }
 }
 
-pub struct TagEntry<'a> {
-    event: Option<Event<'a>>,
-    esi_tag: Option<Tag>,
+/// A single `<esi:when>` branch of an `<esi:choose>` block.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct When {
+    /// The expression in the `test` attribute.
+    pub test: String,
+    /// The entries that are executed if `test` is the first test that is true.
+    pub entries: Vec<TagEntry>,
 }
 
-// This could be much cleaner but I'm not good enough at Rust for that
-fn parse_attributes(bytes: BytesStart) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
-    let mut map: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+/// An entry in a parsed document. Either non-ESI content that is passed through to the output
+/// exactly as it appeared in the source, a standalone ESI tag, or an ESI block containing further
+/// entries. Content is shared with the buffer the document was parsed from, rather than copied.
+///
+/// A parsed document is a list of entries, returned by `Template::entries`. It may be inspected
+/// or rewritten, and executed with `Template::from_entries`. Constructs that are removed by the
+/// parser, such as `<esi:comment>` and `<esi:remove>`, don't appear in it, and the content of
+/// `<!--esi ... -->` comments appears in place of the comment.
+///
+/// With the `serde` feature, entries can be serialized and deserialized, e.g. to dump parsed
+/// templates. Content, tag names and attributes are strings, or arrays of bytes where they aren't
+/// valid UTF-8.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum TagEntry {
+    /// Content that isn't ESI.
+    Raw(#[cfg_attr(feature = "serde", serde(with = "serialize::bytes_string"))] Bytes),
+    /// A tag without entries of its own, such as `<esi:include>`, `<esi:assign>`, `<esi:eval>`,
+    /// `<esi:debug>` or a custom tag. The fallback content of an include and the content of a
+    /// custom tag are its children.
+    Tag(Tag),
+    /// An `<esi:vars>` block, within which variable references are expanded.
+    Vars(Vec<TagEntry>),
+    /// The content of an `<esi:text>` block, which is passed through without being processed.
+    Text(#[cfg_attr(feature = "serde", serde(with = "serialize::bytes_string"))] Bytes),
+    /// An `<esi:try>` block.
+    Try {
+        /// The entries of the `<esi:attempt>` block.
+        attempt: Vec<TagEntry>,
+        /// The entries of the `<esi:except>` block, if there is one.
+        except: Option<Vec<TagEntry>>,
+    },
+    /// An `<esi:choose>` block.
+    Choose {
+        /// The `<esi:when>` branches, in document order.
+        whens: Vec<When>,
+        /// The entries of the `<esi:otherwise>` branch, if there is one.
+        otherwise: Option<Vec<TagEntry>>,
+    },
+    /// An `<esi:inline>` fragment, which is rendered wherever an include's `src` is its name.
+    Inline {
+        /// The `name` attribute.
+        name: String,
+        /// Whether the `fetchable` attribute is `yes`.
+        fetchable: bool,
+        /// The unprocessed content of the fragment, which fetchable fragments are cached with.
+        #[cfg_attr(feature = "serde", serde(with = "serialize::bytes_string"))]
+        content: Bytes,
+        /// The content of the fragment.
+        entries: Vec<TagEntry>,
+    },
+}
 
-    for entry in bytes.attributes().flatten() {
-        if map.insert(entry.key.to_vec(), entry.value.to_vec()).is_some() {
-            return Err(ExecutionError::DuplicateTagAttribute(String::from_utf8(entry.key.to_vec()).unwrap()));
+// Collects the `<esi:inline>` fragments declared anywhere in the document, keyed by name.
+fn collect_inline_fragments(entries: &[TagEntry], fragments: &mut HashMap<String, Arc<[TagEntry]>>) {
+    for entry in entries {
+        match entry {
+            TagEntry::Inline { name, entries, .. } => {
+                fragments.insert(name.clone(), entries.as_slice().into());
+                collect_inline_fragments(entries, fragments);
+            }
+            TagEntry::Vars(entries) => collect_inline_fragments(entries, fragments),
+            TagEntry::Try { attempt, except } => {
+                collect_inline_fragments(attempt, fragments);
+                if let Some(except) = except {
+                    collect_inline_fragments(except, fragments);
+                }
+            }
+            TagEntry::Choose { whens, otherwise } => {
+                for when in whens {
+                    collect_inline_fragments(&when.entries, fragments);
+                }
+                if let Some(otherwise) = otherwise {
+                    collect_inline_fragments(otherwise, fragments);
+                }
+            }
+            TagEntry::Tag(tag) => collect_inline_fragments(&tag.children, fragments),
+            TagEntry::Raw(_) | TagEntry::Text(_) => {}
         }
     }
+}
 
-    Ok(map)
+// Requests for the includes of a list of entries that were started ahead of executing them.
+#[derive(Default)]
+struct Prefetched<'a> {
+    // The index of each include along with its request, and the pending response if the request
+    // was sent rather than being left to the include
+    pending: Vec<(usize, Request, Option<PendingRequest<'a>>)>,
+    // The priority and index of the includes that may be prefetched but haven't been started yet
+    waiting: Vec<(IncludePriority, usize)>,
+    // The index of the next entry that may need to be prefetched
+    next: usize,
+    // The branches of `<esi:choose>` blocks that were selected ahead of executing them, by index
+    branches: Vec<(usize, Branch<'a>)>,
 }
 
-fn parse_tag_entries<'a>(body: impl BufRead) -> Result<Vec<TagEntry<'a>>> {
-    let mut reader = Reader::from_reader(body);
-    let mut buf = Vec::new();
+impl<'a> Prefetched<'a> {
+    fn take(&mut self, index: usize) -> Option<(Request, Option<PendingRequest<'a>>)> {
+        let position = self.pending.iter().position(|(i, _, _)| *i == index)?;
+        let (_, req, pending) = self.pending.remove(position);
+        Some((req, pending))
+    }
+
+    fn take_branch(&mut self, index: usize) -> Option<Branch<'a>> {
+        let position = self.branches.iter().position(|(i, _)| *i == index)?;
+        Some(self.branches.remove(position).1)
+    }
+
+    // Returns the number of requests that are pending, including those within selected branches.
+    fn len(&self) -> usize {
+        self.pending.iter().filter(|(_, _, pending)| pending.is_some()).count()
+            + self.branches.iter().map(|(_, branch)| branch.prefetched.len()).sum::<usize>()
+    }
+}
 
-    let mut events: Vec<TagEntry> = Vec::new();
-    let mut remove = false;
+// The selected branch of an `<esi:choose>` block.
+#[derive(Default)]
+struct Branch<'a> {
+    // The index of the selected `<esi:when>`, or `None` for `<esi:otherwise>`
+    when: Option<usize>,
+    // The evaluated tests, rendered by `<esi:debug>`
+    tests: Vec<String>,
+    // Requests for the includes of the branch that were started ahead of executing it
+    prefetched: Prefetched<'a>,
+}
 
-    // Parse tags and build events vec
-    loop {
-        buf.clear();
-        match reader.read_event(&mut buf) {
-            // Handle <esi:remove> tags
-            Ok(Event::Start(elem)) if elem.starts_with(b"esi:remove") => {
-                remove = true;
+// Returns whether executing the entry may assign variables, which prevents the includes after it
+// from being prefetched.
+fn may_assign(entry: &TagEntry) -> bool {
+    match entry {
+        TagEntry::Tag(tag) => matches!(tag.name.as_str(), "esi:assign" | "esi:eval"),
+        TagEntry::Choose { .. } | TagEntry::Try { .. } | TagEntry::Vars(_) => true,
+        TagEntry::Raw(_) | TagEntry::Text(_) | TagEntry::Inline { .. } => false,
+    }
+}
+
+// State shared by a top-level document and all of its fragments.
+struct DocumentState<'a> {
+    // The number of includes executed so far
+    includes: Cell<usize>,
+    // The time by which the document must be processed, from the configured time limit
+    deadline: Option<Instant>,
+    // The time after which low-priority includes are dropped
+    low_priority_deadline: Option<Instant>,
+    // The visitor that receives the output, which is told about the includes of the top-level
+    // document
+    visitor: Option<&'a RefCell<dyn Visitor + 'a>>,
+    // Warnings about the document and its fragments
+    warnings: RefCell<Vec<Warning>>,
+    // The configured headers of the fragments that were included
+    headers: RefCell<Headers>,
+    // The combined cache policy of the fragments that were requested, or `None` if there weren't any
+    cache_policy: Cell<Option<CachePolicy>>,
+}
+
+impl<'a> DocumentState<'a> {
+    fn new(config: &Configuration) -> Rc<Self> {
+        Self::with_visitor(config, None)
+    }
+
+    fn with_visitor(config: &Configuration, visitor: Option<&'a RefCell<dyn Visitor + 'a>>) -> Rc<Self> {
+        Rc::new(Self {
+            includes: Cell::new(0),
+            deadline: config.time_limit.and_then(|time_limit| Instant::now().checked_add(time_limit)),
+            low_priority_deadline: config
+                .low_priority_time_limit
+                .and_then(|time_limit| Instant::now().checked_add(time_limit)),
+            visitor,
+            warnings: RefCell::new(Vec::new()),
+            headers: RefCell::new(Vec::new()),
+            cache_policy: Cell::new(None),
+        })
+    }
+
+    // Fails once the document's time limit has passed, or its processing has been cancelled.
+    fn check(&self, config: &Configuration) -> Result<()> {
+        if config.cancellation.as_ref().is_some_and(|token| token.is_cancelled()) {
+            return Err(ExecutionError::Cancelled);
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(ExecutionError::TimeLimitExceeded);
+        }
+        Ok(())
+    }
+
+    // Records a warning about the document, which is returned by
+    // `transform_esi_string_with_diagnostics`.
+    fn warn(&self, warning: Warning) {
+        self.warnings.borrow_mut().push(warning);
+    }
+
+    // Records the collected headers of an included fragment, which are returned by
+    // `transform_esi_string_with_headers`. Identical includes share a response, so headers that
+    // have already been collected aren't repeated.
+    fn collect_headers(&self, headers: Headers) {
+        let mut collected = self.headers.borrow_mut();
+        for header in headers {
+            if !collected.contains(&header) {
+                collected.push(header);
             }
-            Ok(Event::End(elem)) if elem.starts_with(b"esi:remove") => {
-                if !remove {
-                    return Err(ExecutionError::UnexpectedClosingTag(String::from_utf8(elem.to_vec()).unwrap()));
+        }
+    }
+
+    // Combines the cache policy of a requested fragment, or of an include that is missing from the
+    // output, into the document's, which is returned by `transform_esi_string_with_cache_policy`.
+    fn restrict_cache(&self, policy: CachePolicy) {
+        let combined = self.cache_policy.get().map_or(policy, |current| current.combine(policy));
+        self.cache_policy.set(Some(combined));
+    }
+
+    // Returns whether includes with the given priority are dropped rather than requested.
+    fn drops(&self, priority: IncludePriority) -> bool {
+        priority == IncludePriority::Low && self.low_priority_deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+// State shared across the execution of a single document.
+struct Executor<'a, C: ExecutionContext + ?Sized> {
+    client: &'a C,
+    // Document-local copy of the variables, so that `<esi:assign>` doesn't affect other documents
+    variables: Variables,
+    config: &'a Configuration,
+    // The number of fragments this document is nested within
+    depth: usize,
+    // The number of `<esi:eval>` fragments that are being executed within this document
+    evals: usize,
+    fragments: HashMap<String, Arc<[TagEntry]>>,
+    // Evaluated expressions and include results, rendered by `<esi:debug>`
+    debug_log: Vec<String>,
+    // Successful include responses, reused by later includes of the same request
+    responses: HashMap<RequestKey, Response>,
+    document: Rc<DocumentState<'a>>,
+    // The URLs of the fragments that this document is nested within, and the names of the inline
+    // fragments that are being rendered, outermost first
+    chain: Vec<String>,
+    // Requests that refresh stale responses from the fragment cache, by cache key
+    refreshes: Vec<(String, Request, PendingRequest<'a>)>,
+    // Buffers for the output of `<esi:attempt>` blocks and fragments, kept for reuse
+    buffers: Vec<Vec<u8>>,
+    // The number of blocks being executed whose output is buffered until they are complete
+    buffered: usize,
+}
+
+// The maximum number of buffers an executor keeps for reuse.
+const MAX_SPARE_BUFFERS: usize = 4;
+
+impl<'a, C: ExecutionContext + ?Sized> Executor<'a, C> {
+    fn new(
+        client: &'a C,
+        variables: &Variables,
+        config: &'a Configuration,
+        document: Rc<DocumentState<'a>>,
+        chain: Vec<String>,
+    ) -> Self {
+        Self {
+            client,
+            variables: variables.clone(),
+            config,
+            depth: chain.len(),
+            evals: 0,
+            fragments: HashMap::new(),
+            debug_log: Vec::new(),
+            responses: HashMap::new(),
+            document,
+            chain,
+            refreshes: Vec::new(),
+            buffers: Vec::new(),
+            buffered: 0,
+        }
+    }
+
+    // Executes all entries in document order and writes the resulting content to `writer`.
+    // Only the selected branch of an `<esi:choose>` block is executed, so the includes of other
+    // branches are never requested. Variable references are expanded in entries that are within
+    // an `<esi:vars>` block.
+    fn execute(&mut self, entries: &[TagEntry], in_vars: bool, writer: &mut Writer<impl Write>) -> Result<()> {
+        self.execute_prefetched(entries, in_vars, Prefetched::default(), writer)
+    }
+
+    // Executes entries whose includes may already have been requested.
+    fn execute_prefetched(
+        &mut self,
+        entries: &[TagEntry],
+        in_vars: bool,
+        mut prefetched: Prefetched<'a>,
+        writer: &mut Writer<impl Write>,
+    ) -> Result<()> {
+        for (index, entry) in entries.iter().enumerate() {
+            self.document.check(self.config)?;
+            self.prefetch(entries, index, &mut prefetched, 0);
+
+            match entry {
+                TagEntry::Raw(content) if in_vars => {
+                    writer.inner().write_all(&variables::substitute(content, &self.variables))?;
                 }
+                TagEntry::Raw(content) => {
+                    writer.inner().write_all(content)?;
+                }
+                TagEntry::Tag(tag) => match tag.name.as_str() {
+                    "esi:include" => {
+                        let pending = prefetched.take(index);
+                        self.execute_include(tag, pending, in_vars, writer)?
+                    }
+                    "esi:assign" => self.execute_assign(tag)?,
+                    "esi:eval" => self.execute_eval(tag)?,
+                    "esi:debug" => self.write_debug(writer)?,
+                    name => {
+                        if let Some(handler) = self.config.tag_handlers.get(name.as_bytes()) {
+                            self.execute_custom_tag(tag, handler, in_vars, writer)?
+                        }
+                    }
+                },
+                TagEntry::Choose { whens, otherwise } => {
+                    let branch = match prefetched.take_branch(index) {
+                        Some(branch) => branch,
+                        None => self.select_branch(whens)?,
+                    };
+                    self.debug_log.extend(branch.tests);
+                    let selected = match branch.when {
+                        Some(when) => Some(&whens[when].entries),
+                        None => otherwise.as_ref(),
+                    };
+
+                    if let Some(entries) = selected {
+                        self.execute_prefetched(entries, in_vars, branch.prefetched, writer)?;
+                    }
+                }
+                TagEntry::Vars(entries) => {
+                    self.execute(entries, true, writer)?;
+                }
+                TagEntry::Text(content) => {
+                    writer.inner().write_all(content)?;
+                }
+                TagEntry::Try { attempt, except } => {
+                    // Buffer the attempt so that nothing is written if it fails part-way through,
+                    // sending everything before it on while its includes are fetched
+                    writer.inner().flush()?;
+                    let mut attempt_writer = Writer::new(self.buffer(0));
+                    self.buffered += 1;
+                    let result = self.execute(attempt, in_vars, &mut attempt_writer);
+                    self.buffered -= 1;
+                    let attempt_output = attempt_writer.into_inner();
+                    match result {
+                        Ok(()) => {
+                            writer.inner().write_all(&attempt_output)?;
+                            self.recycle(attempt_output);
+                        }
+                        Err(err) if err.aborts() => return Err(err),
+                        Err(err) => {
+                            self.recycle(attempt_output);
+                            self.document.warn(Warning::AttemptFailed(err.to_string()));
+                            if let Some(except) = except {
+                                self.execute(except, in_vars, writer)?;
+                            }
+                        }
+                    }
+                }
+                // Inline fragments are only rendered where they are included, and fetchable ones are
+                // kept for the includes of later documents
+                TagEntry::Inline {
+                    name,
+                    fetchable: true,
+                    content,
+                    ..
+                } => self.store_inline_fragment(name, content),
+                TagEntry::Inline { .. } => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    // Starts the requests for the includes from `index` onwards, so that they are fetched
+    // concurrently. Requests are only started up to the next entry that may assign variables, as
+    // the URLs of later includes may depend on them, and no more than the configured number of
+    // requests are pending at once, counting the `outer` requests of enclosing entries.
+    // High-priority includes are requested first, and low-priority ones last.
+    //
+    // As nothing before that entry assigns variables, the tests of an `<esi:choose>` block there
+    // are evaluated ahead of executing it, and the includes of the selected branch are requested
+    // as well.
+    fn prefetch(&self, entries: &[TagEntry], index: usize, prefetched: &mut Prefetched<'a>, outer: usize) {
+        prefetched.next = prefetched.next.max(index);
+        prefetched.waiting.retain(|&(_, waiting)| waiting >= index);
+
+        while let Some(entry) = entries.get(prefetched.next) {
+            if may_assign(entry) || self.includes_inline_fragment(entry) {
+                break;
+            }
+            if let TagEntry::Tag(tag) = entry {
+                if tag.name == "esi:include" {
+                    prefetched.waiting.push((tag_priority(tag), prefetched.next));
+                }
+            }
+            prefetched.next += 1;
+        }
+
+        while outer + prefetched.len() < self.config.max_concurrent_requests()
+            && self.config.max_includes().is_none_or(|max| self.document.includes.get() + outer + prefetched.len() < max)
+        {
+            // The first of the waiting includes with the highest priority
+            let next = prefetched.waiting.iter().enumerate().min_by_key(|(_, waiting)| **waiting);
+            let waiting = match next {
+                Some((position, _)) => prefetched.waiting.remove(position).1,
+                None => break,
+            };
+            if let TagEntry::Tag(tag) = &entries[waiting] {
+                if let Some(req) = self.include_request(tag) {
+                    // Cached responses and duplicates of an earlier include are reused instead, and
+                    // requests that aren't allowed are left to fail when the include is executed
+                    let key = RequestKey::from(&req);
+                    let duplicate = self.cached_response(&req).is_some()
+                        || (self.deduplicates(&req)
+                            && prefetched.pending.iter().any(|(_, r, p)| p.is_some() && RequestKey::from(r) == key));
+                    let allowed = self.config.allows_method(&req.method) && self.config.allows_url(&req.url);
+                    let pending = (allowed && !duplicate).then(|| self.client.send_request_async(req.clone()));
+                    prefetched.pending.push((waiting, req, pending));
+                }
+            }
+        }
+
+        if let Some(TagEntry::Choose { whens, otherwise }) = entries.get(prefetched.next) {
+            let choose = prefetched.next;
+            if !prefetched.branches.iter().any(|(index, _)| *index == choose) {
+                // Tests that fail are left to fail when the block is executed
+                match self.select_branch(whens) {
+                    Ok(branch) => prefetched.branches.push((choose, branch)),
+                    Err(_) => return,
+                }
+            }
+
+            let outer = outer + prefetched.len();
+            if let Some((_, branch)) = prefetched.branches.iter_mut().find(|(index, _)| *index == choose) {
+                let selected = match branch.when {
+                    Some(when) => Some(&whens[when].entries),
+                    None => otherwise.as_ref(),
+                };
+                if let Some(entries) = selected {
+                    let outer = outer - branch.prefetched.len();
+                    self.prefetch(entries, 0, &mut branch.prefetched, outer);
+                }
+            }
+        }
+    }
+
+    // Evaluates the tests of an `<esi:choose>` block, selecting the first `<esi:when>` branch
+    // whose test is true.
+    fn select_branch(&self, whens: &[When]) -> Result<Branch<'a>> {
+        let mut branch = Branch::default();
+        for (index, when) in whens.iter().enumerate() {
+            let result = expression::evaluate(&when.test, &self.variables, &self.config.functions)?;
+            branch.tests.push(format!("when {} => {}", when.test, result));
+            if result.to_bool() {
+                branch.when = Some(index);
+                break;
+            }
+        }
+        Ok(branch)
+    }
 
-                remove = false;
+    // Returns whether the entry includes an `<esi:inline>` fragment, which may assign variables
+    // as it is rendered.
+    fn includes_inline_fragment(&self, entry: &TagEntry) -> bool {
+        match entry {
+            TagEntry::Tag(tag) if tag.name == "esi:include" && !self.fragments.is_empty() => {
+                tag.get_param("src").is_some_and(|src| self.fragments.contains_key(&self.expand(&src)))
             }
-            _ if remove => continue,
+            _ => false,
+        }
+    }
+
+    // Returns the request for an include's `src`, or `None` if it refers to an inline fragment,
+    // would include a fragment within itself, is dropped for its priority, or has no `src`. The
+    // request is built once, and kept for when the include is executed.
+    fn include_request(&self, tag: &Tag) -> Option<Request> {
+        if tag.name != "esi:include" || self.document.drops(tag_priority(tag)) {
+            return None;
+        }
+
+        let src = self.expand(&tag.get_param("src")?);
+        if self.fragments.contains_key(&src) || self.chain.contains(&src) {
+            return None;
+        }
+        Some(self.build_request(tag, &src))
+    }
+
+    // Returns whether the response to a request may be reused by identical includes, which is the
+    // case for `GET` and `HEAD` requests unless deduplication is disabled.
+    fn deduplicates(&self, req: &Request) -> bool {
+        !self.config.no_include_deduplication && matches!(req.method.as_str(), "GET" | "HEAD")
+    }
 
-            // Parse empty ESI tags
-            Ok(Event::Empty(elem)) if elem.name().starts_with(b"esi:") => {
-                events.push(TagEntry {
-                    event: None,
-                    esi_tag: Some(Tag {
-                        name: elem.name().to_vec(),
-                        parameters: parse_attributes(elem)?,
-                        content: None,
-                    }),
+    // Returns an earlier response to the same request, from this document or the fragment cache.
+    fn cached_response(&self, req: &Request) -> Option<CachedResponse> {
+        if self.deduplicates(req) {
+            if let Some(resp) = self.responses.get(&RequestKey::from(req)) {
+                return Some(CachedResponse {
+                    response: resp.clone(),
+                    stale: false,
                 });
             }
+        }
 
-            Ok(Event::Eof) => break,
-            Ok(e) => events.push(TagEntry {
-                event: Some(e.into_owned()),
-                esi_tag: None,
-            }),
-            _ => {}
+        let cache = self.config.cache.as_ref()?;
+        cache.0.get(&cache::cache_key(req)?)
+    }
+
+    // Keeps a response for later includes of the same request, storing it in the fragment cache
+    // if its `Cache-Control` header allows.
+    fn store_response(&mut self, req: &Request, resp: &Response) {
+        if self.deduplicates(req) {
+            self.responses.insert(RequestKey::from(req), resp.clone());
+        }
+
+        if let (Some(cache), Some(key), Some(freshness)) =
+            (&self.config.cache, cache::cache_key(req), cache::freshness(resp))
+        {
+            cache.0.put(&key, resp, freshness);
         }
     }
 
-    Ok(events)
-}
+    // Stores a fetchable inline fragment in the fragment cache, as the response to the request that
+    // an include of its name would make.
+    fn store_inline_fragment(&self, name: &str, content: &Bytes) {
+        if let (Some(cache), Some(ttl)) = (&self.config.cache, self.config.inline_fragment_ttl) {
+            let req = self.build_request(&Tag::new("esi:include"), name);
+            if let Some(key) = cache::cache_key(&req) {
+                let resp = Response::new(200, content.to_vec())
+                    .with_header("Cache-Control", format!("max-age={}", ttl.as_secs()));
+                let freshness = Freshness {
+                    ttl,
+                    stale_while_revalidate: Duration::ZERO,
+                };
+                cache.0.put(&key, &resp, freshness);
+            }
+        }
+    }
 
-// Executes all entries with an ESI tag, and returns a map of those entries with the entry's index as key and content as value.
-fn execute_tag_entries(
-    entries: &[TagEntry],
-    client: &impl ExecutionContext,
-) -> Result<HashMap<usize, Vec<u8>>> {
-    let mut map = HashMap::new();
+    // Starts a request to refresh a stale response from the fragment cache, unless one has already
+    // been started.
+    fn refresh(&mut self, req: Request) {
+        if let Some(key) = cache::cache_key(&req) {
+            if !self.refreshes.iter().any(|(k, _, _)| *k == key) {
+                let pending = self.client.send_request_async(req.clone());
+                self.refreshes.push((key, req, pending));
+            }
+        }
+    }
 
-    for (index, entry) in entries.iter().enumerate() {
-        match &entry.esi_tag {
-            Some(tag) => {
-                if tag.name == b"esi:include" {
-                    let src = match tag.get_param("src") {
-                        Some(src) => src,
-                        None => {
-                            return Err(ExecutionError::MissingRequiredParameter(
-                                String::from_utf8(tag.name.to_vec()).unwrap(),
-                                "src".to_string(),
-                            ));
-                        }
-                    };
+    // Waits for the requests that refresh stale responses, and stores their responses in the
+    // fragment cache.
+    fn finish_refreshes(&mut self) {
+        for (key, req, pending) in std::mem::take(&mut self.refreshes) {
+            match pending.wait().and_then(|resp| self.config.hooks.after_response(&req, resp)) {
+                Ok(resp) => {
+                    if let (Some(cache), Some(freshness)) = (&self.config.cache, cache::freshness(&resp)) {
+                        cache.0.put(&key, &resp, freshness);
+                    }
+                }
+                // Refreshes are sent once the document has been processed asynchronously
+                Err(err) if err.is_suspended() => {}
+                Err(err) => {
+                    self.document.warn(Warning::RefreshFailed {
+                        src: req.url.clone(),
+                        error: err.to_string(),
+                    });
+                }
+            }
+        }
+    }
 
-                    let alt = tag.get_param("alt");
-
-                    match send_request(&src, alt, client) {
-                        Ok(resp) => {
-                            map.insert(index, resp.body).unwrap();
-                        },
-                        Err(err) => match tag.get_param("onerror") {
-                            Some(onerror) => {
-                                if onerror == "continue" {
-                                    println!("Failed to fetch {} but continued", src);
-                                    map.insert(index, vec![]).unwrap();
-                                } else {
-                                    return Err(err);
-                                }
-                            }
-                            _ => return Err(err),
-                        },
+    // Expands variable references in an attribute value.
+    fn expand(&self, value: &str) -> String {
+        parse::decode(&variables::substitute(value.as_bytes(), &self.variables))
+    }
+
+    // Returns the URL to request for a `src` or `alt` URL, rewritten by the configured URL rewriter
+    // and resolved against the URL of the top-level document if it is relative.
+    fn request_url(&self, src: &str) -> String {
+        let url = self.config.hooks.rewrite_url(src);
+        match self.variables.document_url() {
+            Some(base) => url::resolve(base, &url),
+            None => url,
+        }
+    }
+
+    // Builds the request for a tag's `src`, at the URL returned by `request_url`, with the headers
+    // of the client request that are forwarded. Its `method`, `entity` and `headers` attributes are
+    // applied, along with the deadline from its `maxwait` or `timeout` attribute, which is never
+    // later than the document's time limit, or the low-priority time limit for low-priority
+    // includes. `headers` is a list of `Name=value` pairs separated by `;`.
+    fn build_request(&self, tag: &Tag, src: &str) -> Request {
+        let mut req = Request::new(self.request_url(src));
+        if let Some(method) = tag.get_param("method") {
+            req.method = method.to_ascii_uppercase();
+        }
+        req.body = tag.get_param("entity").map(|entity| self.expand(&entity).into_bytes());
+        let forwarding = &self.config.header_forwarding;
+        req.headers.extend(
+            self.variables
+                .request_headers()
+                .iter()
+                .filter(|(name, _)| forwarding.forwards(name))
+                .cloned(),
+        );
+        if let Some(capability) = self.config.surrogate_capability_header(None) {
+            req.headers.push(("Surrogate-Capability".to_string(), capability));
+        }
+        if let Some(headers) = tag.get_param("headers") {
+            let headers = self.expand(&headers);
+            req.headers.extend(headers.split(';').filter_map(|header| {
+                let (name, value) = header.split_once('=')?;
+                let name = name.trim();
+                if name.is_empty() {
+                    return None;
+                }
+                Some((name.to_string(), value.trim().to_string()))
+            }));
+        }
+        let limit = match tag_priority(tag) {
+            IncludePriority::Low => earliest(self.document.deadline, self.document.low_priority_deadline),
+            _ => self.document.deadline,
+        };
+        req.deadline = earliest(include_deadline(tag, self.config), limit);
+        req.context = Some(self.request_context(tag));
+        self.config.hooks.before_request(&mut req);
+        req
+    }
+
+    // Describes the tag that a request is sent for, and the document containing it.
+    fn request_context(&self, tag: &Tag) -> RequestContext {
+        let mut attributes: Vec<(String, String)> =
+            tag.parameters.iter().map(|(name, value)| (name.clone(), value.clone())).collect();
+        attributes.sort();
+        RequestContext {
+            tag: tag.name.clone(),
+            attributes,
+            depth: self.depth,
+            document_url: self.variables.document_url().map(str::to_string),
+            variables: self.variables.clone(),
+        }
+    }
+
+    // Returns the expanded value of a required attribute.
+    fn require_param(&self, tag: &Tag, key: &str) -> Result<String> {
+        match tag.get_param(key) {
+            Some(value) => Ok(self.expand(&value)),
+            None => Err(ExecutionError::MissingRequiredParameter(tag.name.clone(), key.to_string())
+                .at(tag.position)),
+        }
+    }
+
+    // Executes an `<esi:assign>` tag, setting a document-local variable to the result of evaluating
+    // its `value` attribute, or its content when it has no `value`.
+    fn execute_assign(&mut self, tag: &Tag) -> Result<()> {
+        let name = self.require_param(tag, "name")?;
+        let value = match tag.get_param("value").or_else(|| tag.content.clone()) {
+            Some(value) => value,
+            None => {
+                return Err(
+                    ExecutionError::MissingRequiredParameter(tag.name.clone(), "value".to_string())
+                        .at(tag.position),
+                );
+            }
+        };
+
+        let result = expression::evaluate(&value, &self.variables, &self.config.functions).map_err(|err| err.at(tag.position))?;
+        self.debug_log.push(format!("assign {} = {} => {}", name, value, result));
+        self.variables.insert(name, result.to_string());
+
+        Ok(())
+    }
+
+    // Executes an `<esi:eval>` tag by fetching its `src` and executing it as ESI within the scope of
+    // this document. The fragment is fetched like the fragment of an include, but its output is
+    // discarded, along with any fallback content or placeholder, while the variables it assigns
+    // are kept.
+    fn execute_eval(&mut self, tag: &Tag) -> Result<()> {
+        self.execute_include(tag, None, false, &mut Writer::new(io::sink()))
+    }
+
+    // Executes a custom tag by processing its content and passing the output to its handler,
+    // writing whatever the handler returns in place of the tag.
+    fn execute_custom_tag(
+        &mut self,
+        tag: &Tag,
+        handler: &dyn TagHandler,
+        in_vars: bool,
+        writer: &mut Writer<impl Write>,
+    ) -> Result<()> {
+        let mut content_writer = Writer::new(self.buffer(0));
+        self.buffered += 1;
+        let result = self.execute(&tag.children, in_vars, &mut content_writer);
+        self.buffered -= 1;
+        result?;
+        let content = content_writer.into_inner();
+
+        let context = TagContext {
+            client: &self.client,
+            variables: &self.variables,
+            content: &content,
+            depth: self.depth,
+        };
+        let result = handler.handle(tag, &context).map_err(|err| err.at(tag.position));
+        self.recycle(content);
+
+        writer.inner().write_all(&result?)?;
+        Ok(())
+    }
+
+    // Executes an `<esi:include>` tag, writing the content that should replace it. Includes that
+    // refer to an `<esi:inline>` fragment of the same document are rendered without a request.
+    // Variable references in `src` and `alt` are expanded before the request is made, unless it
+    // was built when the include was prefetched, along with its `pending` response. If the
+    // fragment can't be fetched, any content within the tag is rendered in its place. The
+    // fragments of `<esi:eval>` tags are executed within the scope of this document instead of
+    // being written out.
+    fn execute_include(
+        &mut self,
+        tag: &Tag,
+        prefetched: Option<(Request, Option<PendingRequest<'a>>)>,
+        in_vars: bool,
+        writer: &mut Writer<impl Write>,
+    ) -> Result<()> {
+        let src = self.require_param(tag, "src")?;
+        let eval = tag.name == "esi:eval";
+
+        // Fragments that include themselves, directly or through other fragments, would never
+        // finish rendering
+        if self.chain.contains(&src) {
+            let cycle = [&self.chain[..], std::slice::from_ref(&src)].concat().join(" -> ");
+            return self.include_failed(tag, &src, ExecutionError::IncludeCycle(cycle), in_vars, writer);
+        }
+        // `<esi:eval>` fragments count towards the recursion depth, but are always executed at the
+        // top level
+        let max_depth = self.config.recursion_depth.max(1);
+        if eval && self.depth + self.evals >= max_depth {
+            let err = ExecutionError::LimitExceeded("fragment depth", max_depth);
+            return self.include_failed(tag, &src, err, in_vars, writer);
+        }
+
+        if let Some(fragment) = self.fragments.get(&src).cloned() {
+            self.debug_log.push(format!("include {} => inline fragment", src));
+            self.chain.push(src);
+            let result = self.execute(&fragment, in_vars, writer);
+            self.chain.pop();
+            return result;
+        }
+
+        // Pages that are missing the fragments of dropped includes shouldn't be reused either
+        if self.document.drops(tag_priority(tag)) {
+            self.debug_log.push(format!("include {} => dropped for its priority", src));
+            self.document.restrict_cache(CachePolicy::from_cache_control(None));
+            self.document.warn(Warning::IncludeDropped {
+                src: src.clone(),
+                position: tag.position,
+            });
+            if !tag.children.is_empty() {
+                return self.execute(&tag.children, in_vars, writer);
+            }
+            return Ok(());
+        }
+
+        if let Some((max, action)) = &self.config.include_limit {
+            if self.document.includes.get() >= *max {
+                self.debug_log.push(format!("include {} => over the include limit", src));
+                return match action {
+                    IncludeLimitAction::Error => {
+                        Err(ExecutionError::LimitExceeded("number of includes", *max).at(tag.position))
+                    }
+                    IncludeLimitAction::Placeholder(placeholder) => {
+                        self.document.restrict_cache(CachePolicy::from_cache_control(None));
+                        self.document.warn(Warning::IncludeLimitReached {
+                            src: src.clone(),
+                            position: tag.position,
+                        });
+                        writer.inner().write_all(placeholder.as_bytes())?;
+                        Ok(())
                     }
+                };
+            }
+        }
+        self.document.includes.set(self.document.includes.get() + 1);
+
+        // `alt` may contain several whitespace-separated fallback URLs, which are tried in order
+        let alts: Vec<String> = match tag.get_param("alt") {
+            Some(alt) => self
+                .expand(&alt)
+                .split_whitespace()
+                .map(|alt| self.request_url(alt))
+                .collect(),
+            None => vec![],
+        };
+
+        // Send everything before the include on while waiting for the fragment
+        writer.inner().flush()?;
+        let started = Instant::now();
+        self.config.observer.0.include_started(&src);
+
+        // Fragments are processed as ESI themselves until the configured recursion depth is reached
+        let (req, pending) = match prefetched {
+            Some((req, pending)) => (req, pending),
+            None => (self.build_request(tag, &src), None),
+        };
+        let response = match self.cached_response(&req) {
+            Some(cached) => {
+                if cached.stale {
+                    self.refresh(req.clone());
                 }
+                Ok(cached.response)
             }
-            None => {}
+            None => match send_request(req.clone(), pending, &alts, self.client, self.config) {
+                Ok((resp, alt)) => {
+                    if let Some(alt) = alt {
+                        self.debug_log.push(format!("include {} => failed, fetched {} instead", src, alt));
+                        self.document.warn(Warning::AltFetched {
+                            src: src.clone(),
+                            alt,
+                            position: tag.position,
+                        });
+                    }
+                    self.config.hooks.after_response(&req, resp)
+                }
+                Err(err) => Err(err),
+            },
+        };
+        let max_fragment_bytes = self.config.limits.max_fragment_bytes;
+        let response = response.and_then(|resp| {
+            if resp.body.len() > max_fragment_bytes {
+                return Err(ExecutionError::LimitExceeded("fragment size", max_fragment_bytes));
+            }
+            // Compressed fragments can't be inserted into the document
+            match resp.content_encoding() {
+                Some(encoding) if !encoding.eq_ignore_ascii_case("identity") => {
+                    Err(ExecutionError::UnsupportedContentEncoding(req.url.clone(), encoding.to_string()))
+                }
+                _ => Ok(resp),
+            }
+        });
+        if let Ok(resp) = &response {
+            self.store_response(&req, resp);
         }
+
+        let status = response.as_ref().map_or(0, |resp| resp.status_code);
+        let escape = self.escapes(tag);
+        let headers: Headers = response.as_ref().map_or_else(
+            |_| Vec::new(),
+            |resp| {
+                let collected = resp.headers.iter().filter(|(name, _)| self.config.aggregates_header(name));
+                collected.cloned().collect()
+            },
+        );
+        let cache_policy =
+            CachePolicy::from_cache_control(response.as_ref().ok().and_then(|resp| resp.cache_control()));
+        let result = match response {
+            Ok(resp) => {
+                self.config.observer.0.include_completed(&src, started.elapsed(), resp.status_code);
+                self.debug_log
+                    .push(format!("include {} => {} ({} bytes)", src, resp.status_code, resp.body.len()));
+                // The fragments of `<esi:eval>` tags are only executed for the variables they assign,
+                // and fragments that aren't text, such as images, are inserted as they are
+                if eval {
+                    self.chain.push(src.clone());
+                    self.evals += 1;
+                    let result = parse::parse_document(&Bytes::from(resp.body), self.config)
+                        .and_then(|entries| self.execute(&entries, false, &mut Writer::new(io::sink())));
+                    self.evals -= 1;
+                    self.chain.pop();
+                    result.map(|()| Vec::new())
+                } else if self.depth < self.config.recursion_depth && resp.is_text() && !escape {
+                    let mut fragment_writer = Writer::new(self.buffer(resp.body.len()));
+                    ParsedDocument::from_bytes(Bytes::from(resp.body), self.config)
+                        .and_then(|fragment| {
+                            fragment.execute(
+                                self.client,
+                                &self.variables,
+                                self.config,
+                                self.document.clone(),
+                                [&self.chain[..], std::slice::from_ref(&src)].concat(),
+                                &mut fragment_writer,
+                            )
+                        })
+                        .map(|()| fragment_writer.into_inner())
+                        .and_then(|content| match content.len() {
+                            // The processed fragment would take the output over its limit
+                            len if len > self.config.limits.max_output_bytes => {
+                                Err(ExecutionError::LimitExceeded("output size", self.config.limits.max_output_bytes))
+                            }
+                            _ => Ok(content),
+                        })
+                } else {
+                    Ok(resp.body)
+                }
+            }
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(content) => {
+                if !eval {
+                    self.notify(OutputEvent::Include { src: &src, status })?;
+                }
+                self.document.collect_headers(headers);
+                self.document.restrict_cache(cache_policy);
+                if escape {
+                    writer.inner().write_all(&quick_xml::escape::escape(&content))?;
+                } else {
+                    writer.inner().write_all(&content)?;
+                }
+                self.recycle(content);
+                Ok(())
+            }
+            Err(err) => self.include_failed(tag, &src, err, in_vars, writer),
+        }
+    }
+
+    // Passes an event to the document's visitor, if it has one and the event is part of the output
+    // of the top-level document rather than of a fragment or a buffered block.
+    fn notify(&self, event: OutputEvent<'_>) -> Result<()> {
+        match self.document.visitor {
+            Some(visitor) if self.depth == 0 && self.buffered == 0 => visitor.borrow_mut().visit(event),
+            _ => Ok(()),
+        }
+    }
+
+    // Returns an empty buffer with at least the given capacity, for output that is only written
+    // once it is complete.
+    fn buffer(&mut self, capacity: usize) -> Vec<u8> {
+        let mut buffer = self.buffers.pop().unwrap_or_default();
+        buffer.reserve(capacity);
+        buffer
+    }
+
+    // Keeps a buffer that is no longer needed, to be reused by `buffer`.
+    fn recycle(&mut self, mut buffer: Vec<u8>) {
+        if self.buffers.len() < MAX_SPARE_BUFFERS {
+            buffer.clear();
+            self.buffers.push(buffer);
+        }
+    }
+
+    // Handles an include whose fragment couldn't be rendered, by rendering any content within the
+    // tag in its place, or rendering nothing if the tag has `onerror="continue"` or is dropped for
+    // its priority.
+    fn include_failed(
+        &mut self,
+        tag: &Tag,
+        src: &str,
+        err: ExecutionError,
+        in_vars: bool,
+        writer: &mut Writer<impl Write>,
+    ) -> Result<()> {
+        if err.aborts() {
+            return Err(err);
+        }
+        self.config.observer.0.include_failed(src, &err);
+        // Failed fragments leave the page degraded, so it shouldn't be reused
+        self.document.restrict_cache(CachePolicy::from_cache_control(None));
+        // Requests fail at the document's time limit, which fallbacks can't recover from
+        self.document.check(self.config)?;
+
+        self.debug_log.push(format!("include {} => error: {}", src, err));
+        self.notify(OutputEvent::Error { src, error: &err })?;
+        if !tag.children.is_empty() {
+            self.document.warn(fragment_warning(tag, src, &err, true));
+            return self.execute(&tag.children, in_vars, writer);
+        }
+        match self.onerror(tag) {
+            OnErrorPolicy::Continue => {
+                self.document.warn(fragment_warning(tag, src, &err, false));
+                if self.config.error_markers {
+                    write_error_marker(src, err.status(), writer)?;
+                }
+                Ok(())
+            }
+            OnErrorPolicy::Placeholder(placeholder) => {
+                self.document.warn(fragment_warning(tag, src, &err, false));
+                writer.inner().write_all(placeholder.as_bytes())?;
+                Ok(())
+            }
+            // The request was cut short by the low-priority time limit
+            OnErrorPolicy::Fail if self.document.drops(tag_priority(tag)) => {
+                self.document.warn(Warning::IncludeDropped {
+                    src: src.to_string(),
+                    position: tag.position,
+                });
+                Ok(())
+            }
+            OnErrorPolicy::Fail => Err(fragment_failed(tag, src, err)),
+        }
+    }
+
+    // Returns how the failure of a tag's fragment is handled: `onerror="continue"` removes the tag,
+    // any other `onerror` value fails the document, and tags without `onerror` use the configured
+    // default.
+    fn onerror(&self, tag: &Tag) -> &'a OnErrorPolicy {
+        match tag.attr("onerror") {
+            Some("continue") => &OnErrorPolicy::Continue,
+            Some(_) => &OnErrorPolicy::Fail,
+            None => &self.config.onerror,
+        }
+    }
+
+    // Returns whether the body of a tag's fragment is HTML-escaped: `escape="html"` escapes it,
+    // `escape="none"` doesn't, and tags without either use the configured default.
+    fn escapes(&self, tag: &Tag) -> bool {
+        match tag.attr("escape") {
+            Some("html") => true,
+            Some("none") => false,
+            _ => self.config.escape_fragments,
+        }
+    }
+
+    // Writes an HTML comment listing all variables, along with the expressions and includes that
+    // have been executed so far.
+    fn write_debug(&self, writer: &mut Writer<impl Write>) -> Result<()> {
+        let mut block = String::from(" esi:debug\nvariables:\n");
+        let mut variables: Vec<(&str, &VariableValue)> = self.variables.iter().collect();
+        variables.sort_by_key(|(name, _)| *name);
+        for (name, value) in variables {
+            block.push_str(&format!("  {} = {}\n", name, value));
+        }
+        block.push_str("log:\n");
+        for line in &self.debug_log {
+            block.push_str(&format!("  {}\n", line));
+        }
+
+        // `--` isn't allowed within a comment
+        let block = block.replace("--", "- -");
+        writer.write_event(Event::Comment(BytesText::from_escaped(block.into_bytes())))?;
+
+        Ok(())
+    }
+}
+
+// The parsed entries of a document, along with the inline fragments that it declares.
+#[derive(Debug, Clone)]
+struct ParsedDocument {
+    entries: Vec<TagEntry>,
+    fragments: HashMap<String, Arc<[TagEntry]>>,
+    // The length of the source, which the output is usually close to
+    len: usize,
+    // Warnings from parsing the document
+    warnings: Vec<Warning>,
+}
+
+impl ParsedDocument {
+    fn parse(body: impl BufRead, config: &Configuration) -> Result<Self> {
+        Self::from_bytes(parse::read_document(body, config)?, config)
+    }
+
+    fn from_bytes(input: Bytes, config: &Configuration) -> Result<Self> {
+        // Parse tags
+        let (entries, warnings) = parse::parse_document_with_warnings(&input, config)?;
+        Ok(Self {
+            warnings,
+            ..Self::from_entries(entries, input.len())
+        })
+    }
+
+    fn from_entries(entries: Vec<TagEntry>, len: usize) -> Self {
+        // Register inline fragments
+        let mut fragments = HashMap::new();
+        collect_inline_fragments(&entries, &mut fragments);
+
+        Self {
+            entries,
+            fragments,
+            len,
+            warnings: Vec::new(),
+        }
+    }
+
+    // Executes the document nested within the fragments at the given URLs, writing the
+    // transformed body to `writer`.
+    fn execute<'a>(
+        &self,
+        client: &'a (impl ExecutionContext + ?Sized),
+        variables: &Variables,
+        config: &'a Configuration,
+        document: Rc<DocumentState<'a>>,
+        chain: Vec<String>,
+        writer: &mut Writer<impl Write>,
+    ) -> Result<()> {
+        let top_level = chain.is_empty();
+        document.warnings.borrow_mut().extend(self.warnings.iter().cloned());
+        let mut executor = Executor::new(client, variables, config, document, chain);
+        executor.fragments = self.fragments.clone();
+
+        // Execute tags and build output XML
+        executor.execute(&self.entries, false, writer)?;
+
+        if config.debug && top_level {
+            executor.write_debug(writer)?;
+        }
+
+        // The document is sent on before stale fragments are refreshed
+        writer.inner().flush()?;
+        executor.finish_refreshes();
+
+        Ok(())
+    }
+
+    // Executes the top-level document, writing its output to `output` up to the configured
+    // maximum size, and returns `output` once the document is complete.
+    fn execute_top_level<'a, W: Write>(
+        &self,
+        client: &'a (impl ExecutionContext + ?Sized),
+        variables: &Variables,
+        config: &'a Configuration,
+        document: Rc<DocumentState<'a>>,
+        output: W,
+    ) -> Result<W> {
+        let mut writer = Writer::new(LimitedWriter::new(output, config));
+        self.execute(client, variables, config, document.clone(), Vec::new(), &mut writer)
+            .map_err(output_error)?;
+
+        let output = writer.into_inner();
+        config.observer.0.document_completed(document.includes.get(), output.written);
+        Ok(output.inner)
+    }
+
+    // Executes the top-level document, returning its output along with the warnings about it and
+    // its fragments.
+    fn execute_with_diagnostics(
+        &self,
+        client: &(impl ExecutionContext + ?Sized),
+        variables: &Variables,
+        config: &Configuration,
+    ) -> Result<(Vec<u8>, Vec<Warning>)> {
+        let document = DocumentState::new(config);
+        let output = self.execute_top_level(client, variables, config, document.clone(), Vec::with_capacity(self.len))?;
+
+        Ok((output, document.warnings.take()))
+    }
+
+    // Executes the top-level document, returning its output along with the configured headers of
+    // its fragments.
+    fn execute_with_headers(
+        &self,
+        client: &(impl ExecutionContext + ?Sized),
+        variables: &Variables,
+        config: &Configuration,
+    ) -> Result<(Vec<u8>, Headers)> {
+        let document = DocumentState::new(config);
+        let output = self.execute_top_level(client, variables, config, document.clone(), Vec::with_capacity(self.len))?;
+
+        Ok((output, document.headers.take()))
+    }
+
+    // Executes the top-level document, returning its output along with the combined cache policy
+    // of the template and its fragments.
+    fn execute_with_cache_policy(
+        &self,
+        client: &(impl ExecutionContext + ?Sized),
+        variables: &Variables,
+        config: &Configuration,
+        cache_control: Option<&str>,
+    ) -> Result<(Vec<u8>, CachePolicy)> {
+        let document = DocumentState::new(config);
+        let output = self.execute_top_level(client, variables, config, document.clone(), Vec::with_capacity(self.len))?;
+        let template = CachePolicy::from_cache_control(cache_control);
+
+        Ok((output, document.cache_policy.get().map_or(template, |fragments| template.combine(fragments))))
     }
 
-    Ok(map)
+    // Executes the top-level document, passing its output and the outcome of its includes to
+    // `visitor` as they are produced.
+    fn execute_with_visitor(
+        &self,
+        client: &(impl ExecutionContext + ?Sized),
+        variables: &Variables,
+        config: &Configuration,
+        visitor: &mut (impl Visitor + ?Sized),
+    ) -> Result<()> {
+        let visitor = RefCell::new(visitor::VisitorRef(visitor));
+        let document = DocumentState::with_visitor(config, Some(&visitor));
+        self.execute_top_level(client, variables, config, document, visitor::VisitorWriter(&visitor))?;
+
+        Ok(())
+    }
 }
 
 /// Processes a given ESI response body and returns the transformed body after all ESI instructions
-/// have been executed.
+/// have been executed. `variables` are made available to expressions and `<esi:vars>` blocks, and
+/// are usually built from the original client request using `Variables::from_request`. Options
+/// are set with `Processor::builder()`, or by passing a `Configuration` to
+/// `transform_esi_string_with_config`.
+///
+/// The body doesn't need to be UTF-8: content outside of ESI tags is passed through byte for byte,
+/// and attribute values that aren't valid UTF-8 are read as ISO-8859-1.
 pub fn transform_esi_string(
     body: impl BufRead,
-    client: &impl ExecutionContext,
+    client: &(impl ExecutionContext + ?Sized),
+    variables: &Variables,
 ) -> Result<Vec<u8>> {
-    // Parse tags
-    let events = parse_tag_entries(body)?;
+    transform_esi_string_with_config(body, client, variables, &Configuration::default())
+}
 
-    // Execute tags
-    let results = execute_tag_entries(&events, client)?;
+/// Processes a given ESI response body with application-defined variables, such as feature flags,
+/// experiment buckets or tenant ids, and returns the transformed body. The variables can be
+/// referenced from expressions and `<esi:vars>` blocks like any other, e.g. as `$(TENANT)`. To
+/// combine them with the variables of the client request, extend the result of
+/// `Variables::from_request` with them instead.
+///
+/// # Examples
+/// ```
+/// use esi::{transform_esi_string_with_vars, ExecutionContext, Request, Response, Result};
+/// use std::collections::HashMap;
+///
+/// struct Backend;
+///
+/// impl ExecutionContext for Backend {
+///     fn send_request(&self, req: Request) -> Result<Response> {
+///         Ok(Response::new(200, format!("[{}]", req.url)))
+///     }
+/// }
+///
+/// let vars = HashMap::from([("TENANT", "acme"), ("BUCKET", "b")]);
+/// let body = "<esi:choose><esi:when test=\"$(BUCKET) == 'b'\"><esi:include src=\"/$(TENANT)/nav\"/></esi:when></esi:choose>";
+/// # #[cfg(feature = "expressions")]
+/// assert_eq!(transform_esi_string_with_vars(body.as_bytes(), &Backend, vars)?, b"[/acme/nav]");
+/// # Ok::<(), esi::ExecutionError>(())
+/// ```
+pub fn transform_esi_string_with_vars(
+    body: impl BufRead,
+    client: &(impl ExecutionContext + ?Sized),
+    vars: impl IntoIterator<Item = (impl Into<String>, impl Into<VariableValue>)>,
+) -> Result<Vec<u8>> {
+    transform_esi_string(body, client, &vars.into_iter().collect())
+}
 
-    // Build output XML
-    let mut writer = Writer::new(Vec::new());
+/// Processes a given ESI response body using the given `Configuration`, and returns the transformed
+/// body after all ESI instructions have been executed.
+pub fn transform_esi_string_with_config(
+    body: impl BufRead,
+    client: &(impl ExecutionContext + ?Sized),
+    variables: &Variables,
+    config: &Configuration,
+) -> Result<Vec<u8>> {
+    process_document(
+        body,
+        config,
+        |document| {
+            let output = Vec::with_capacity(document.len);
+            document.execute_top_level(client, variables, config, DocumentState::new(config), output)
+        },
+        |original| original,
+    )
+}
 
-    for (index, entry) in events.iter().enumerate() {
-        match &entry.esi_tag {
-            Some(_tag) => if let Some(content) = results.get(&index) {
-                writer
-                    .write_event(Event::Text(BytesText::from_escaped(content)))
-                    .unwrap();
-            },
-            _ => match &entry.event {
-                Some(event) => {
-                    writer.write_event(event).unwrap();
-                }
-                None => {}
-            },
+// Reads a whole document and processes it with `process`. With `Configuration::with_fail_open`,
+// errors other than suspensions are reported, and `recover` makes the result from the original
+// body instead.
+fn process_document<T>(
+    mut body: impl BufRead,
+    config: &Configuration,
+    process: impl FnOnce(ParsedDocument) -> Result<T>,
+    recover: impl FnOnce(Vec<u8>) -> T,
+) -> Result<T> {
+    let input = parse::read_document(&mut body, config)?;
+    match ParsedDocument::from_bytes(input.clone(), config).and_then(process) {
+        Err(err) if config.fail_open && !err.is_suspended() => {
+            Ok(recover(original_document(&err, &input, body, config)?))
         }
+        result => result,
     }
+}
 
-    println!("esi processing done.");
+// Reads and parses a whole document whose output is streamed. With `Configuration::with_fail_open`,
+// documents that fail to parse are passed to `recover` as they were received, as nothing has been
+// output yet, and `None` is returned.
+fn parse_streamed_document(
+    mut body: impl BufRead,
+    config: &Configuration,
+    recover: impl FnOnce(&[u8]) -> Result<()>,
+) -> Result<Option<ParsedDocument>> {
+    let input = parse::read_document(&mut body, config)?;
+    match ParsedDocument::from_bytes(input.clone(), config) {
+        Ok(document) => Ok(Some(document)),
+        Err(err) if config.fail_open => {
+            recover(&original_document(&err, &input, body, config)?)?;
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
 
-    Ok(writer.into_inner())
+// Reports the error that a document failed with to the observer, and returns the document as it
+// was received, which is the `input` that was read followed by the rest of `body`.
+fn original_document(
+    err: &ExecutionError,
+    input: &Bytes,
+    mut body: impl BufRead,
+    config: &Configuration,
+) -> Result<Vec<u8>> {
+    config.observer.0.document_failed(err);
+    // Documents over the buffering limit haven't been read in full
+    let mut original = input.to_vec();
+    body.read_to_end(&mut original)?;
+    Ok(original)
 }
 
-/// Sends a request to the given `src`, optionally falling back to the `alt` if the first request is not successful.
+/// Processes a given ESI response body using the given `Configuration`, and returns the transformed
+/// body along with warnings about the parts of the document that were degraded without failing
+/// it: unknown tags that were removed, fragments that couldn't be rendered and were skipped or
+/// replaced by their fallback content, includes that were dropped or over the include limit,
+/// `<esi:attempt>` blocks that failed, and cached fragments that couldn't be refreshed. Fragments
+/// that are too large fail like any other fragment, and are reported with their fallback.
+///
+/// # Examples
+/// ```
+/// use esi::{transform_esi_string_with_diagnostics, Configuration, ExecutionContext, ExecutionError};
+/// use esi::{Request, Response, Result, Variables, Warning};
+///
+/// struct Backend;
+///
+/// impl ExecutionContext for Backend {
+///     fn send_request(&self, req: Request) -> Result<Response> {
+///         Err(ExecutionError::RequestFailed(req.url))
+///     }
+/// }
+///
+/// let body = "<esi:include src=\"/a\" onerror=\"continue\"/><esi:bogus/>".as_bytes();
+/// let config = Configuration::default();
+/// let (output, warnings) = transform_esi_string_with_diagnostics(body, &Backend, &Variables::new(), &config)?;
+/// assert!(output.is_empty());
+/// assert!(matches!(&warnings[0], Warning::UnknownTagRemoved { tag, .. } if tag == "esi:bogus"));
+/// assert!(matches!(&warnings[1], Warning::FragmentSkipped { src, .. } if src == "/a"));
+/// # Ok::<(), esi::ExecutionError>(())
+/// ```
+pub fn transform_esi_string_with_diagnostics(
+    body: impl BufRead,
+    client: &(impl ExecutionContext + ?Sized),
+    variables: &Variables,
+    config: &Configuration,
+) -> Result<(Vec<u8>, Vec<Warning>)> {
+    process_document(
+        body,
+        config,
+        |document| document.execute_with_diagnostics(client, variables, config),
+        |original| (original, Vec::new()),
+    )
+}
+
+/// Processes a given ESI response body using the given `Configuration`, and returns the transformed
+/// body along with the headers of the included fragments that are named by
+/// `Configuration::with_aggregated_headers`, so that they can be merged into the client response.
+/// Headers are collected from every fragment that was inserted into the output, including nested
+/// and cached fragments, but not from fragments that failed or were skipped.
+///
+/// # Examples
+/// ```
+/// use esi::{test::MockExecutionContext, transform_esi_string_with_headers, Configuration, Response, Variables};
+///
+/// let client = MockExecutionContext::new()
+///     .with_response("/cart", Response::new(200, "3 items").with_header("Set-Cookie", "cart=3"))
+///     .with_response("/user", Response::new(200, "Hi").with_header("Vary", "Cookie"));
+/// let body = "<esi:include src=\"/cart\"/> <esi:include src=\"/user\"/>".as_bytes();
+/// let config = Configuration::default().with_aggregated_headers(["set-cookie", "vary"]);
+///
+/// let (output, headers) = transform_esi_string_with_headers(body, &client, &Variables::new(), &config)?;
+/// assert_eq!(output, b"3 items Hi");
+/// assert_eq!(headers, [("Set-Cookie".into(), "cart=3".into()), ("Vary".into(), "Cookie".into())]);
+/// # Ok::<(), esi::ExecutionError>(())
+/// ```
+pub fn transform_esi_string_with_headers(
+    body: impl BufRead,
+    client: &(impl ExecutionContext + ?Sized),
+    variables: &Variables,
+    config: &Configuration,
+) -> Result<(Vec<u8>, Headers)> {
+    process_document(
+        body,
+        config,
+        |document| document.execute_with_headers(client, variables, config),
+        |original| (original, Vec::new()),
+    )
+}
+
+/// Processes a given ESI response body using the given `Configuration`, and returns the transformed
+/// body along with the policy that the assembled page may be cached with. `cache_control` is the
+/// `Cache-Control` header of the template, which is combined with those of the fragments that
+/// were requested for it, including nested fragments. The page is fresh for the shortest of their
+/// lifetimes, and is private or not stored if any of them is. Pages with a fragment that failed
+/// or was left out, such as one over the include limit or dropped for its priority, or with one
+/// that doesn't have a positive lifetime, may not be reused without revalidation. Fragments
+/// served from the fragment cache count with the lifetime of their cached `Cache-Control` header.
+///
+/// # Examples
+/// ```
+/// use esi::{test::MockExecutionContext, transform_esi_string_with_cache_policy, Configuration, Response, Variables};
+///
+/// let client = MockExecutionContext::new()
+///     .with_response("/nav", Response::new(200, "nav").with_header("Cache-Control", "max-age=3600"))
+///     .with_response("/news", Response::new(200, "news").with_header("Cache-Control", "max-age=60"));
+/// let body = "<esi:include src=\"/nav\"/><esi:include src=\"/news\"/>".as_bytes();
+/// let (output, policy) = transform_esi_string_with_cache_policy(
+///     body,
+///     &client,
+///     &Variables::new(),
+///     &Configuration::default(),
+///     Some("max-age=300"),
+/// )?;
+/// assert_eq!(output, b"navnews");
+/// assert_eq!(policy.cache_control(), "max-age=60");
+/// # Ok::<(), esi::ExecutionError>(())
+/// ```
+pub fn transform_esi_string_with_cache_policy(
+    body: impl BufRead,
+    client: &(impl ExecutionContext + ?Sized),
+    variables: &Variables,
+    config: &Configuration,
+    cache_control: Option<&str>,
+) -> Result<(Vec<u8>, CachePolicy)> {
+    process_document(
+        body,
+        config,
+        |document| document.execute_with_cache_policy(client, variables, config, cache_control),
+        |original| (original, CachePolicy::from_cache_control(cache_control)),
+    )
+}
+
+/// Processes a given ESI response body using the given `Configuration`, writing the transformed
+/// body to `output` as it is produced. The output is flushed before each fragment is requested, so
+/// that content preceding an include can be sent on without waiting for the fragment.
+///
+/// With `Configuration::with_fail_open`, documents that fail to parse are written out as they
+/// were received, but errors during execution are returned, as part of the output may already
+/// have been written.
+pub fn transform_esi_string_to_writer(
+    body: impl BufRead,
+    client: &(impl ExecutionContext + ?Sized),
+    variables: &Variables,
+    config: &Configuration,
+    mut output: impl Write,
+) -> Result<()> {
+    let document = parse_streamed_document(body, config, |original| Ok(output.write_all(original)?))?;
+    let document = match document {
+        Some(document) => document,
+        None => return Ok(()),
+    };
+    document.execute_top_level(client, variables, config, DocumentState::new(config), output)?;
+
+    Ok(())
+}
+
+/// Processes a given ESI response body using the given `Configuration`, passing the transformed
+/// body to `visitor` as it is produced instead of writing it out. This suits integrations that
+/// stream the output into their own body type, or that handle the content of fragments
+/// differently from the rest of the document: the output of each include of the top-level
+/// document is preceded by an `OutputEvent::Include` with the status of its fragment, or an
+/// `OutputEvent::Error` if the fragment couldn't be rendered.
+///
+/// With `Configuration::with_fail_open`, documents that fail to parse are passed to `visitor` as
+/// they were received, as a single `OutputEvent::Content`, but errors during execution are
+/// returned, as part of the output may already have been passed on.
+///
+/// # Examples
+/// ```
+/// use esi::{transform_esi_string_with_visitor, Configuration, ExecutionContext, OutputEvent, Request, Response, Result, Variables};
+///
+/// struct Backend;
+///
+/// impl ExecutionContext for Backend {
+///     fn send_request(&self, req: Request) -> Result<Response> {
+///         Ok(Response::new(200, format!("[{}]", req.url)))
+///     }
+/// }
+///
+/// let mut events = Vec::new();
+/// let mut visitor = |event: OutputEvent<'_>| {
+///     events.push(match event {
+///         OutputEvent::Content(content) => String::from_utf8_lossy(content).into_owned(),
+///         OutputEvent::Include { src, status } => format!("include {} {}", src, status),
+///         OutputEvent::Error { src, .. } => format!("error {}", src),
+///     });
+///     Ok(())
+/// };
+///
+/// let body = "<p><esi:include src=\"http://example.com/a\"/></p>".as_bytes();
+/// transform_esi_string_with_visitor(body, &Backend, &Variables::new(), &Configuration::default(), &mut visitor)?;
+/// assert_eq!(events, ["<p>", "include http://example.com/a 200", "[http://example.com/a]", "</p>"]);
+/// # Ok::<(), esi::ExecutionError>(())
+/// ```
+pub fn transform_esi_string_with_visitor(
+    body: impl BufRead,
+    client: &(impl ExecutionContext + ?Sized),
+    variables: &Variables,
+    config: &Configuration,
+    visitor: &mut impl Visitor,
+) -> Result<()> {
+    let document = parse_streamed_document(body, config, |original| visitor.visit(OutputEvent::Content(original)))?;
+    if let Some(document) = document {
+        document.execute_with_visitor(client, variables, config, visitor)?;
+    }
+
+    Ok(())
+}
+
+// Passes the output of a document on, failing with `ExecutionError::LimitExceeded` once it would
+// exceed the configured maximum size. The error is returned through `io::Error`, and recovered
+// from it by `output_error`.
+struct LimitedWriter<W> {
+    inner: W,
+    written: usize,
+    max_len: usize,
+}
+
+impl<W: Write> LimitedWriter<W> {
+    fn new(inner: W, config: &Configuration) -> Self {
+        Self {
+            inner,
+            written: 0,
+            max_len: config.limits.max_output_bytes,
+        }
+    }
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.written + buf.len() > self.max_len {
+            let err = ExecutionError::LimitExceeded("output size", self.max_len);
+            return Err(std::io::Error::other(err));
+        }
+
+        let len = self.inner.write(buf)?;
+        self.written += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Writes the comment that marks an include that failed and was continued past, with the status of
+// the response that failed it, if there was one.
+fn write_error_marker(src: &str, status: Option<u16>, writer: &mut Writer<impl Write>) -> Result<()> {
+    // `--` isn't allowed within a comment, and quotes would end the attribute
+    let mut marker = format!(" esi:error src=\"{}\"", src.replace('"', "&quot;").replace("--", "- -"));
+    if let Some(status) = status {
+        marker.push_str(&format!(" status=\"{}\"", status));
+    }
+    marker.push(' ');
+    writer.write_event(Event::Comment(BytesText::from_escaped(marker.into_bytes())))?;
+
+    Ok(())
+}
+
+// Describes the fragment that a tag requested but couldn't render, which was replaced by the tag's
+// fallback content if `fallback` is set, or skipped otherwise.
+fn fragment_warning(tag: &Tag, src: &str, err: &ExecutionError, fallback: bool) -> Warning {
+    let (tag, src, position, error) = (tag.name.clone(), src.to_string(), tag.position, err.to_string());
+    if fallback {
+        Warning::FallbackRendered { tag, src, position, error }
+    } else {
+        Warning::FragmentSkipped { tag, src, position, error }
+    }
+}
+
+// Describes the failure of the fragment that a tag requested.
+fn fragment_failed(tag: &Tag, src: &str, err: ExecutionError) -> ExecutionError {
+    ExecutionError::FragmentFailed {
+        tag: tag.name.clone(),
+        src: src.to_string(),
+        position: tag.position,
+        source: Box::new(err),
+    }
+}
+
+// Recovers the error of a `LimitedWriter` from the I/O error it was returned as.
+fn output_error(err: ExecutionError) -> ExecutionError {
+    let io_err = match err {
+        ExecutionError::IoError(io_err) | ExecutionError::XMLError(quick_xml::Error::Io(io_err)) => io_err,
+        err => return err,
+    };
+
+    if !io_err.get_ref().is_some_and(|inner| inner.is::<ExecutionError>()) {
+        return ExecutionError::IoError(io_err);
+    }
+    match io_err.into_inner().map(|inner| inner.downcast::<ExecutionError>()) {
+        Some(Ok(err)) => *err,
+        Some(Err(inner)) => ExecutionError::IoError(io::Error::other(inner)),
+        None => ExecutionError::IoError(io::Error::other("output could not be written")),
+    }
+}
+
+// Returns the priority of an include from its `priority` attribute.
+fn tag_priority(tag: &Tag) -> IncludePriority {
+    tag.get_param("priority")
+        .map_or(IncludePriority::Normal, |priority| IncludePriority::parse(&priority))
+}
+
+// Returns the earlier of two optional deadlines.
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// Returns the deadline for a tag's request from its `maxwait` or `timeout` attribute, given in
+// milliseconds, or from the configured default timeout. Values that aren't a whole number of
+// milliseconds are ignored.
+fn include_deadline(tag: &Tag, config: &Configuration) -> Option<Instant> {
+    let timeout = tag
+        .get_param("maxwait")
+        .or_else(|| tag.get_param("timeout"))
+        .and_then(|millis| millis.trim().parse().ok())
+        .map(Duration::from_millis)
+        .or(config.default_timeout)?;
+    // Timeouts too long to be represented don't expire
+    Instant::now().checked_add(timeout)
+}
+
+/// Processes a document that is received in chunks, such as a streamed backend response, without
+/// waiting for the whole document. Each chunk is processed as soon as it no longer ends part-way
+/// through a tag or an ESI block, and its output is written to `output` in document order.
+///
+/// Output is flushed before each fragment is requested, so content up to an include is sent on
+/// while the fragment is fetched, and the fragment is inserted as soon as its response arrives.
+///
+/// Unlike `transform_esi_string`, an `<esi:inline>` fragment can only be included once it has been
+/// declared.
+pub struct Processor<'a, C: ExecutionContext + ?Sized, W: Write> {
+    executor: Executor<'a, C>,
+    writer: Writer<LimitedWriter<W>>,
+    // Received input that hasn't been processed yet
+    buffer: Vec<u8>,
+    // State that carries over between the parsed chunks of the document
+    state: parse::ParseState,
+}
+
+impl<'a, C: ExecutionContext + ?Sized, W: Write> Processor<'a, C, W> {
+    /// Creates a processor for a single document that writes its output to `output`.
+    pub fn new(client: &'a C, variables: &Variables, config: &'a Configuration, output: W) -> Self {
+        Self {
+            executor: Executor::new(client, variables, config, DocumentState::new(config), Vec::new()),
+            writer: Writer::new(LimitedWriter::new(output, config)),
+            buffer: Vec::new(),
+            state: parse::ParseState::new(config),
+        }
+    }
+
+    /// Adds the next chunk of the document, and writes and flushes the output for as much of the
+    /// document as can be processed so far. Nothing is written if more input is needed.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<()> {
+        self.buffer.extend_from_slice(chunk);
+
+        let len = parse::complete_prefix_len(&self.buffer, self.executor.config, &self.state);
+        if len == 0 {
+            return parse::check_buffered_len(self.buffer.len(), self.executor.config);
+        }
+
+        let rest = self.buffer.split_off(len);
+        let complete = std::mem::replace(&mut self.buffer, rest);
+        self.process(Bytes::from(complete)).map_err(output_error)?;
+        self.writer.inner().flush()?;
+
+        Ok(())
+    }
+
+    /// Processes the remainder of the document, writes its output, and returns the output sink.
+    pub fn finish(mut self) -> Result<W> {
+        let rest = std::mem::take(&mut self.buffer);
+        self.process(Bytes::from(rest)).map_err(output_error)?;
+
+        if self.executor.config.debug {
+            self.executor.write_debug(&mut self.writer).map_err(output_error)?;
+        }
+        self.writer.inner().flush()?;
+        self.executor.finish_refreshes();
+
+        let output = self.writer.into_inner();
+        let includes = self.executor.document.includes.get();
+        self.executor.config.observer.0.document_completed(includes, output.written);
+
+        Ok(output.inner)
+    }
+
+    // Returns the output sink, e.g. to take the output written to it so far.
+    pub(crate) fn output_mut(&mut self) -> &mut W {
+        &mut self.writer.inner().inner
+    }
+
+    fn process(&mut self, input: Bytes) -> Result<()> {
+        let events = parse::parse_chunk(&input, self.executor.config, &mut self.state)?;
+        collect_inline_fragments(&events, &mut self.executor.fragments);
+
+        self.executor.execute(&events, false, &mut self.writer)
+    }
+}
+
+/// Sends the request, unless it's already `pending`, retrying it according to the configured
+/// `RetryPolicy` and then falling back to each of the `alts` URLs in order until one of the
+/// requests is successful, returning the response along with the `alt` URL it was fetched from, if
+/// any. If every request fails, the error from the original request is returned. Once the
+/// request's deadline has passed, no further requests are made and responses are treated as timed
+/// out.
 fn send_request(
-    src: &str,
-    alt: Option<String>,
-    client: &impl ExecutionContext,
+    req: Request,
+    pending: Option<PendingRequest>,
+    alts: &[String],
+    client: &(impl ExecutionContext + ?Sized),
+    config: &Configuration,
+) -> Result<(Response, Option<String>)> {
+    let src = req.url.clone();
+    if !config.allows_method(&req.method) {
+        return Err(ExecutionError::MethodNotAllowed(src, req.method));
+    }
+    let deadline = req.deadline;
+    let timed_out = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+
+    // Requests to URLs that aren't allowed fail without being sent, and their `alt` URLs are tried
+    let err = if config.allows_url(&src) {
+        let result = match pending {
+            Some(pending) => pending.wait(),
+            None => client.send_request(req.clone()),
+        };
+        match retry(result, &req, client, config) {
+            Ok(_) if timed_out() => ExecutionError::Timeout(src.clone()),
+            Ok(resp) => return Ok((resp, None)),
+            Err(err) if err.aborts() => return Err(err),
+            Err(err) => err,
+        }
+    } else {
+        ExecutionError::UrlNotAllowed(src.clone())
+    };
+
+    for alt in alts.iter().filter(|alt| config.allows_url(alt)) {
+        if timed_out() {
+            break;
+        }
+        match client.send_request(Request {
+            url: alt.clone(),
+            ..req.clone()
+        }) {
+            Ok(resp) if !timed_out() => {
+                config.observer.0.include_fell_back(&src, alt);
+                return Ok((resp, Some(alt.clone())));
+            }
+            Err(err) if err.aborts() => return Err(err),
+            _ => {}
+        }
+    }
+
+    Err(err)
+}
+
+// Resends a request for as long as its result should be retried under the retry policy, waiting
+// for the policy's backoff before each attempt. No attempt is made that would start after the
+// request's deadline.
+fn retry(
+    mut result: Result<Response>,
+    req: &Request,
+    client: &(impl ExecutionContext + ?Sized),
+    config: &Configuration,
 ) -> Result<Response> {
-    match client.send_request(Request::from_url(src)) {
-        Ok(resp) => Ok(resp),
-        Err(err) => match alt {
-            Some(alt) => match client.send_request(Request::from_url(&alt)) {
-                Ok(resp) => Ok(resp),
-                Err(_) => Err(err),
-            },
-            None => Err(err),
-        },
+    let policy = match &config.retry {
+        Some(policy) => policy,
+        None => return result,
+    };
+
+    let mut backoff = policy.backoff;
+    for attempt in 1..policy.max_attempts {
+        let too_late = |deadline| Instant::now().checked_add(backoff).is_none_or(|start| start >= deadline);
+        if !policy.retries(&result) || req.deadline.is_some_and(too_late) {
+            break;
+        }
+        std::thread::sleep(backoff);
+        config.observer.0.include_retried(&req.url, attempt);
+        result = client.send_request(req.clone());
+        backoff = backoff.saturating_mul(2);
     }
+
+    result
 }
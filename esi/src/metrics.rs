@@ -0,0 +1,64 @@
+//! Callbacks for metrics about the includes and documents that are processed, which adapters can
+//! bridge to the metrics of their platform.
+
+use crate::ExecutionError;
+use std::{fmt, sync::Arc, time::Duration};
+
+/// Receives events about the processing of documents, set with `Configuration::with_observer`.
+/// Every method does nothing by default, so implementations only need to handle the events they
+/// record.
+///
+/// Events are reported for includes and `<esi:eval>` tags that are requested, including those
+/// whose responses are read from the fragment cache, but not for includes of `<esi:inline>`
+/// fragments. When documents are processed with `transform_esi_string_async`, include events are
+/// reported in every round that executes the include.
+pub trait Observer: Send + Sync {
+    /// Called before the fragment of an include is requested, with the include's `src`.
+    fn include_started(&self, _src: &str) {}
+
+    /// Called once the fragment of an include has been received, with the time since the include
+    /// started and the status of the response.
+    fn include_completed(&self, _src: &str, _duration: Duration, _status: u16) {}
+
+    /// Called before a request for the fragment of an include is resent under the retry policy,
+    /// with the request's URL and the number of attempts that have failed so far.
+    fn include_retried(&self, _url: &str, _attempts: usize) {}
+
+    /// Called when the fragment of an include couldn't be fetched from its `src`, and was fetched
+    /// from one of its `alt` URLs instead.
+    fn include_fell_back(&self, _src: &str, _alt: &str) {}
+
+    /// Called when the fragment of an include couldn't be rendered, whether or not the include
+    /// has fallback content.
+    fn include_failed(&self, _src: &str, _error: &ExecutionError) {}
+
+    /// Called once a top-level document has been processed successfully, with the number of
+    /// fragments it requested, counting those of its fragments, and the size of its output.
+    fn document_completed(&self, _fragments: usize, _bytes: usize) {}
+
+    /// Called when a top-level document couldn't be processed and was returned as it was
+    /// received instead, with `Configuration::with_fail_open`.
+    fn document_failed(&self, _error: &ExecutionError) {}
+}
+
+/// An `Observer` that ignores every event, which is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {}
+
+// Holds the observer of a `Configuration`, which can't derive `Debug` or `Default` through it.
+#[derive(Clone)]
+pub(crate) struct SharedObserver(pub(crate) Arc<dyn Observer>);
+
+impl Default for SharedObserver {
+    fn default() -> Self {
+        Self(Arc::new(NoopObserver))
+    }
+}
+
+impl fmt::Debug for SharedObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
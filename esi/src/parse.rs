@@ -0,0 +1,1280 @@
+//! Parsing of documents into a tree of `TagEntry` values. ESI control structures such as
+//! `<esi:choose>`, `<esi:try>` and `<esi:vars>` may be nested within each other up to the
+//! configured maximum nesting depth, which is `DEFAULT_MAX_NESTING_DEPTH` levels by default.
+
+use bytes::Bytes;
+use memchr::memmem;
+use crate::{ConformanceMode, Configuration, ExecutionError, Result, Tag, TagEntry, UnknownTagPolicy, Warning, When};
+use quick_xml::{
+    events::{BytesEnd, BytesStart, BytesText, Event},
+    Reader,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{BufRead, Read},
+};
+
+/// The default maximum number of ESI blocks that may be nested within each other. Deeper documents
+/// fail to parse rather than exhausting the stack.
+pub(crate) const DEFAULT_MAX_NESTING_DEPTH: usize = 64;
+
+/// Reads a whole document into a buffer to be parsed.
+pub(crate) fn read_document(body: impl BufRead, config: &Configuration) -> Result<Bytes> {
+    // Read one byte more than the limit, so that oversized documents can be detected
+    let mut input = Vec::new();
+    body.take((config.limits.max_buffered_bytes as u64).saturating_add(1))
+        .read_to_end(&mut input)?;
+
+    Ok(Bytes::from(input))
+}
+
+/// Parses a document into a tree of entries. Content outside of ESI constructs is kept as the
+/// exact bytes it was received as, sharing the buffer the document was read into.
+pub(crate) fn parse_document(input: &Bytes, config: &Configuration) -> Result<Vec<TagEntry>> {
+    parse_document_with_warnings(input, config).map(|(entries, _)| entries)
+}
+
+/// Parses a document like `parse_document`, returning the warnings about it as well.
+pub(crate) fn parse_document_with_warnings(
+    input: &Bytes,
+    config: &Configuration,
+) -> Result<(Vec<TagEntry>, Vec<Warning>)> {
+    let mut state = ParseState::new(config);
+    let entries = parse_chunk(input, config, &mut state)?;
+    Ok((entries, state.warnings))
+}
+
+/// State that carries over between the chunks of a document that is parsed incrementally.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ParseState {
+    // The namespace prefixes of ESI tags, including any that have been declared so far
+    prefixes: Vec<Vec<u8>>,
+    // The position of the start of the next chunk
+    position: Position,
+    // The number of ESI tags parsed so far
+    tags: usize,
+    // The full names of the custom tags that have a registered handler
+    custom_tags: Vec<Vec<u8>>,
+    // Warnings about the document so far
+    pub(crate) warnings: Vec<Warning>,
+}
+
+impl ParseState {
+    pub(crate) fn new(config: &Configuration) -> Self {
+        Self {
+            prefixes: namespace_prefixes(config),
+            custom_tags: config.tag_handlers.names().map(|name| name.as_bytes().to_vec()).collect(),
+            ..Self::default()
+        }
+    }
+}
+
+/// Parses the next chunk of a document.
+pub(crate) fn parse_chunk(input: &Bytes, config: &Configuration, state: &mut ParseState) -> Result<Vec<TagEntry>> {
+    check_buffered_len(input.len(), config)?;
+
+    // Most documents don't contain any ESI, and are passed through without being parsed
+    if !may_contain_esi(input, &state.prefixes) && !may_contain_custom_tag(input, &state.custom_tags) {
+        state.position.advance(input);
+        if input.is_empty() {
+            return Ok(Vec::new());
+        }
+        return Ok(vec![TagEntry::Raw(input.clone())]);
+    }
+
+    let mut source = new_source(input, input, config, std::mem::take(state));
+    let events = parse_tag_entries(&mut source, None, config, 0).map_err(|err| err.at(source.position()));
+    source.advance_to(input.len());
+    *state = source.state;
+
+    events
+}
+
+/// Returns an error if `len` bytes of a document would exceed the configured limit on buffered
+/// input.
+pub(crate) fn check_buffered_len(len: usize, config: &Configuration) -> Result<()> {
+    let max_len = config.limits.max_buffered_bytes;
+    if len > max_len {
+        return Err(ExecutionError::LimitExceeded("buffered document size", max_len));
+    }
+    Ok(())
+}
+
+/// A position within a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Position {
+    /// The line number, starting at 1.
+    pub line: usize,
+    /// The column number in bytes, starting at 1.
+    pub column: usize,
+    /// The byte offset from the start of the document.
+    pub offset: usize,
+}
+
+impl Position {
+    /// Moves the position past `input`.
+    pub(crate) fn advance(&mut self, input: &[u8]) {
+        match memchr::memrchr(b'\n', input) {
+            Some(last_line) => {
+                self.line += memchr::memchr_iter(b'\n', input).count();
+                self.column = input.len() - last_line;
+            }
+            None => self.column += input.len(),
+        }
+        self.offset += input.len();
+    }
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Self {
+            line: 1,
+            column: 1,
+            offset: 0,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+// A source of events for the parser. Events borrow from the input where possible.
+trait EventSource<'a> {
+    fn next_event(&mut self) -> Result<Event<'a>>;
+
+    // Returns the input that the last event was read from.
+    fn raw(&self) -> &'a [u8];
+
+    // Returns the offset within the input at which the last event starts.
+    fn start(&self) -> usize;
+}
+
+// Reads events from a document using an XML parser. Closing tags aren't matched against their
+// opening tags, as documents are usually HTML, and only the nesting of ESI tags matters.
+struct XmlSource<'a> {
+    input: &'a [u8],
+    reader: Reader<&'a [u8]>,
+    buf: Vec<u8>,
+    // The offset of the reader's input within the document
+    offset: usize,
+    // Whether `<script>` and `<style>` elements are passed through without being parsed
+    skip_raw_text: bool,
+    // The range of the input that the last event was read from
+    start: usize,
+    end: usize,
+}
+
+impl<'a> XmlSource<'a> {
+    fn new(input: &'a [u8], offset: usize, skip_raw_text: bool) -> Self {
+        let mut reader = Reader::from_reader(&input[offset..]);
+        reader.check_end_names(false);
+
+        Self {
+            input,
+            reader,
+            buf: Vec::new(),
+            offset,
+            skip_raw_text,
+            start: offset,
+            end: offset,
+        }
+    }
+}
+
+impl<'a> EventSource<'a> for XmlSource<'a> {
+    fn next_event(&mut self) -> Result<Event<'a>> {
+        self.buf.clear();
+        self.start = self.offset + self.reader.buffer_position();
+        let event = self.reader.read_event(&mut self.buf)?;
+
+        // The reader's position can run ahead of trailing text at the end of the input, but text
+        // content is never unescaped, so it matches the input exactly and can be borrowed from it
+        let event = match event {
+            Event::Text(text) => {
+                self.end = self.start + text.len();
+                Event::Text(BytesText::from_escaped(self.raw()))
+            }
+            event => {
+                self.end = self.offset + self.reader.buffer_position();
+                event.into_owned()
+            }
+        };
+
+        // Return raw text elements as a single piece of text, and continue reading after them
+        if let Event::Start(elem) = &event {
+            let name = RAW_TEXT_ELEMENTS.iter().find(|name| elem.name().eq_ignore_ascii_case(name));
+            if let Some(name) = name.filter(|_| self.skip_raw_text) {
+                let end = match raw_text_end(&self.input[self.end..], name) {
+                    Some(len) => self.end + len,
+                    None => self.input.len(),
+                };
+                *self = Self {
+                    start: self.start,
+                    end,
+                    ..Self::new(self.input, end, self.skip_raw_text)
+                };
+                return Ok(Event::Text(BytesText::from_escaped(self.raw())));
+            }
+        }
+
+        Ok(event)
+    }
+
+    fn raw(&self) -> &'a [u8] {
+        &self.input[self.start..self.end.min(self.input.len())]
+    }
+
+    fn start(&self) -> usize {
+        self.start
+    }
+}
+
+// Reads events from a document that may not be well-formed XML. Only ESI tags and `<!--esi`
+// comments are recognised, and everything in between is returned as text without being parsed.
+struct HtmlSource<'a> {
+    input: &'a [u8],
+    position: usize,
+    // Whether `<script>` and `<style>` elements are passed through without being parsed
+    skip_raw_text: bool,
+    prefixes: Vec<Vec<u8>>,
+    custom_tags: Vec<Vec<u8>>,
+    // The start of the last event
+    start: usize,
+}
+
+impl<'a> HtmlSource<'a> {
+    // Returns the offset of the next ESI construct at or after the current position.
+    fn next_construct(&self) -> Option<usize> {
+        let mut position = self.position;
+
+        while let Some(offset) = memchr::memchr(b'<', &self.input[position..]) {
+            position += offset;
+            let rest = &self.input[position..];
+            if is_esi_tag(rest, &self.prefixes) || is_custom_tag(rest, &self.custom_tags) || rest.starts_with(b"<!--esi") {
+                return Some(position);
+            }
+
+            // Other comments and CDATA sections are passed through along with anything inside them,
+            // as are raw text elements unless configured otherwise
+            position += if rest.starts_with(b"<!--") {
+                find(&rest[4..], b"-->").map_or(rest.len(), |end| end + 7)
+            } else if rest.starts_with(b"<![CDATA[") {
+                find(&rest[9..], b"]]>").map_or(rest.len(), |end| end + 12)
+            } else if let Some(name) = raw_text_element(rest).filter(|_| self.skip_raw_text) {
+                raw_text_element_len(rest, name).unwrap_or(rest.len())
+            } else {
+                1
+            };
+        }
+
+        None
+    }
+
+    // Returns the text up to `end`, and advances past it.
+    fn text(&mut self, end: usize) -> Event<'a> {
+        let text = &self.input[self.position..end];
+        self.position = end;
+        Event::Text(BytesText::from_escaped(text))
+    }
+}
+
+impl<'a> EventSource<'a> for HtmlSource<'a> {
+    fn next_event(&mut self) -> Result<Event<'a>> {
+        self.start = self.position;
+        if self.position >= self.input.len() {
+            return Ok(Event::Eof);
+        }
+
+        let start = match self.next_construct() {
+            Some(start) if start == self.position => start,
+            Some(start) => return Ok(self.text(start)),
+            None => return Ok(self.text(self.input.len())),
+        };
+        let rest: &'a [u8] = &self.input[start..];
+
+        if rest.starts_with(b"<!--") {
+            return Ok(match find(&rest[4..], b"-->") {
+                Some(end) => {
+                    let comment = &rest[4..4 + end];
+                    self.position = start + end + 7;
+                    Event::Comment(BytesText::from_escaped(comment))
+                }
+                None => self.text(self.input.len()),
+            });
+        }
+
+        let len = match tag_len(rest) {
+            Some(len) => len,
+            None => return Ok(self.text(self.input.len())),
+        };
+        let event = if rest.starts_with(b"</") {
+            Event::End(BytesEnd::borrowed(rest[2..len - 1].trim_ascii_end()))
+        } else {
+            let content = match rest[..len].strip_suffix(b"/>") {
+                Some(content) => &content[1..],
+                None => &rest[1..len - 1],
+            };
+            let name_len = content
+                .iter()
+                .position(|c| c.is_ascii_whitespace() || *c == b'/')
+                .unwrap_or(content.len());
+            let elem = BytesStart::borrowed(content, name_len);
+            if rest[..len].ends_with(b"/>") {
+                Event::Empty(elem)
+            } else {
+                Event::Start(elem)
+            }
+        };
+
+        self.position = start + len;
+        Ok(event)
+    }
+
+    fn raw(&self) -> &'a [u8] {
+        &self.input[self.start..self.position]
+    }
+
+    fn start(&self) -> usize {
+        self.start
+    }
+}
+
+// Creates an event source for a document, according to the configured parsing mode. The input is
+// part of `buffer`, which passed through content is shared with.
+fn new_source<'a>(buffer: &'a Bytes, input: &'a [u8], config: &Configuration, state: ParseState) -> NamespacedSource<'a> {
+    let source: Box<dyn EventSource<'a> + 'a> = if config.html {
+        Box::new(HtmlSource {
+            input,
+            position: 0,
+            skip_raw_text: !config.esi_in_scripts,
+            prefixes: state.prefixes.clone(),
+            custom_tags: state.custom_tags.clone(),
+            start: 0,
+        })
+    } else {
+        Box::new(XmlSource::new(input, 0, !config.esi_in_scripts))
+    };
+
+    NamespacedSource {
+        inner: source,
+        buffer,
+        input,
+        starts_line: state.position.column == 1,
+        state,
+        cursor: 0,
+        max_tags: config.limits.max_tags,
+    }
+}
+
+// The namespace URI that identifies ESI tags in `xmlns` declarations.
+const ESI_NAMESPACE: &[u8] = b"http://www.edge-delivery.org/esi/1.0";
+
+// Returns the configured namespace prefixes of ESI tags, e.g. `esi` for `<esi:include>`.
+fn namespace_prefixes(config: &Configuration) -> Vec<Vec<u8>> {
+    match &config.namespace_prefixes {
+        Some(prefixes) => prefixes.iter().map(|prefix| prefix.as_bytes().to_vec()).collect(),
+        None => vec![b"esi".to_vec()],
+    }
+}
+
+// Returns the local name of `name` if it has one of the ESI namespace prefixes, e.g. `include`
+// for `esi:include`.
+fn local_name<'n>(name: &'n [u8], prefixes: &[Vec<u8>]) -> Option<&'n [u8]> {
+    prefixes
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix.as_slice())?.strip_prefix(b":"))
+}
+
+// Returns whether the input may contain ESI markup, i.e. an ESI tag with one of the namespace
+// prefixes, an `<!--esi` comment, or a declaration of the ESI namespace that could introduce
+// further prefixes.
+fn may_contain_esi(input: &[u8], prefixes: &[Vec<u8>]) -> bool {
+    memmem::find(input, b"<!--esi").is_some()
+        || memmem::find(input, ESI_NAMESPACE).is_some()
+        || prefixes.iter().any(|prefix| {
+            memmem::find_iter(input, prefix).any(|position| {
+                input.get(position + prefix.len()) == Some(&b':')
+                    && position > 0
+                    && matches!(input[position - 1], b'<' | b'/')
+            })
+        })
+}
+
+// Returns whether `input` starts with an ESI start or end tag.
+fn is_esi_tag(input: &[u8], prefixes: &[Vec<u8>]) -> bool {
+    input
+        .strip_prefix(b"</")
+        .or_else(|| input.strip_prefix(b"<"))
+        .is_some_and(|name| local_name(name, prefixes).is_some())
+}
+
+// Returns whether the input may contain one of the custom tags.
+fn may_contain_custom_tag(input: &[u8], custom_tags: &[Vec<u8>]) -> bool {
+    custom_tags.iter().any(|name| {
+        memmem::find_iter(input, name).any(|position| position > 0 && matches!(input[position - 1], b'<' | b'/'))
+    })
+}
+
+// Returns whether `input` starts with a start or end tag of one of the custom tags.
+fn is_custom_tag(input: &[u8], custom_tags: &[Vec<u8>]) -> bool {
+    let rest = match input.strip_prefix(b"</").or_else(|| input.strip_prefix(b"<")) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    custom_tags.iter().any(|name| {
+        rest.strip_prefix(name.as_slice())
+            .is_some_and(|after| matches!(after.first(), Some(b'>' | b'/') | Some(b' ' | b'\t' | b'\r' | b'\n')))
+    })
+}
+
+// Renames ESI tags to use the `esi` prefix that the parser expects, and passes `esi:` tags through
+// as text if `esi` isn't one of the configured prefixes. Prefixes that are bound to the ESI
+// namespace by `xmlns` attributes are recognised as well.
+//
+// The number of ESI tags is limited, and the position of events within the document is tracked.
+struct NamespacedSource<'a> {
+    inner: Box<dyn EventSource<'a> + 'a>,
+    // The buffer that the input is part of
+    buffer: &'a Bytes,
+    input: &'a [u8],
+    state: ParseState,
+    // The offset within the input up to which the state's position has been tracked
+    cursor: usize,
+    // Whether the input starts at the beginning of a line
+    starts_line: bool,
+    max_tags: usize,
+}
+
+impl<'a> EventSource<'a> for NamespacedSource<'a> {
+    fn next_event(&mut self) -> Result<Event<'a>> {
+        let event = self.read_event()?;
+        if let Event::Start(elem) | Event::Empty(elem) = &event {
+            if elem.name().starts_with(b"esi:") || self.state.custom_tags.iter().any(|name| name == elem.name()) {
+                self.state.tags += 1;
+                if self.state.tags > self.max_tags {
+                    return Err(ExecutionError::LimitExceeded("number of esi tags", self.max_tags));
+                }
+            }
+        }
+
+        Ok(event)
+    }
+
+    fn raw(&self) -> &'a [u8] {
+        self.inner.raw()
+    }
+
+    fn start(&self) -> usize {
+        self.inner.start()
+    }
+}
+
+impl<'a> NamespacedSource<'a> {
+    fn read_event(&mut self) -> Result<Event<'a>> {
+        let event = self.inner.next_event()?;
+        if let Event::Start(elem) | Event::Empty(elem) = &event {
+            declare_prefixes(elem, &mut self.state.prefixes);
+        }
+
+        let name = match &event {
+            Event::Start(elem) | Event::Empty(elem) => elem.name(),
+            Event::End(elem) => elem.name(),
+            _ => return Ok(event),
+        };
+        let renamed = match local_name(name, &self.state.prefixes) {
+            Some(local) if name.starts_with(b"esi:") && local.len() + 4 == name.len() => return Ok(event),
+            Some(local) => [b"esi:", local].concat(),
+            None if name.starts_with(b"esi:") => return Ok(Event::Text(BytesText::from_escaped(self.raw()))),
+            None => return Ok(event),
+        };
+
+        Ok(match event {
+            Event::Start(elem) => Event::Start(rename(&elem, renamed)),
+            Event::Empty(elem) => Event::Empty(rename(&elem, renamed)),
+            _ => Event::End(BytesEnd::owned(renamed)),
+        })
+    }
+
+    // Returns part of the input as a slice of the buffer that it was read into.
+    fn share(&self, raw: &[u8]) -> Bytes {
+        self.buffer.slice_ref(raw)
+    }
+
+    // Returns the offset within the input at which the last event ends.
+    fn end(&self) -> usize {
+        self.inner.start() + self.inner.raw().len()
+    }
+
+    // Returns the position of the last event within the document.
+    fn position(&mut self) -> Position {
+        self.advance_to(self.inner.start());
+        self.state.position
+    }
+
+    // Tracks the position up to `offset` within the input. Events are read in order, so the
+    // position only moves forwards.
+    fn advance_to(&mut self, offset: usize) {
+        let offset = offset.min(self.input.len());
+        if offset > self.cursor {
+            self.state.position.advance(&self.input[self.cursor..offset]);
+            self.cursor = offset;
+        }
+    }
+}
+
+// Adds the prefixes that `elem` binds to the ESI namespace to `prefixes`.
+fn declare_prefixes(elem: &BytesStart, prefixes: &mut Vec<Vec<u8>>) {
+    for attribute in elem.attributes().flatten() {
+        if let Some(prefix) = attribute.key.strip_prefix(b"xmlns:") {
+            if attribute.value.as_ref() == ESI_NAMESPACE && !prefixes.iter().any(|p| p == prefix) {
+                prefixes.push(prefix.to_vec());
+            }
+        }
+    }
+}
+
+// Replaces the name of a start tag, keeping its attributes.
+fn rename(elem: &BytesStart, name: Vec<u8>) -> BytesStart<'static> {
+    let name_len = name.len();
+    let mut content = name;
+    content.extend_from_slice(&elem[elem.name().len()..]);
+    BytesStart::owned(content, name_len)
+}
+
+// Elements whose content is passed through without interpreting any ESI markup within it, unless
+// configured otherwise.
+const RAW_TEXT_ELEMENTS: &[&[u8]] = &[b"script", b"style"];
+
+// Returns the name of the raw text element whose start tag is at the start of `input`, if any.
+fn raw_text_element(input: &[u8]) -> Option<&'static [u8]> {
+    let rest = input.strip_prefix(b"<")?;
+    RAW_TEXT_ELEMENTS.iter().copied().find(|name| {
+        rest.len() > name.len()
+            && rest[..name.len()].eq_ignore_ascii_case(name)
+            && (rest[name.len()] == b'>' || rest[name.len()] == b'/' || rest[name.len()].is_ascii_whitespace())
+    })
+}
+
+// Returns the length of the raw text element `name` at the start of `input`, up to and including
+// its closing tag, or `None` if the element isn't complete.
+fn raw_text_element_len(input: &[u8], name: &[u8]) -> Option<usize> {
+    let tag_len = tag_len(input)?;
+    if input[..tag_len].ends_with(b"/>") {
+        return Some(tag_len);
+    }
+
+    raw_text_end(&input[tag_len..], name).map(|len| tag_len + len)
+}
+
+// Returns the length of the content of the raw text element `name` at the start of `input`, up to
+// and including its closing tag, or `None` if there is no closing tag.
+fn raw_text_end(input: &[u8], name: &[u8]) -> Option<usize> {
+    let mut position = 0;
+
+    while let Some(offset) = find(&input[position..], b"</") {
+        position += offset + 2;
+        let rest = &input[position..];
+        if rest.len() >= name.len() && rest[..name.len()].eq_ignore_ascii_case(name) {
+            let after = &rest[name.len()..];
+            if after.first().is_none_or(|c| *c == b'>' || c.is_ascii_whitespace()) {
+                return after.iter().position(|c| *c == b'>').map(|end| position + name.len() + end + 1);
+            }
+        }
+    }
+
+    None
+}
+
+// Adds input that is passed through to the output as-is, extending the preceding content if it
+// directly precedes `raw` in the buffer, so that no content is copied.
+fn push_raw(events: &mut Vec<TagEntry>, source: &NamespacedSource, raw: &[u8]) {
+    if raw.is_empty() {
+        return;
+    }
+
+    let buffer = source.buffer;
+    let offset = |bytes: &[u8]| (bytes.as_ptr() as usize).checked_sub(buffer.as_ptr() as usize);
+    match events.last_mut() {
+        Some(TagEntry::Raw(content)) if offset(content).map(|start| start + content.len()) == offset(raw) => {
+            if let (Some(start), Some(end)) = (offset(content), offset(raw)) {
+                *content = buffer.slice(start..end + raw.len());
+            }
+        }
+        _ => events.push(TagEntry::Raw(source.share(raw))),
+    }
+}
+
+/// Returns the length of the longest prefix of a partially received document that can be parsed
+/// on its own, i.e. that doesn't end within a tag, a comment or an ESI block.
+pub(crate) fn complete_prefix_len(input: &[u8], config: &Configuration, state: &ParseState) -> usize {
+    let mut prefixes = state.prefixes.clone();
+    let mut complete = 0;
+    let mut depth = 0usize;
+    let mut position = 0;
+
+    // When trimming whitespace, the document is only split at the start of a line, so that removed
+    // constructs are on the same side of the split as the rest of their line
+    let split_at_lines = config.trim_whitespace;
+
+    while let Some(offset) = memchr::memchr(b'<', &input[position..]) {
+        if depth == 0 {
+            if !split_at_lines {
+                complete = position + offset;
+            } else if let Some(line_end) = input[position..position + offset].iter().rposition(|c| *c == b'\n') {
+                complete = position + line_end + 1;
+            }
+        }
+        position += offset;
+
+        let rest = &input[position..];
+        let end = if rest.starts_with(b"<!--") {
+            find(&rest[4..], b"-->").map(|end| end + 7)
+        } else if rest.starts_with(b"<![CDATA[") {
+            find(&rest[9..], b"]]>").map(|end| end + 12)
+        } else if let Some(name) = raw_text_element(rest).filter(|_| !config.esi_in_scripts) {
+            raw_text_element_len(rest, name)
+        } else {
+            tag_len(rest)
+        };
+        let end = match end {
+            Some(end) => end,
+            None => return complete,
+        };
+
+        let tag = &rest[..end];
+        if !config.html && !tag.starts_with(b"</") && !tag.starts_with(b"<!") && !tag.starts_with(b"<?") {
+            let content = &tag[1..tag_len(tag).unwrap_or(end) - 1];
+            let name_len = content.iter().position(|c| c.is_ascii_whitespace()).unwrap_or(content.len());
+            declare_prefixes(&BytesStart::borrowed(content, name_len), &mut prefixes);
+        }
+
+        let is_tag = is_esi_tag(tag, &prefixes) || is_custom_tag(tag, &state.custom_tags);
+        if tag.starts_with(b"</") && is_tag {
+            depth = depth.saturating_sub(1);
+        } else if is_tag && !tag.ends_with(b"/>") {
+            depth += 1;
+        }
+
+        position += end;
+        if depth == 0 && !split_at_lines {
+            complete = position;
+        }
+    }
+
+    complete
+}
+
+/// Decodes a tag name, attribute value or tag content. Documents aren't required to be UTF-8, so
+/// anything that isn't valid UTF-8 is decoded as ISO-8859-1 instead of being corrupted.
+pub(crate) fn decode(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&byte| char::from(byte)).collect(),
+    }
+}
+
+// Returns the length of the tag at the start of `input`, including any `>` characters within
+// quoted attribute values, or `None` if the tag is incomplete.
+fn tag_len(input: &[u8]) -> Option<usize> {
+    let mut quote = None;
+
+    for (i, c) in input.iter().enumerate() {
+        match (quote, c) {
+            (None, b'>') => return Some(i + 1),
+            (None, b'"' | b'\'') => quote = Some(*c),
+            (Some(q), c) if q == *c => quote = None,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    memmem::find(haystack, needle)
+}
+
+// Every tag name that the processor recognises. How other `esi:` tags are treated depends on the
+// configured `UnknownTagPolicy`.
+const KNOWN_TAGS: &[&[u8]] = &[
+    b"esi:include",
+    b"esi:comment",
+    b"esi:remove",
+    b"esi:choose",
+    b"esi:when",
+    b"esi:otherwise",
+    b"esi:vars",
+    b"esi:text",
+    b"esi:try",
+    b"esi:attempt",
+    b"esi:except",
+    b"esi:inline",
+    b"esi:assign",
+    b"esi:eval",
+    b"esi:debug",
+];
+
+// Returns the feature that a tag needs if it isn't enabled, such as `expressions` for the tags
+// that evaluate expressions.
+fn missing_feature(name: &[u8]) -> Option<&'static str> {
+    if !cfg!(feature = "expressions") && matches!(name, b"esi:choose" | b"esi:when" | b"esi:otherwise" | b"esi:assign") {
+        return Some("expressions");
+    }
+    if !cfg!(feature = "variables") && name == b"esi:vars" {
+        return Some("variables");
+    }
+    None
+}
+
+// Returns whether a tag is honored, which it isn't if the configuration doesn't allow it or it
+// needs a feature that isn't enabled.
+fn is_available(name: &[u8], config: &Configuration) -> bool {
+    config.allows_tag(name) && missing_feature(name).is_none()
+}
+
+// Returns the error for a tag that isn't honored.
+fn unavailable(name: &[u8]) -> ExecutionError {
+    match missing_feature(name) {
+        Some(feature) => ExecutionError::FeatureNotEnabled(format!("the `{}` tag", decode(name)), feature),
+        None => ExecutionError::TagNotAllowed(decode(name)),
+    }
+}
+
+// Returns the name of the tag of a start, empty or end event, or an empty name for other events.
+fn event_name<'e>(event: &'e Event) -> &'e [u8] {
+    match event {
+        Event::Start(elem) | Event::Empty(elem) => elem.name(),
+        Event::End(elem) => elem.name(),
+        _ => b"",
+    }
+}
+
+// Returns whether the event is a tag in the `esi:` namespace that isn't a known tag.
+fn is_unknown_tag(event: &Event) -> bool {
+    let name = event_name(event);
+    name.starts_with(b"esi:") && !KNOWN_TAGS.contains(&name)
+}
+
+// Malformed attributes are an error in strict mode, and are otherwise ignored. Values are unescaped,
+// e.g. `&amp;` is decoded to `&`. Values may be single or double quoted, or unquoted if configured
+// outside of strict mode.
+fn parse_attributes(bytes: BytesStart, config: &Configuration) -> Result<HashMap<String, String>> {
+    let mut map: HashMap<String, String> = HashMap::new();
+
+    let attributes = if config.unquoted_attributes && config.conformance != ConformanceMode::Strict {
+        bytes.html_attributes()
+    } else {
+        bytes.attributes()
+    };
+
+    for entry in attributes {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) if config.conformance == ConformanceMode::Strict => return Err(err.into()),
+            Err(_) => continue,
+        };
+
+        if map.len() >= config.limits.max_attributes {
+            return Err(ExecutionError::LimitExceeded("number of attributes on a tag", config.limits.max_attributes));
+        }
+        if entry.value.len() > config.limits.max_attribute_length {
+            return Err(ExecutionError::LimitExceeded("attribute length", config.limits.max_attribute_length));
+        }
+
+        // Values with unknown references or bare `&` characters are left as they are, as HTML
+        // documents commonly contain both
+        let value = match entry.unescaped_value() {
+            Ok(value) => decode(&value),
+            Err(_) => decode(&entry.value),
+        };
+
+        if map.insert(decode(entry.key), value).is_some() {
+            return Err(ExecutionError::DuplicateTagAttribute(decode(entry.key)));
+        }
+    }
+
+    Ok(map)
+}
+
+// Returns the depth of a block nested within a block at `depth`, or an error if that would exceed
+// the configured maximum nesting depth.
+fn nested(depth: usize, config: &Configuration) -> Result<usize> {
+    let max_depth = config.max_nesting_depth.unwrap_or(DEFAULT_MAX_NESTING_DEPTH);
+    if depth >= max_depth {
+        return Err(ExecutionError::NestingTooDeep(max_depth));
+    }
+    Ok(depth + 1)
+}
+
+// Parses entries until the closing tag named `until` is reached, or until EOF when `until` is `None`.
+// `depth` is the number of ESI blocks the entries are nested within.
+fn parse_tag_entries(
+    source: &mut NamespacedSource,
+    until: Option<&[u8]>,
+    config: &Configuration,
+    depth: usize,
+) -> Result<Vec<TagEntry>> {
+    let start = source.position();
+    let mut events: Vec<TagEntry> = Vec::new();
+    // The position and input offset of the `<esi:remove>` block being skipped, if any
+    let mut remove = None;
+    // The number of bytes of whitespace to drop from the start of the next event
+    let mut trim_next = 0;
+
+    // Parse tags and build events vec
+    loop {
+        let trim = std::mem::take(&mut trim_next);
+        match source.next_event() {
+            // Handle ESI tags that the configuration doesn't allow, or that need a feature that isn't
+            // enabled, in the same way as unknown tags
+            Ok(event @ (Event::Start(_) | Event::Empty(_) | Event::End(_)))
+                if remove.is_none() && !is_available(event_name(&event), config) =>
+            {
+                match config.unknown_tag_policy() {
+                    UnknownTagPolicy::Error => return Err(unavailable(event_name(&event))),
+                    UnknownTagPolicy::Passthrough => push_raw(&mut events, source, source.raw()),
+                    UnknownTagPolicy::Strip if matches!(event, Event::End(_)) => {}
+                    UnknownTagPolicy::Strip => {
+                        let position = source.position();
+                        source.state.warnings.push(Warning::DisallowedTagRemoved {
+                            tag: decode(event_name(&event)),
+                            position,
+                        });
+                    }
+                }
+            }
+
+            // Handle <esi:remove> tags. Empty `<esi:remove/>` tags have nothing to remove, and are
+            // dropped
+            Ok(Event::Start(elem)) if elem.name() == b"esi:remove" => {
+                remove = Some((source.position(), source.start()));
+            }
+            Ok(Event::Empty(elem)) if elem.name() == b"esi:remove" && remove.is_none() => {
+                trim_next = trim_line(&mut events, source, source.start(), config);
+            }
+            Ok(Event::End(elem)) if elem.name() == b"esi:remove" => {
+                let remove_offset = match remove.take() {
+                    Some((_, offset)) => offset,
+                    None => return Err(ExecutionError::UnexpectedClosingTag(decode(&elem))),
+                };
+                trim_next = trim_line(&mut events, source, remove_offset, config);
+            }
+            Ok(Event::Eof) => {
+                if let Some((remove_start, _)) = remove {
+                    unclosed(b"esi:remove", remove_start, config)?;
+                }
+                if let Some(until) = until {
+                    unclosed(until, start, config)?;
+                }
+                break;
+            }
+            _ if remove.is_some() => continue,
+
+            // Unwrap <!--esi ... --> comments and process their contents as ESI
+            Ok(Event::Comment(text)) if text.starts_with(b"esi") => {
+                let mut state = source.state.clone();
+                state.position = source.position();
+                state.position.advance(b"<!--esi");
+                // The comment's content, without the `esi` that starts it
+                let content = &source.raw()[7..4 + text.len()];
+                let mut inner_source = new_source(source.buffer, content, config, state);
+                let entries = parse_tag_entries(&mut inner_source, None, config, depth);
+                source.state.tags = inner_source.state.tags;
+                source.state.warnings = std::mem::take(&mut inner_source.state.warnings);
+                events.extend(entries.map_err(|err| err.at(inner_source.position()))?);
+            }
+
+            // Strip <esi:comment> tags along with anything inside them
+            Ok(Event::Empty(elem)) if elem.name() == b"esi:comment" => {
+                trim_next = trim_line(&mut events, source, source.start(), config);
+            }
+            Ok(Event::Start(elem)) if elem.name() == b"esi:comment" => {
+                let comment_offset = source.start();
+                skip_element(source, b"esi:comment", config)?;
+                trim_next = trim_line(&mut events, source, comment_offset, config);
+            }
+
+            // Handle <esi:choose> blocks
+            Ok(Event::Start(elem)) if elem.name() == b"esi:choose" => {
+                events.push(parse_choose(source, config, nested(depth, config)?)?);
+            }
+
+            // Handle <esi:try> blocks
+            Ok(Event::Start(elem)) if elem.name() == b"esi:try" => {
+                events.push(parse_try(source, config, nested(depth, config)?)?);
+            }
+
+            // Handle <esi:inline> fragments
+            Ok(Event::Start(elem)) if elem.name() == b"esi:inline" => {
+                let name = elem.name().to_vec();
+                let mut attributes = parse_attributes(elem, config)?;
+                let fragment_name = match attributes.remove("name") {
+                    Some(fragment_name) => fragment_name,
+                    None => {
+                        return Err(ExecutionError::MissingRequiredParameter(
+                            decode(&name),
+                            "name".to_string(),
+                        ));
+                    }
+                };
+
+                let content_start = source.end();
+                let entries = parse_tag_entries(source, Some(b"esi:inline"), config, nested(depth, config)?)?;
+                let content = &source.input[content_start..source.start().max(content_start)];
+                events.push(TagEntry::Inline {
+                    name: fragment_name,
+                    fetchable: attributes.get("fetchable").is_some_and(|v| v == "yes"),
+                    content: source.share(content),
+                    entries,
+                });
+            }
+
+            // Handle <esi:assign> tags that have their value as content
+            Ok(Event::Start(elem)) if elem.name() == b"esi:assign" => {
+                let name = elem.name().to_vec();
+                let position = source.position();
+                let parameters = parse_attributes(elem, config)?;
+                events.push(TagEntry::Tag(Tag {
+                    name: decode(&name),
+                    parameters,
+                    content: Some(read_text(source, b"esi:assign", config)?),
+                    children: Vec::new(),
+                    position,
+                }));
+            }
+
+            // Handle <esi:include> tags written as start/end pairs. Any content between the tags is
+            // fallback content, which is used if the fragment can't be fetched
+            Ok(Event::Start(elem)) if elem.name() == b"esi:include" => {
+                let name = elem.name().to_vec();
+                let position = source.position();
+                let parameters = parse_attributes(elem, config)?;
+                let mut children = parse_tag_entries(source, Some(b"esi:include"), config, nested(depth, config)?)?;
+                if children.iter().all(is_whitespace) {
+                    children.clear();
+                }
+
+                events.push(TagEntry::Tag(Tag {
+                    name: decode(&name),
+                    parameters,
+                    content: None,
+                    children,
+                    position,
+                }));
+            }
+
+            // Handle tags without content that are written as start/end pairs, such as
+            // `<esi:eval src="..."></esi:eval>`. Anything between the tags is discarded.
+            Ok(Event::Start(elem)) if matches!(elem.name(), b"esi:eval" | b"esi:debug") => {
+                let name = elem.name().to_vec();
+                let position = source.position();
+                let parameters = parse_attributes(elem, config)?;
+                skip_element(source, &name, config)?;
+
+                events.push(TagEntry::Tag(Tag {
+                    name: decode(&name),
+                    parameters,
+                    content: None,
+                    children: Vec::new(),
+                    position,
+                }));
+            }
+
+            // Handle <esi:text> blocks, whose contents are passed through verbatim
+            Ok(Event::Start(elem)) if elem.name() == b"esi:text" => {
+                events.push(TagEntry::Text(parse_text(source, config)?));
+            }
+
+            // Handle <esi:vars> blocks
+            Ok(Event::Start(elem)) if elem.name() == b"esi:vars" => {
+                events.push(TagEntry::Vars(parse_tag_entries(source, Some(b"esi:vars"), config, nested(depth, config)?)?));
+            }
+
+            // Return at the closing tag of the enclosing container
+            Ok(Event::End(elem)) if Some(elem.name()) == until => break,
+            Ok(Event::End(elem))
+                if matches!(elem.name(), b"esi:choose"
+                        | b"esi:when"
+                        | b"esi:otherwise"
+                        | b"esi:vars"
+                        | b"esi:try"
+                        | b"esi:attempt"
+                        | b"esi:except"
+                        | b"esi:inline"
+                        | b"esi:include"
+                        | b"esi:text"
+                ) =>
+            {
+                return Err(ExecutionError::UnexpectedClosingTag(decode(elem.name())));
+            }
+
+            // Handle custom tags that have a registered handler. Content between the start and end
+            // tags is parsed like the rest of the document
+            Ok(Event::Start(elem)) if config.tag_handlers.contains(elem.name()) => {
+                let name = elem.name().to_vec();
+                let position = source.position();
+                let parameters = parse_attributes(elem, config)?;
+                let children = parse_tag_entries(source, Some(&name), config, nested(depth, config)?)?;
+
+                events.push(TagEntry::Tag(Tag {
+                    name: decode(&name),
+                    parameters,
+                    content: None,
+                    children,
+                    position,
+                }));
+            }
+            Ok(Event::Empty(elem)) if config.tag_handlers.contains(elem.name()) => {
+                events.push(TagEntry::Tag(Tag {
+                    name: decode(elem.name()),
+                    parameters: parse_attributes(elem, config)?,
+                    content: None,
+                    children: Vec::new(),
+                    position: source.position(),
+                }));
+            }
+            Ok(Event::End(elem)) if config.tag_handlers.contains(elem.name()) => {
+                return Err(ExecutionError::UnexpectedClosingTag(decode(elem.name())));
+            }
+
+            // Handle ESI tags that aren't part of the supported tag set
+            Ok(event @ (Event::Start(_) | Event::Empty(_) | Event::End(_))) if is_unknown_tag(&event) => {
+                match config.unknown_tag_policy() {
+                    UnknownTagPolicy::Error => return Err(ExecutionError::UnknownTag(decode(event_name(&event)))),
+                    UnknownTagPolicy::Passthrough => push_raw(&mut events, source, source.raw()),
+                    // Closing tags are removed along with their opening tags
+                    UnknownTagPolicy::Strip if matches!(event, Event::End(_)) => {}
+                    UnknownTagPolicy::Strip => {
+                        let position = source.position();
+                        source.state.warnings.push(Warning::UnknownTagRemoved {
+                            tag: decode(event_name(&event)),
+                            position,
+                        });
+                    }
+                }
+            }
+
+            // Parse empty ESI tags
+            Ok(Event::Empty(elem)) if elem.name().starts_with(b"esi:") => {
+                events.push(TagEntry::Tag(Tag {
+                    name: decode(elem.name()),
+                    parameters: parse_attributes(elem, config)?,
+                    content: None,
+                    children: Vec::new(),
+                    position: source.position(),
+                }));
+            }
+
+            // Pass the XML declaration, DOCTYPE and processing instructions through exactly as
+            // they were received
+            Ok(Event::Decl(_) | Event::DocType(_) | Event::PI(_)) => push_raw(&mut events, source, source.raw()),
+
+            Ok(_) => push_raw(&mut events, source, &source.raw()[trim.min(source.raw().len())..]),
+            Err(err @ ExecutionError::LimitExceeded(..)) => return Err(err),
+            _ => {}
+        }
+    }
+
+    Ok(events)
+}
+
+// Removes the indentation before a construct that was stripped from the output, if whitespace
+// trimming is configured and the construct is on a line of its own. The construct starts at
+// `start` in the input, and ends with the last event. Returns the number of bytes of whitespace
+// after the construct, including the line break, that should be dropped along with it.
+fn trim_line(events: &mut [TagEntry], source: &NamespacedSource, start: usize, config: &Configuration) -> usize {
+    if !config.trim_whitespace {
+        return 0;
+    }
+
+    let (before, after) = (&source.input[..start], &source.input[source.end()..]);
+    let indent = before.iter().rev().take_while(|c| matches!(c, b' ' | b'\t')).count();
+    let line_start = start - indent;
+    if !((line_start == 0 && source.starts_line) || (line_start > 0 && before[line_start - 1] == b'\n')) {
+        return 0;
+    }
+
+    let spaces = after.iter().take_while(|c| matches!(c, b' ' | b'\t' | b'\r')).count();
+    let trailing = match after.get(spaces) {
+        Some(b'\n') => spaces + 1,
+        Some(_) => return 0,
+        None => spaces,
+    };
+
+    if let Some(TagEntry::Raw(content)) = events.last_mut() {
+        if content.len() >= indent {
+            content.truncate(content.len() - indent);
+        }
+    }
+    trailing
+}
+
+// Handles the end of the document being reached within the element named `name` that starts at
+// `start`. This is an error, unless unclosed elements are configured to be closed automatically.
+fn unclosed(name: &[u8], start: Position, config: &Configuration) -> Result<()> {
+    if config.auto_close {
+        return Ok(());
+    }
+    Err(ExecutionError::UnclosedTag(decode(name)).at(start))
+}
+
+// Reads the text content of an element up to its closing tag named `name`, unescaping any
+// entities.
+fn read_text(source: &mut NamespacedSource, name: &[u8], config: &Configuration) -> Result<String> {
+    let start = source.position();
+    let mut content = Vec::new();
+
+    loop {
+        match source.next_event()? {
+            Event::Text(text) | Event::CData(text) => match text.unescaped() {
+                Ok(unescaped) => content.extend_from_slice(&unescaped),
+                Err(_) => content.extend_from_slice(&text),
+            },
+            Event::End(elem) if elem.name() == name => break,
+            Event::Eof => {
+                unclosed(name, start, config)?;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(decode(&content))
+}
+
+// Returns whether the entry is text consisting only of whitespace.
+fn is_whitespace(entry: &TagEntry) -> bool {
+    match entry {
+        TagEntry::Raw(content) => content.iter().all(u8::is_ascii_whitespace),
+        _ => false,
+    }
+}
+
+// Discards everything up to and including the closing tag named `name`.
+fn skip_element(source: &mut NamespacedSource, name: &[u8], config: &Configuration) -> Result<()> {
+    let start = source.position();
+    let mut depth = 0;
+
+    loop {
+        match source.next_event()? {
+            Event::Start(elem) if elem.name() == name => depth += 1,
+            Event::End(elem) if elem.name() == name => {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+            }
+            Event::Eof => {
+                unclosed(name, start, config)?;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Reads the contents of an `<esi:text>` block as plain content, without interpreting any ESI
+// markup.
+fn parse_text(source: &mut NamespacedSource, config: &Configuration) -> Result<Bytes> {
+    let start = source.position();
+    let content_start = source.end();
+
+    let content_end = loop {
+        match source.next_event()? {
+            Event::End(elem) if elem.name() == b"esi:text" => break source.start(),
+            Event::Eof => {
+                unclosed(b"esi:text", start, config)?;
+                break source.input.len();
+            }
+            _ => {}
+        }
+    };
+
+    Ok(source.share(&source.input[content_start..content_end]))
+}
+
+// Parses the contents of an `<esi:choose>` block. Anything outside of the `<esi:when>` and
+// `<esi:otherwise>` branches is discarded.
+fn parse_choose(source: &mut NamespacedSource, config: &Configuration, depth: usize) -> Result<TagEntry> {
+    let start = source.position();
+    let mut whens = Vec::new();
+    let mut otherwise = None;
+
+    loop {
+        match source.next_event()? {
+            Event::Start(elem) if elem.name() == b"esi:when" => {
+                let name = elem.name().to_vec();
+                let test = match parse_attributes(elem, config)?.remove("test") {
+                    Some(test) => test,
+                    None => {
+                        return Err(ExecutionError::MissingRequiredParameter(
+                            decode(&name),
+                            "test".to_string(),
+                        ));
+                    }
+                };
+
+                whens.push(When {
+                    test,
+                    entries: parse_tag_entries(source, Some(b"esi:when"), config, depth)?,
+                });
+            }
+            Event::Start(elem) if elem.name() == b"esi:otherwise" => {
+                otherwise = Some(parse_tag_entries(source, Some(b"esi:otherwise"), config, depth)?);
+            }
+            Event::End(elem) if elem.name() == b"esi:choose" => break,
+            Event::End(elem) if elem.name().starts_with(b"esi:") => {
+                return Err(ExecutionError::UnexpectedClosingTag(decode(elem.name())));
+            }
+            Event::Eof => {
+                unclosed(b"esi:choose", start, config)?;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(TagEntry::Choose { whens, otherwise })
+}
+
+// Parses the contents of an `<esi:try>` block. Anything outside of the `<esi:attempt>` and
+// `<esi:except>` branches is discarded.
+fn parse_try(source: &mut NamespacedSource, config: &Configuration, depth: usize) -> Result<TagEntry> {
+    let start = source.position();
+    let mut attempt = None;
+    let mut except = None;
+
+    loop {
+        match source.next_event()? {
+            Event::Start(elem) if elem.name() == b"esi:attempt" => {
+                attempt = Some(parse_tag_entries(source, Some(b"esi:attempt"), config, depth)?);
+            }
+            Event::Start(elem) if elem.name() == b"esi:except" => {
+                except = Some(parse_tag_entries(source, Some(b"esi:except"), config, depth)?);
+            }
+            Event::End(elem) if elem.name() == b"esi:try" => break,
+            Event::End(elem) if elem.name().starts_with(b"esi:") => {
+                return Err(ExecutionError::UnexpectedClosingTag(decode(elem.name())));
+            }
+            Event::Eof => {
+                unclosed(b"esi:try", start, config)?;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    match attempt {
+        Some(attempt) => Ok(TagEntry::Try { attempt, except }),
+        None => Err(ExecutionError::MissingRequiredElement("esi:try".to_string(), "esi:attempt".to_string())),
+    }
+}
+
@@ -0,0 +1,101 @@
+//! Concurrent fragment requests for blocking `ExecutionContext`s, by sending them on a pool of
+//! threads. Enabled by the `thread-pool` feature.
+
+use crate::{ExecutionContext, ExecutionError, PendingRequest, Request, Response, Result};
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// An `ExecutionContext` that sends the requests of a blocking `ExecutionContext` on a fixed pool
+/// of threads, so that the includes of a document are fetched in parallel even though each
+/// request blocks its thread. At most as many requests are sent at once as there are threads,
+/// and further requests are queued until a thread is free.
+///
+/// The pool is meant to be created once and shared by the documents that are processed, and its
+/// threads are stopped when it is dropped.
+pub struct ThreadPoolContext<C> {
+    inner: Arc<C>,
+    jobs: Option<Mutex<mpsc::Sender<Job>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<C: ExecutionContext + Send + Sync + 'static> ThreadPoolContext<C> {
+    /// Wraps an `ExecutionContext`, sending its requests on the given number of threads.
+    pub fn new(inner: C, threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            inner: Arc::new(inner),
+            jobs: Some(Mutex::new(sender)),
+            workers,
+        }
+    }
+
+    /// Returns the wrapped `ExecutionContext`.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C: ExecutionContext + Send + Sync + 'static> ExecutionContext for ThreadPoolContext<C> {
+    fn send_request(&self, req: Request) -> Result<Response> {
+        self.send_request_async(req).wait()
+    }
+
+    fn send_request_async(&self, req: Request) -> PendingRequest<'_> {
+        let (url, deadline) = (req.url.clone(), req.deadline);
+        let (sender, receiver) = mpsc::channel();
+        let inner = self.inner.clone();
+        let job: Job = Box::new(move || {
+            // A panicking request fails on its own, rather than taking its thread down with it
+            let url = req.url.clone();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| inner.send_request(req)))
+                .unwrap_or_else(|_| Err(ExecutionError::RequestFailed(format!("request to `{}` panicked", url))));
+            let _ = sender.send(result);
+        });
+
+        // A job that can't be queued is dropped along with its sender, failing the request
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.lock().unwrap_or_else(PoisonError::into_inner).send(job);
+        }
+        PendingRequest::new(move || {
+            let dropped = || ExecutionError::RequestFailed(format!("request to `{}` was dropped by the thread pool", url));
+            match deadline {
+                Some(deadline) => match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(result) => result,
+                    Err(mpsc::RecvTimeoutError::Timeout) => Err(ExecutionError::Timeout(url)),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => Err(dropped()),
+                },
+                None => receiver.recv().unwrap_or_else(|_| Err(dropped())),
+            }
+        })
+    }
+}
+
+impl<C> Drop for ThreadPoolContext<C> {
+    fn drop(&mut self) {
+        // Workers stop once the queue is closed and empty
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
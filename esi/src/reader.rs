@@ -0,0 +1,141 @@
+//! Pull-based access to the output of a document, for streaming it into body types that read from
+//! a source rather than being written to.
+
+use crate::{Configuration, ExecutionContext, ExecutionError, Processor, Result, Variables};
+use bytes::Bytes;
+use std::io::{self, BufRead, Read};
+
+/// Processes a document as its output is read, reading the input only as far as is needed to
+/// produce more output. Like a `Processor`, only the input of an ESI block that isn't complete
+/// yet is held back, along with the output of the last chunk of input, rather than the whole
+/// document.
+///
+/// The output can be read with `Read`, which returns errors as I/O errors that wrap the
+/// `ExecutionError`, or as an `Iterator` of chunks. Output that was produced before an error is
+/// returned before the error, and nothing is returned after it.
+///
+/// # Examples
+/// ```
+/// use esi::{Configuration, ExecutionContext, OutputReader, Request, Response, Result, Variables};
+/// use std::io::Read;
+///
+/// struct Backend;
+///
+/// impl ExecutionContext for Backend {
+///     fn send_request(&self, req: Request) -> Result<Response> {
+///         Ok(Response::new(200, format!("[{}]", req.url)))
+///     }
+/// }
+///
+/// let body = "<p><esi:include src=\"/a\"/></p>".as_bytes();
+/// let config = Configuration::default();
+/// let mut output = String::new();
+/// OutputReader::new(body, &Backend, &Variables::new(), &config).read_to_string(&mut output)?;
+/// assert_eq!(output, "<p>[/a]</p>");
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct OutputReader<'a, C: ExecutionContext + ?Sized, R: BufRead> {
+    input: R,
+    // The processor, until the document is finished or has failed
+    processor: Option<Processor<'a, C, Vec<u8>>>,
+    // Output that hasn't been read yet, from `position`
+    output: Vec<u8>,
+    position: usize,
+    // The error to return once the output before it has been read
+    error: Option<ExecutionError>,
+}
+
+impl<'a, C: ExecutionContext + ?Sized, R: BufRead> OutputReader<'a, C, R> {
+    /// Creates a reader of the processed output of the document that is read from `input`.
+    pub fn new(input: R, client: &'a C, variables: &Variables, config: &'a Configuration) -> Self {
+        Self {
+            input,
+            processor: Some(Processor::new(client, variables, config, Vec::new())),
+            output: Vec::new(),
+            position: 0,
+            error: None,
+        }
+    }
+
+    // Processes input until there is output to read, the document is finished, or it fails.
+    // Returns whether there is output or an error to return.
+    fn fill(&mut self) -> bool {
+        while self.position == self.output.len() && self.error.is_none() {
+            let processor = match &mut self.processor {
+                Some(processor) => processor,
+                None => return false,
+            };
+
+            self.output.clear();
+            self.position = 0;
+            let chunk = match self.input.fill_buf() {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    self.processor = None;
+                    self.error = Some(err.into());
+                    break;
+                }
+            };
+
+            if chunk.is_empty() {
+                let result = self.processor.take().map(Processor::finish);
+                match result {
+                    Some(Ok(output)) => self.output = output,
+                    Some(Err(err)) => self.error = Some(err),
+                    None => {}
+                }
+                continue;
+            }
+
+            let len = chunk.len();
+            let result = processor.feed(chunk);
+            self.input.consume(len);
+            std::mem::swap(&mut self.output, processor.output_mut());
+            if let Err(err) = result {
+                self.processor = None;
+                self.error = Some(err);
+            }
+        }
+
+        self.position < self.output.len() || self.error.is_some()
+    }
+
+    // Takes the output that hasn't been read yet, or the error once all output has been read.
+    fn take(&mut self) -> Option<Result<Bytes>> {
+        if !self.fill() {
+            return None;
+        }
+        if self.position < self.output.len() {
+            let mut output = std::mem::take(&mut self.output);
+            output.drain(..std::mem::take(&mut self.position));
+            return Some(Ok(Bytes::from(output)));
+        }
+        self.error.take().map(Err)
+    }
+}
+
+impl<C: ExecutionContext + ?Sized, R: BufRead> Read for OutputReader<'_, C, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.fill() {
+            return Ok(0);
+        }
+        if self.position == self.output.len() {
+            if let Some(err) = self.error.take() {
+                return Err(io::Error::other(err));
+            }
+        }
+
+        let len = buf.len().min(self.output.len() - self.position);
+        buf[..len].copy_from_slice(&self.output[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl<C: ExecutionContext + ?Sized, R: BufRead> Iterator for OutputReader<'_, C, R> {
+    type Item = Result<Bytes>;
+
+    fn next(&mut self) -> Option<Result<Bytes>> {
+        self.take()
+    }
+}
@@ -0,0 +1,123 @@
+//! Serialization of parsed documents, enabled by the `serde` feature. Content is serialized as a
+//! string when it is valid UTF-8, and as bytes otherwise, so that dumps of templates are readable
+//! without losing content in other encodings.
+
+use crate::{parse::Position, Tag, TagEntry};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+// Bytes that are serialized as a string if they are valid UTF-8.
+struct ByteStr<'a>(&'a [u8]);
+
+impl Serialize for ByteStr<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(self.0) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => serializer.serialize_bytes(self.0),
+        }
+    }
+}
+
+// Bytes that are deserialized from a string or a sequence of bytes.
+struct ByteString(Vec<u8>);
+
+impl<'de> Deserialize<'de> for ByteString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ByteStringVisitor)
+    }
+}
+
+struct ByteStringVisitor;
+
+impl<'de> de::Visitor<'de> for ByteStringVisitor {
+    type Value = ByteString;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a string or a sequence of bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<ByteString, E> {
+        Ok(ByteString(value.as_bytes().to_vec()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<ByteString, E> {
+        Ok(ByteString(value.to_vec()))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<ByteString, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(ByteString(bytes))
+    }
+}
+
+// Serializes the `Bytes` of a `TagEntry` as a string where possible, for use with
+// `#[serde(with)]`.
+pub(crate) mod bytes_string {
+    use super::{ByteStr, ByteString};
+    use bytes::Bytes;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        ByteStr(bytes).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        ByteString::deserialize(deserializer).map(|bytes| Bytes::from(bytes.0))
+    }
+}
+
+// The serialized form of a `Tag`, whose attributes are sorted by name so that dumps are stable.
+#[derive(Serialize)]
+struct TagRef<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<&'a str>,
+    attributes: BTreeMap<&'a str, &'a str>,
+    #[serde(skip_serializing_if = "<[TagEntry]>::is_empty")]
+    children: &'a [TagEntry],
+    position: Position,
+}
+
+#[derive(Deserialize)]
+struct TagRepr {
+    name: String,
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    attributes: HashMap<String, String>,
+    #[serde(default)]
+    children: Vec<TagEntry>,
+    position: Position,
+}
+
+impl Serialize for Tag {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        TagRef {
+            name: &self.name,
+            content: self.content.as_deref(),
+            attributes: self.parameters.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect(),
+            children: &self.children,
+            position: self.position,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Tag {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = TagRepr::deserialize(deserializer)?;
+        Ok(Tag {
+            name: repr.name,
+            content: repr.content,
+            parameters: repr.attributes,
+            children: repr.children,
+            position: repr.position,
+        })
+    }
+}
@@ -0,0 +1,182 @@
+//! Parsing of the `Surrogate-Control` response header, as defined by the
+//! [Edge Architecture Specification](https://www.w3.org/TR/edge-arch/).
+//!
+//! An origin requests ESI processing by sending `Surrogate-Control: content="ESI/1.0"`. Once the
+//! document has been processed, the `content` directive should be removed before the header is
+//! passed on, so that downstream surrogates don't process the document again.
+//!
+//! Surrogates advertise their own capabilities to origins with the `Surrogate-Capability` request
+//! header, e.g. `Surrogate-Capability: edge="ESI/1.0"`.
+
+use std::fmt;
+
+/// The content token that requests ESI 1.0 processing.
+pub const ESI_CONTENT_TOKEN: &str = "ESI/1.0";
+
+/// A single directive of a `Surrogate-Control` header, e.g. `max-age=60;device`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Directive {
+    pub name: String,
+    pub value: Option<String>,
+    /// The device token the directive is targeted at, if any.
+    pub target: Option<String>,
+}
+
+impl Directive {
+    /// Returns whether this directive applies to a surrogate identifying as `device`.
+    /// Untargeted directives apply to all surrogates.
+    pub fn applies_to(&self, device: Option<&str>) -> bool {
+        match &self.target {
+            Some(target) => Some(target.as_str()) == device,
+            None => true,
+        }
+    }
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(value) = &self.value {
+            if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric() || "-+.".contains(c)) {
+                write!(f, "=\"{}\"", value)?;
+            } else {
+                write!(f, "={}", value)?;
+            }
+        }
+        if let Some(target) = &self.target {
+            write!(f, ";{}", target)?;
+        }
+        Ok(())
+    }
+}
+
+/// A parsed `Surrogate-Control` header.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SurrogateControl {
+    pub directives: Vec<Directive>,
+}
+
+impl SurrogateControl {
+    /// Parses the value of a `Surrogate-Control` header. Malformed directives are skipped.
+    pub fn parse(header: &str) -> Self {
+        let directives = split_unquoted(header, ',')
+            .into_iter()
+            .filter_map(|directive| parse_directive(&directive))
+            .collect();
+
+        Self { directives }
+    }
+
+    /// Returns whether ESI processing was requested of a surrogate identifying as `device`.
+    pub fn requests_esi(&self, device: Option<&str>) -> bool {
+        self.directives.iter().any(|directive| {
+            directive.name.eq_ignore_ascii_case("content")
+                && directive.applies_to(device)
+                && directive
+                    .value
+                    .as_deref()
+                    .is_some_and(|value| value.split_whitespace().any(|token| token == ESI_CONTENT_TOKEN))
+        })
+    }
+
+    /// Returns the header that should be sent downstream once ESI processing is done for
+    /// `device`, with the `ESI/1.0` content token removed. Returns `None` if no directives remain.
+    pub fn without_esi(&self, device: Option<&str>) -> Option<String> {
+        let directives: Vec<String> = self
+            .directives
+            .iter()
+            .filter_map(|directive| {
+                if !directive.name.eq_ignore_ascii_case("content") || !directive.applies_to(device) {
+                    return Some(directive.clone());
+                }
+
+                let remaining: Vec<&str> = directive
+                    .value
+                    .as_deref()
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .filter(|token| *token != ESI_CONTENT_TOKEN)
+                    .collect();
+
+                if remaining.is_empty() {
+                    None
+                } else {
+                    Some(Directive {
+                        value: Some(remaining.join(" ")),
+                        ..directive.clone()
+                    })
+                }
+            })
+            .map(|directive| directive.to_string())
+            .collect();
+
+        if directives.is_empty() {
+            None
+        } else {
+            Some(directives.join(", "))
+        }
+    }
+}
+
+// Splits `input` on `separator`, ignoring separators within double quotes.
+fn split_unquoted(input: &str, separator: char) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in input.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            c if c == separator && !quoted => parts.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    parts.push(current);
+
+    parts
+}
+
+fn parse_directive(input: &str) -> Option<Directive> {
+    let mut parts = split_unquoted(input, ';').into_iter();
+    let directive = parts.next()?;
+    let target = parts.next().map(|target| target.trim().to_string()).filter(|target| !target.is_empty());
+
+    let (name, value) = match directive.split_once('=') {
+        Some((name, value)) => (name, Some(value.trim().trim_matches('"').to_string())),
+        None => (directive.as_str(), None),
+    };
+
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(Directive {
+        name: name.to_string(),
+        value,
+        target,
+    })
+}
+
+/// Returns whether a `Surrogate-Capability` header advertises that some surrogate is capable of
+/// ESI 1.0 processing.
+pub fn advertises_esi(surrogate_capability: &str) -> bool {
+    split_unquoted(surrogate_capability, ',').iter().any(|capability| match capability.split_once('=') {
+        Some((_, value)) => value.trim().trim_matches('"').split_whitespace().any(|token| token == ESI_CONTENT_TOKEN),
+        None => false,
+    })
+}
+
+/// Returns a `Surrogate-Capability` header advertising ESI 1.0 capability for `device`, appended
+/// to the capabilities of any surrogates that `existing` was received from.
+pub fn append_capability(existing: Option<&str>, device: &str) -> String {
+    let capability = format!("{}=\"{}\"", device, ESI_CONTENT_TOKEN);
+
+    match existing.map(str::trim).filter(|existing| !existing.is_empty()) {
+        Some(existing) => format!("{}, {}", existing, capability),
+        None => capability,
+    }
+}
@@ -0,0 +1,95 @@
+//! Handlers for custom tags, such as `<app:widget>`, that extend the set of tags documents may
+//! contain.
+//!
+//! A custom tag is recognised by its full name, including its prefix, once a handler has been
+//! registered for it with `Configuration::with_tag_handler`. It may be written as an empty tag, or
+//! as a start/end pair whose content is processed like the rest of the document and passed to the
+//! handler. Whatever the handler returns is written to the output in place of the tag.
+
+use crate::{ExecutionContext, Result, Tag, Variables};
+use std::{collections::HashMap, fmt, sync::Arc};
+
+/// A handler that renders a custom tag.
+pub trait TagHandler: Send + Sync {
+    /// Renders the tag, returning the bytes to write to the output in its place. An error fails
+    /// the document like a failed include does, unless it is within an `<esi:attempt>` block.
+    fn handle(&self, tag: &Tag, context: &TagContext<'_>) -> Result<Vec<u8>>;
+}
+
+impl<F> TagHandler for F
+where
+    F: Fn(&Tag, &TagContext<'_>) -> Result<Vec<u8>> + Send + Sync,
+{
+    fn handle(&self, tag: &Tag, context: &TagContext<'_>) -> Result<Vec<u8>> {
+        self(tag, context)
+    }
+}
+
+/// The set of custom tags that documents may contain, keyed by their full name, e.g. `app:widget`.
+#[derive(Clone, Default)]
+pub struct TagHandlers {
+    handlers: HashMap<String, Arc<dyn TagHandler>>,
+}
+
+impl TagHandlers {
+    /// Registers a handler for the tag with the given name, replacing any existing handler for it.
+    pub fn register(&mut self, name: impl Into<String>, handler: impl TagHandler + 'static) {
+        self.handlers.insert(name.into(), Arc::new(handler));
+    }
+
+    /// Returns the handler for the tag with the given name.
+    pub fn get(&self, name: &[u8]) -> Option<&dyn TagHandler> {
+        let name = std::str::from_utf8(name).ok()?;
+        self.handlers.get(name).map(|handler| handler.as_ref())
+    }
+
+    /// Returns whether a handler is registered for the tag with the given name.
+    pub fn contains(&self, name: &[u8]) -> bool {
+        self.get(name).is_some()
+    }
+
+    // Returns the names of the tags that have a registered handler.
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.handlers.keys().map(String::as_str)
+    }
+}
+
+impl fmt::Debug for TagHandlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<&String> = self.handlers.keys().collect();
+        names.sort();
+        f.debug_set().entries(names).finish()
+    }
+}
+
+/// What a `TagHandler` is given along with the tag it renders.
+pub struct TagContext<'a> {
+    pub(crate) client: &'a dyn ExecutionContext,
+    pub(crate) variables: &'a Variables,
+    pub(crate) content: &'a [u8],
+    pub(crate) depth: usize,
+}
+
+impl<'a> TagContext<'a> {
+    /// Returns the `ExecutionContext` that the document's fragments are requested with, so that a
+    /// handler can make requests of its own.
+    pub fn client(&self) -> &'a dyn ExecutionContext {
+        self.client
+    }
+
+    /// Returns the variables of the document at the tag, including any assigned before it.
+    pub fn variables(&self) -> &'a Variables {
+        self.variables
+    }
+
+    /// Returns the output of the content between the tag's start and end tags, after it has been
+    /// processed. This is empty for tags written as empty tags.
+    pub fn content(&self) -> &'a [u8] {
+        self.content
+    }
+
+    /// Returns the number of fragments that the tag's document is nested within.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
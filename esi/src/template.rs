@@ -0,0 +1,150 @@
+//! Documents that are parsed once and executed many times.
+
+use crate::{Configuration, DocumentState, ExecutionContext, Headers, ParsedDocument, Result, TagEntry, Variables};
+use crate::{Visitor, Warning};
+use std::io::{BufRead, Write};
+
+/// A parsed ESI document that can be executed any number of times, for services that serve the
+/// same template with different variables and fragments on every request. Executing a template
+/// gives the same output as processing its source with `transform_esi_string_with_config`, without
+/// parsing it again.
+///
+/// # Examples
+/// ```
+/// use esi::{ExecutionContext, Request, Response, Result, Template, Variables};
+///
+/// struct Backend;
+///
+/// impl ExecutionContext for Backend {
+///     fn send_request(&self, req: Request) -> Result<Response> {
+///         Ok(Response { body: format!("[{}]", req.url).into_bytes(), status_code: 200, headers: Vec::new() })
+///     }
+/// }
+///
+/// let template = Template::compile(&b"<p><esi:include src=\"/header\"/></p>"[..]).unwrap();
+/// let output = template.execute(&Backend, &Variables::new()).unwrap();
+/// assert_eq!(output, b"<p>[/header]</p>");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Template {
+    document: ParsedDocument,
+    config: Configuration,
+}
+
+impl Template {
+    /// Parses a document with the default `Configuration`.
+    pub fn compile(body: impl BufRead) -> Result<Self> {
+        Self::compile_with_config(body, &Configuration::default())
+    }
+
+    /// Parses a document with the given `Configuration`, which is also used to execute it.
+    pub fn compile_with_config(body: impl BufRead, config: &Configuration) -> Result<Self> {
+        Ok(Self {
+            document: ParsedDocument::parse(body, config)?,
+            config: config.clone(),
+        })
+    }
+
+    /// Creates a template from entries that were parsed or rewritten by other code, to be executed
+    /// with the given `Configuration`.
+    pub fn from_entries(entries: Vec<TagEntry>, config: &Configuration) -> Self {
+        Self {
+            document: ParsedDocument::from_entries(entries, 0),
+            config: config.clone(),
+        }
+    }
+
+    /// Returns the entries of the parsed document, so that it can be inspected without executing
+    /// it.
+    ///
+    /// # Examples
+    /// ```
+    /// use esi::{TagEntry, Template};
+    ///
+    /// let template = Template::compile(&b"<esi:include src=\"/a\"/><esi:vars><esi:include src=\"/b\"/></esi:vars>"[..]).unwrap();
+    /// let includes: Vec<&str> = template
+    ///     .entries()
+    ///     .iter()
+    ///     .flat_map(|entry| match entry {
+    ///         TagEntry::Vars(entries) => entries.as_slice(),
+    ///         entry => std::slice::from_ref(entry),
+    ///     })
+    ///     .filter_map(|entry| match entry {
+    ///         TagEntry::Tag(tag) if tag.name() == "esi:include" => tag.attr("src"),
+    ///         _ => None,
+    ///     })
+    ///     .collect();
+    /// assert_eq!(includes, ["/a", "/b"]);
+    /// ```
+    pub fn entries(&self) -> &[TagEntry] {
+        &self.document.entries
+    }
+
+    /// Returns the entries of the parsed document, to be rewritten and passed to `from_entries`.
+    pub fn into_entries(self) -> Vec<TagEntry> {
+        self.document.entries
+    }
+
+    /// Returns the configuration that the template is executed with.
+    pub fn config(&self) -> &Configuration {
+        &self.config
+    }
+
+    /// Executes the template with the given variables, and returns the transformed body.
+    pub fn execute(&self, client: &(impl ExecutionContext + ?Sized), variables: &Variables) -> Result<Vec<u8>> {
+        let output = self.document.execute_top_level(
+            client,
+            variables,
+            &self.config,
+            DocumentState::new(&self.config),
+            Vec::with_capacity(self.document.len),
+        )?;
+
+        Ok(output)
+    }
+
+    /// Executes the template with the given variables, writing the transformed body to `output` as
+    /// it is produced, like `transform_esi_string_to_writer`.
+    pub fn execute_to_writer(
+        &self,
+        client: &(impl ExecutionContext + ?Sized),
+        variables: &Variables,
+        output: impl Write,
+    ) -> Result<()> {
+        let document = DocumentState::new(&self.config);
+        self.document.execute_top_level(client, variables, &self.config, document, output)?;
+
+        Ok(())
+    }
+
+    /// Executes the template with the given variables, passing the transformed body to `visitor`
+    /// as it is produced, like `transform_esi_string_with_visitor`.
+    pub fn execute_with_visitor(
+        &self,
+        client: &(impl ExecutionContext + ?Sized),
+        variables: &Variables,
+        visitor: &mut impl Visitor,
+    ) -> Result<()> {
+        self.document.execute_with_visitor(client, variables, &self.config, visitor)
+    }
+
+    /// Executes the template with the given variables, and returns the transformed body along with
+    /// warnings about it, like `transform_esi_string_with_diagnostics`.
+    pub fn execute_with_diagnostics(
+        &self,
+        client: &(impl ExecutionContext + ?Sized),
+        variables: &Variables,
+    ) -> Result<(Vec<u8>, Vec<Warning>)> {
+        self.document.execute_with_diagnostics(client, variables, &self.config)
+    }
+
+    /// Executes the template with the given variables, and returns the transformed body along with
+    /// the collected fragment headers, like `transform_esi_string_with_headers`.
+    pub fn execute_with_headers(
+        &self,
+        client: &(impl ExecutionContext + ?Sized),
+        variables: &Variables,
+    ) -> Result<(Vec<u8>, Headers)> {
+        self.document.execute_with_headers(client, variables, &self.config)
+    }
+}
@@ -0,0 +1,312 @@
+//! Utilities for testing templates without a backend.
+//!
+//! `MockExecutionContext` responds to fragment requests with canned responses, records the
+//! requests that it receives, and can be told to fail or delay requests to particular URLs.
+//!
+//! A `Fixture` describes a document, the fragments it includes and the output it is expected to
+//! produce. `conformance_fixtures` returns a corpus of fixtures covering the examples of the ESI
+//! 1.0 specification, which adapters can run against their own `ExecutionContext` by serving
+//! each fixture's fragments from it.
+//!
+//! # Fixture format
+//! Fixtures are read from text by `parse_fixtures`. Each fixture starts with a `=== name` line,
+//! followed by sections that start with `---` lines. Anything before the first fixture is ignored.
+//!
+//! - `--- document` and `--- expected` are followed by the document and its expected output.
+//! - `--- fragment <url>` is followed by the body of the fragment at `url`.
+//! - `--- failure <url>` makes requests to `url` fail.
+//! - `--- header <name>: <value>` and `--- query <query string>` set the request that the
+//!   document's variables are read from.
+//! - `--- error` expects processing of the document to fail, instead of an `expected` section.
+//!
+//! Sections that have content end at the next `===` or `---` line. Blank lines at the end of a
+//! section, and the line break before the next line, aren't part of its content.
+
+use crate::{transform_esi_string_with_config, Configuration, ExecutionContext, ExecutionError, Request, Response};
+use crate::{Result, Variables};
+use std::{collections::HashMap, fmt, sync::Mutex, thread, time::Duration};
+use thiserror::Error;
+
+// What a `MockExecutionContext` does with a request to a mapped URL.
+#[derive(Debug, Clone)]
+enum Canned {
+    Response(Response),
+    // Fails the request with this message
+    Failure(String),
+}
+
+/// An `ExecutionContext` that maps URLs to canned responses, for testing templates.
+///
+/// Requests are matched by their whole URL, after variables have been expanded and URLs have
+/// been rewritten. Like `esi_fastly`, responses with a status outside the 2xx range fail with
+/// `ExecutionError::UnexpectedStatus`, and requests to URLs that aren't mapped fail with a 404
+/// status. Every request is recorded, in the order it was sent, whether or not it succeeded.
+///
+/// # Examples
+/// ```
+/// use esi::{test::MockExecutionContext, transform_esi_string, Variables};
+///
+/// let client = MockExecutionContext::new()
+///     .with_body("/header", "<h1>Hello</h1>")
+///     .with_failure("/ads", "backend unavailable");
+/// let body = "<esi:include src=\"/header\"/><esi:include src=\"/ads\" onerror=\"continue\"/>";
+///
+/// let output = transform_esi_string(body.as_bytes(), &client, &Variables::new())?;
+/// assert_eq!(output, b"<h1>Hello</h1>");
+/// assert_eq!(client.requested_urls(), ["/header", "/ads"]);
+/// # Ok::<(), esi::ExecutionError>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct MockExecutionContext {
+    responses: HashMap<String, Canned>,
+    latencies: HashMap<String, Duration>,
+    requests: Mutex<Vec<Request>>,
+}
+
+impl MockExecutionContext {
+    /// Creates a context that doesn't have any URLs mapped.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Responds to requests to `url` with the given response.
+    pub fn with_response(mut self, url: impl Into<String>, response: Response) -> Self {
+        self.responses.insert(url.into(), Canned::Response(response));
+        self
+    }
+
+    /// Responds to requests to `url` with a 200 response with the given body.
+    pub fn with_body(self, url: impl Into<String>, body: impl Into<Vec<u8>>) -> Self {
+        self.with_response(url, Response::new(200, body))
+    }
+
+    /// Fails requests to `url` with `ExecutionError::RequestError`, as if the backend couldn't be
+    /// reached.
+    pub fn with_failure(mut self, url: impl Into<String>, message: impl Into<String>) -> Self {
+        self.responses.insert(url.into(), Canned::Failure(message.into()));
+        self
+    }
+
+    /// Delays requests to `url` by the given duration before responding, e.g. to test timeouts.
+    /// The delay blocks the thread that sends the request.
+    pub fn with_latency(mut self, url: impl Into<String>, latency: Duration) -> Self {
+        self.latencies.insert(url.into(), latency);
+        self
+    }
+
+    /// Returns the requests that have been received, in the order they were sent.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().map(|requests| requests.clone()).unwrap_or_default()
+    }
+
+    /// Returns the URLs of the requests that have been received, in the order they were sent.
+    pub fn requested_urls(&self) -> Vec<String> {
+        self.requests().into_iter().map(|req| req.url).collect()
+    }
+
+    /// Forgets the requests that have been received.
+    pub fn clear_requests(&self) {
+        if let Ok(mut requests) = self.requests.lock() {
+            requests.clear();
+        }
+    }
+}
+
+impl ExecutionContext for MockExecutionContext {
+    fn send_request(&self, req: Request) -> Result<Response> {
+        if let Ok(mut requests) = self.requests.lock() {
+            requests.push(req.clone());
+        }
+        if let Some(latency) = self.latencies.get(&req.url) {
+            thread::sleep(*latency);
+        }
+
+        match self.responses.get(&req.url) {
+            Some(Canned::Response(response)) if (200..=299).contains(&response.status_code) => Ok(response.clone()),
+            Some(Canned::Response(response)) => Err(ExecutionError::UnexpectedStatus(req.url, response.status_code)),
+            Some(Canned::Failure(message)) => Err(ExecutionError::request(req.url, message.clone())),
+            None => Err(ExecutionError::UnexpectedStatus(req.url, 404)),
+        }
+    }
+}
+
+/// A document and the output that it is expected to produce given a set of fragments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fixture {
+    /// The name of the fixture, which describes what it tests.
+    pub name: String,
+    /// The document to process.
+    pub document: String,
+    /// The bodies of the fragments that the document includes, by URL.
+    pub fragments: Vec<(String, String)>,
+    /// The URLs of fragments whose requests fail.
+    pub failures: Vec<String>,
+    /// The headers of the request that the document's variables are read from.
+    pub headers: Vec<(String, String)>,
+    /// The query string of the request that the document's variables are read from.
+    pub query: Option<String>,
+    /// The expected output, or `None` if processing the document is expected to fail.
+    pub expected: Option<String>,
+}
+
+impl Fixture {
+    /// Returns a `MockExecutionContext` that serves the fixture's fragments and fails its
+    /// failures.
+    pub fn mock(&self) -> MockExecutionContext {
+        let mock = self
+            .fragments
+            .iter()
+            .fold(MockExecutionContext::new(), |mock, (url, body)| mock.with_body(url, body.as_str()));
+        self.failures
+            .iter()
+            .fold(mock, |mock, url| mock.with_failure(url, "fixture failure"))
+    }
+
+    /// Returns the variables of the fixture's request.
+    pub fn variables(&self) -> Variables {
+        let headers = self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+        Variables::from_request(headers, self.query.as_deref())
+    }
+
+    /// Processes the document with the given context and configuration, and checks that it
+    /// produces the expected output. Use `mock` for a context that serves the fixture's fragments.
+    pub fn check(
+        &self,
+        client: &(impl ExecutionContext + ?Sized),
+        config: &Configuration,
+    ) -> std::result::Result<(), Box<FixtureFailure>> {
+        let output = transform_esi_string_with_config(self.document.as_bytes(), client, &self.variables(), config)
+            .map(|output| String::from_utf8_lossy(&output).into_owned());
+        match (&output, &self.expected) {
+            (Ok(output), Some(expected)) if output == expected => Ok(()),
+            (Err(_), None) => Ok(()),
+            _ => Err(Box::new(FixtureFailure {
+                name: self.name.clone(),
+                expected: self.expected.clone(),
+                output,
+            })),
+        }
+    }
+}
+
+/// A fixture whose document didn't produce its expected output, returned by `Fixture::check`.
+#[derive(Debug)]
+pub struct FixtureFailure {
+    /// The name of the fixture.
+    pub name: String,
+    /// The expected output, or `None` if processing was expected to fail.
+    pub expected: Option<String>,
+    /// The output that was produced, or the error that processing failed with.
+    pub output: Result<String>,
+}
+
+impl fmt::Display for FixtureFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fixture `{}` ", self.name)?;
+        match &self.expected {
+            Some(expected) => write!(f, "expected {:?}", expected)?,
+            None => write!(f, "expected an error")?,
+        }
+        match &self.output {
+            Ok(output) => write!(f, ", but the output was {:?}", output),
+            Err(err) => write!(f, ", but processing failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for FixtureFailure {}
+
+/// An error in the text of a set of fixtures, returned by `parse_fixtures`.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid fixture at line {line}: {message}")]
+pub struct FixtureError {
+    /// The line of the error, starting from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+/// Returns the bundled fixtures that cover the examples of the ESI 1.0 specification. Some of
+/// them need the `expressions` and `variables` features.
+pub fn conformance_fixtures() -> Vec<Fixture> {
+    parse_fixtures(include_str!("../fixtures/esi-1.0.txt")).expect("the bundled fixtures are valid")
+}
+
+/// Reads fixtures written in the format described in the [module documentation](self).
+pub fn parse_fixtures(source: &str) -> std::result::Result<Vec<Fixture>, FixtureError> {
+    let mut fixtures: Vec<Fixture> = Vec::new();
+    // Whether the current fixture has an `expected` or `error` section
+    let mut has_outcome = false;
+    // The section whose content is being read, with the URL of `fragment` sections
+    let mut section: Option<(&str, &str)> = None;
+    let mut content: Vec<&str> = Vec::new();
+
+    // The end of the input is read as `None`, which finishes the last fixture
+    for (index, line) in source.lines().map(Some).chain(std::iter::once(None)).enumerate() {
+        let end = line.is_none();
+        let line = line.unwrap_or("===");
+        let error = |message: String| FixtureError { line: index + 1, message };
+        if !line.starts_with("===") && !line.starts_with("---") {
+            if section.is_some() {
+                content.push(line);
+            }
+            continue;
+        }
+
+        if let (Some((kind, url)), Some(fixture)) = (section.take(), fixtures.last_mut()) {
+            while content.last() == Some(&"") {
+                content.pop();
+            }
+            let content = std::mem::take(&mut content).join("\n");
+            match kind {
+                "document" => fixture.document = content,
+                "expected" => fixture.expected = Some(content),
+                _ => fixture.fragments.push((url.to_string(), content)),
+            }
+        }
+
+        if let Some(name) = line.strip_prefix("===") {
+            if let Some(fixture) = fixtures.last() {
+                if !has_outcome {
+                    return Err(error(format!("fixture `{}` has no `expected` or `error` section", fixture.name)));
+                }
+            }
+            if !end {
+                has_outcome = false;
+                fixtures.push(Fixture {
+                    name: name.trim().to_string(),
+                    ..Fixture::default()
+                });
+            }
+            continue;
+        }
+
+        let fixture = fixtures.last_mut().ok_or_else(|| error("section outside of a fixture".to_string()))?;
+        let line = line[3..].trim();
+        let (kind, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim();
+        match kind {
+            "document" => section = Some((kind, argument)),
+            "expected" | "error" if has_outcome => {
+                return Err(error(format!("fixture `{}` has more than one outcome", fixture.name)));
+            }
+            "expected" => {
+                has_outcome = true;
+                section = Some((kind, argument));
+            }
+            "error" => has_outcome = true,
+            "fragment" | "failure" | "query" | "header" if argument.is_empty() => {
+                return Err(error(format!("`{}` section has no argument", kind)));
+            }
+            "fragment" => section = Some((kind, argument)),
+            "failure" => fixture.failures.push(argument.to_string()),
+            "query" => fixture.query = Some(argument.to_string()),
+            "header" => match argument.split_once(':') {
+                Some((name, value)) => fixture.headers.push((name.trim().to_string(), value.trim().to_string())),
+                None => return Err(error(format!("header `{}` has no value", argument))),
+            },
+            _ => return Err(error(format!("unknown section `{}`", kind))),
+        }
+    }
+
+    Ok(fixtures)
+}
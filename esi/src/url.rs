@@ -0,0 +1,135 @@
+//! Resolution of relative URLs against the URL of the document they appear in.
+
+/// Resolves `reference` against the absolute URL `base`, as described in RFC 3986. References
+/// that are already absolute, and any reference if `base` isn't absolute, are returned as they
+/// are.
+pub(crate) fn resolve(base: &str, reference: &str) -> String {
+    if scheme(reference).is_some() {
+        return reference.to_string();
+    }
+    let base_scheme = match scheme(base) {
+        Some(scheme) => scheme,
+        None => return reference.to_string(),
+    };
+    if reference.starts_with("//") {
+        return format!("{}:{}", base_scheme, reference);
+    }
+
+    let base = split(&base[base_scheme.len() + 1..]);
+    let reference = split(reference);
+    let (path, query) = if reference.path.is_empty() {
+        (base.path.to_string(), reference.query.or(base.query))
+    } else if reference.path.starts_with('/') {
+        (remove_dot_segments(reference.path), reference.query)
+    } else {
+        let merged = match base.path.rfind('/') {
+            Some(end) => format!("{}{}", &base.path[..=end], reference.path),
+            None => format!("/{}", reference.path),
+        };
+        (remove_dot_segments(&merged), reference.query)
+    };
+
+    let mut url = format!("{}:", base_scheme);
+    if let Some(authority) = base.authority {
+        url.push_str("//");
+        url.push_str(authority);
+    }
+    url.push_str(&path);
+    for (separator, part) in [("?", query), ("#", reference.fragment)] {
+        if let Some(part) = part {
+            url.push_str(separator);
+            url.push_str(part);
+        }
+    }
+    url
+}
+
+/// Returns the host of `url` in lowercase, without its port or user information, if it is an
+/// absolute URL that has one. URLs containing backslashes, whitespace or control characters have
+/// no host, as WHATWG URL parsers, which adapters route requests with, read their hosts
+/// differently, e.g. treating `\` as a path delimiter.
+pub(crate) fn host(url: &str) -> Option<String> {
+    if url.chars().any(|c| c == '\\' || c.is_whitespace() || c.is_control()) {
+        return None;
+    }
+    let scheme = scheme(url)?;
+    let authority = split(&url[scheme.len() + 1..]).authority?;
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let host = match host.strip_prefix('[') {
+        // IPv6 addresses are bracketed, and contain colons
+        Some(address) => &address[..address.find(']')?],
+        None => host.split(':').next().unwrap_or(host),
+    };
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
+
+// Returns the scheme of `url`, if it is an absolute URL.
+fn scheme(url: &str) -> Option<&str> {
+    let end = url.find(':')?;
+    let scheme = &url[..end];
+    let mut chars = scheme.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    valid.then_some(scheme)
+}
+
+// The parts of a URL that follow its scheme.
+struct Parts<'a> {
+    authority: Option<&'a str>,
+    path: &'a str,
+    query: Option<&'a str>,
+    fragment: Option<&'a str>,
+}
+
+fn split(url: &str) -> Parts<'_> {
+    let (url, fragment) = match url.split_once('#') {
+        Some((url, fragment)) => (url, Some(fragment)),
+        None => (url, None),
+    };
+    let (url, query) = match url.split_once('?') {
+        Some((url, query)) => (url, Some(query)),
+        None => (url, None),
+    };
+    let (authority, path) = match url.strip_prefix("//") {
+        Some(rest) => {
+            let end = rest.find('/').unwrap_or(rest.len());
+            (Some(&rest[..end]), &rest[end..])
+        }
+        None => (None, url),
+    };
+
+    Parts {
+        authority,
+        path,
+        query,
+        fragment,
+    }
+}
+
+// Removes the `.` and `..` segments from an absolute path.
+fn remove_dot_segments(path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').collect();
+    let mut output: Vec<&str> = Vec::with_capacity(segments.len());
+
+    for (index, segment) in segments.iter().enumerate() {
+        let last = index == segments.len() - 1;
+        match *segment {
+            "." => {}
+            ".." => {
+                if output.len() > 1 {
+                    output.pop();
+                }
+            }
+            segment => {
+                output.push(segment);
+                continue;
+            }
+        }
+        // A path ending in a dot segment refers to a directory
+        if last {
+            output.push("");
+        }
+    }
+
+    output.join("/")
+}
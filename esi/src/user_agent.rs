@@ -0,0 +1,68 @@
+//! Parsing of the `User-Agent` header into the components exposed as
+//! `$(HTTP_USER_AGENT{'browser'})`, `$(HTTP_USER_AGENT{'version'})` and `$(HTTP_USER_AGENT{'os'})`.
+
+/// The components of a parsed `User-Agent` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserAgent {
+    /// The browser family, e.g. `MSIE`, `MOZILLA` or `OTHER`.
+    pub browser: String,
+    /// The browser version, e.g. `5.0`.
+    pub version: String,
+    /// The operating system, e.g. `WIN`, `MAC`, `UNIX` or `OTHER`.
+    pub os: String,
+}
+
+/// Parses `User-Agent` headers. Implement this to substitute a more capable parser for
+/// `SimpleUserAgentParser`.
+pub trait UserAgentParser {
+    fn parse(&self, user_agent: &str) -> UserAgent;
+}
+
+/// A parser that recognises the browser and OS families defined by the ESI specification.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SimpleUserAgentParser;
+
+impl UserAgentParser for SimpleUserAgentParser {
+    fn parse(&self, user_agent: &str) -> UserAgent {
+        let (browser, version) = if let Some(version) = version_after(user_agent, "MSIE ") {
+            ("MSIE", version)
+        } else if user_agent.contains("Trident/") {
+            ("MSIE", version_after(user_agent, "rv:").unwrap_or_default())
+        } else if let Some(version) = version_after(user_agent, "Mozilla/") {
+            ("MOZILLA", version)
+        } else {
+            ("OTHER", String::new())
+        };
+
+        let os = if user_agent.contains("Windows") {
+            "WIN"
+        } else if user_agent.contains("Mac") {
+            "MAC"
+        } else if ["Linux", "X11", "BSD", "SunOS"].iter().any(|os| user_agent.contains(os)) {
+            "UNIX"
+        } else {
+            "OTHER"
+        };
+
+        UserAgent {
+            browser: browser.to_string(),
+            version,
+            os: os.to_string(),
+        }
+    }
+}
+
+// Returns the version number immediately following `prefix`, e.g. `5.0` in `Mozilla/5.0 (...)`.
+fn version_after(user_agent: &str, prefix: &str) -> Option<String> {
+    let start = user_agent.find(prefix)? + prefix.len();
+    let version: String = user_agent[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+
+    if version.is_empty() {
+        None
+    } else {
+        Some(version)
+    }
+}
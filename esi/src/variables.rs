@@ -0,0 +1,548 @@
+//! Variables that can be referenced from ESI markup using the `$(NAME)` and `$(NAME{key})` syntax.
+//! A default value for when the variable is unset or empty can be given as `$(NAME|'default')`.
+//!
+//! Applications can expose their own variables, such as `$(GEO{country})`, by registering a
+//! `VariableProvider` that resolves them at execution time.
+//!
+//! References are only substituted with the `variables` feature enabled, which it is by default.
+//! Without it, they are left in place.
+
+use crate::user_agent::{SimpleUserAgentParser, UserAgentParser};
+use crate::Value;
+use std::{collections::HashMap, fmt, iter::FromIterator, sync::Arc};
+
+/// The value of a variable.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VariableValue {
+    String(String),
+    /// A list of values, such as `HTTP_ACCEPT_LANGUAGE`. Looking up a key in a list evaluates to
+    /// whether the list contains that key, ignoring case.
+    List(Vec<String>),
+    /// A value with named components, such as `HTTP_USER_AGENT`, `HTTP_COOKIE` or `QUERY_STRING`.
+    /// The raw value is used when no key is given.
+    Dictionary {
+        raw: String,
+        entries: HashMap<String, String>,
+    },
+}
+
+impl VariableValue {
+    // Resolves the value, or the given key within it.
+    fn resolve(&self, key: Option<&str>) -> Option<Value> {
+        match (self, key) {
+            (VariableValue::String(s), None) => Some(Value::String(s.clone())),
+            (VariableValue::String(_), Some(_)) => None,
+            (VariableValue::List(_), None) => Some(Value::String(self.to_string())),
+            (VariableValue::List(items), Some(key)) => {
+                Some(Value::Boolean(items.iter().any(|item| item.eq_ignore_ascii_case(key))))
+            }
+            (VariableValue::Dictionary { raw, .. }, None) => Some(Value::String(raw.clone())),
+            (VariableValue::Dictionary { entries, .. }, Some(key)) => entries.get(key).cloned().map(Value::String),
+        }
+    }
+}
+
+impl fmt::Display for VariableValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VariableValue::String(s) => write!(f, "{}", s),
+            VariableValue::List(items) => write!(f, "{}", items.join(", ")),
+            VariableValue::Dictionary { raw, .. } => write!(f, "{}", raw),
+        }
+    }
+}
+
+impl From<String> for VariableValue {
+    fn from(value: String) -> Self {
+        VariableValue::String(value)
+    }
+}
+
+impl From<&str> for VariableValue {
+    fn from(value: &str) -> Self {
+        VariableValue::String(value.to_string())
+    }
+}
+
+impl From<Vec<String>> for VariableValue {
+    fn from(items: Vec<String>) -> Self {
+        VariableValue::List(items)
+    }
+}
+
+/// Resolves variables at execution time, e.g. to expose application-specific namespaces.
+pub trait VariableProvider: Send + Sync {
+    /// Returns the value of the variable `name`, or of `key` within it if a key is given. Returning
+    /// `None` falls back to the next provider, and finally to the variables set on `Variables`.
+    fn resolve(&self, name: &str, key: Option<&str>) -> Option<Value>;
+}
+
+impl<F> VariableProvider for F
+where
+    F: Fn(&str, Option<&str>) -> Option<Value> + Send + Sync,
+{
+    fn resolve(&self, name: &str, key: Option<&str>) -> Option<Value> {
+        self(name, key)
+    }
+}
+
+/// A set of named variables available during ESI execution.
+#[derive(Default, Clone)]
+pub struct Variables {
+    values: HashMap<String, VariableValue>,
+    providers: Vec<Arc<dyn VariableProvider>>,
+    // The URL of the document that is being processed
+    document_url: Option<String>,
+    // The headers of the client request, which are forwarded to fragment requests
+    request_headers: Vec<(String, String)>,
+}
+
+impl fmt::Debug for Variables {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Variables")
+            .field("values", &self.values)
+            .field("providers", &self.providers.len())
+            .field("document_url", &self.document_url)
+            .field("request_headers", &self.request_headers)
+            .finish()
+    }
+}
+
+// Request headers that are exposed as standard ESI variables, and the variable each one populates.
+const REQUEST_HEADER_VARIABLES: &[(&str, &str)] = &[
+    ("host", "HTTP_HOST"),
+    ("cookie", "HTTP_COOKIE"),
+    ("user-agent", "HTTP_USER_AGENT"),
+    ("accept-language", "HTTP_ACCEPT_LANGUAGE"),
+    ("referer", "HTTP_REFERER"),
+];
+
+impl Variables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the standard ESI variables from the headers and query string of the original client
+    /// request: `HTTP_HOST`, `HTTP_COOKIE`, `HTTP_USER_AGENT`, `HTTP_ACCEPT_LANGUAGE`,
+    /// `HTTP_REFERER` and `QUERY_STRING`. Header names are matched case-insensitively, and repeated
+    /// headers are joined into a single value. `HTTP_ACCEPT_LANGUAGE` is a list of the language
+    /// tags of the header, without their quality values, `HTTP_COOKIE` is a dictionary of the
+    /// percent-decoded cookie values, and `HTTP_USER_AGENT` is parsed using `SimpleUserAgentParser`.
+    /// `QUERY_STRING` is a dictionary of the URL-decoded query parameters, where the values of a
+    /// parameter that occurs more than once are joined with `, `.
+    ///
+    /// The headers are kept, and copied onto fragment requests according to the configured
+    /// `HeaderForwarding`.
+    pub fn from_request<'a>(
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+        query_string: Option<&str>,
+    ) -> Self {
+        Self::from_request_with_parser(headers, query_string, &SimpleUserAgentParser)
+    }
+
+    /// Builds the standard ESI variables like `from_request`, using the given parser for the
+    /// components of `HTTP_USER_AGENT`.
+    pub fn from_request_with_parser<'a>(
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+        query_string: Option<&str>,
+        user_agent_parser: &dyn UserAgentParser,
+    ) -> Self {
+        let mut variables = Self::new();
+        let mut header_values: HashMap<&str, String> = HashMap::new();
+
+        for (name, value) in headers {
+            variables.request_headers.push((name.to_string(), value.to_string()));
+            let variable = REQUEST_HEADER_VARIABLES
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, variable)| *variable);
+
+            if let Some(variable) = variable {
+                let separator = if variable == "HTTP_COOKIE" { "; " } else { ", " };
+                header_values
+                    .entry(variable)
+                    .and_modify(|existing| {
+                        existing.push_str(separator);
+                        existing.push_str(value);
+                    })
+                    .or_insert_with(|| value.to_string());
+            }
+        }
+
+        for (variable, value) in header_values {
+            match variable {
+                "HTTP_ACCEPT_LANGUAGE" => variables.insert(variable, parse_accept_language(&value)),
+                "HTTP_COOKIE" => {
+                    let entries = parse_cookies(&value);
+                    variables.insert(variable, VariableValue::Dictionary { raw: value, entries })
+                }
+                "HTTP_USER_AGENT" => {
+                    let user_agent = user_agent_parser.parse(&value);
+                    let entries = vec![
+                        ("browser".to_string(), user_agent.browser),
+                        ("version".to_string(), user_agent.version),
+                        ("os".to_string(), user_agent.os),
+                    ];
+                    variables.insert(
+                        variable,
+                        VariableValue::Dictionary {
+                            raw: value,
+                            entries: entries.into_iter().collect(),
+                        },
+                    )
+                }
+                _ => variables.insert(variable, value),
+            }
+        }
+
+        if let Some(query_string) = query_string {
+            variables.insert(
+                "QUERY_STRING",
+                VariableValue::Dictionary {
+                    raw: query_string.to_string(),
+                    entries: parse_query_string(query_string),
+                },
+            );
+        }
+
+        variables
+    }
+
+    /// Sets the value of the variable with the given name.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<VariableValue>) {
+        self.values.insert(name.into(), value.into());
+    }
+
+    /// Registers a provider that is consulted before the variables set on this instance. Providers
+    /// are consulted in the order they were registered.
+    pub fn register_provider(&mut self, provider: impl VariableProvider + 'static) {
+        self.providers.push(Arc::new(provider));
+    }
+
+    /// Sets the URL of the document that is being processed, which is passed to the
+    /// `ExecutionContext` along with each of its requests. Relative URLs of includes, such as
+    /// `/nav` or `fragment.html`, are resolved against it, including those within fragments.
+    pub fn set_document_url(&mut self, url: impl Into<String>) {
+        self.document_url = Some(url.into());
+    }
+
+    /// Returns the headers of the client request that the variables were built from, in the order
+    /// they were given to `from_request`.
+    pub fn request_headers(&self) -> &[(String, String)] {
+        &self.request_headers
+    }
+
+    /// Returns the URL of the document that is being processed, if it has been set.
+    pub fn document_url(&self) -> Option<&str> {
+        self.document_url.as_deref()
+    }
+
+    /// Returns an iterator over the names and values of all variables that have been set.
+    /// Variables resolved by providers are not included.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &VariableValue)> {
+        self.values.iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// Returns the value of the given variable. If a `key` is given, the value for that key within
+    /// the variable is returned instead. Registered providers are consulted first.
+    pub fn get(&self, name: &str, key: Option<&str>) -> Option<Value> {
+        if let Some(value) = self.providers.iter().find_map(|provider| provider.resolve(name, key)) {
+            return Some(value);
+        }
+
+        self.values.get(name)?.resolve(key)
+    }
+}
+
+impl<K: Into<String>, V: Into<VariableValue>> Extend<(K, V)> for Variables {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, variables: I) {
+        for (name, value) in variables {
+            self.insert(name, value);
+        }
+    }
+}
+
+/// Builds variables from names and values, such as a `HashMap<String, String>` of
+/// application-defined variables.
+impl<K: Into<String>, V: Into<VariableValue>> FromIterator<(K, V)> for Variables {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(variables: I) -> Self {
+        let mut result = Self::new();
+        result.extend(variables);
+        result
+    }
+}
+
+// Parses an `Accept-Language` header into its language tags, e.g. `en-GB,en;q=0.8` becomes
+// `["en-GB", "en"]`.
+fn parse_accept_language(header: &str) -> Vec<String> {
+    header
+        .split(',')
+        .filter_map(|language| language.split(';').next())
+        .map(str::trim)
+        .filter(|language| !language.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// Parses a `Cookie` header into a map of cookie names to their percent-decoded values. When a
+// name occurs more than once, the first occurrence wins, as user agents send the most specific
+// cookie first.
+fn parse_cookies(header: &str) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+
+    for cookie in header.split(';') {
+        if let Some((name, value)) = cookie.split_once('=') {
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let value = value.trim().trim_matches('"');
+            cookies.entry(name.to_string()).or_insert_with(|| percent_decode(value));
+        }
+    }
+
+    cookies
+}
+
+// Parses a query string into a map of URL-decoded parameter names to their values.
+fn parse_query_string(query_string: &str) -> HashMap<String, String> {
+    let mut parameters: HashMap<String, String> = HashMap::new();
+
+    for parameter in query_string.split('&').filter(|parameter| !parameter.is_empty()) {
+        let (name, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+        let name = percent_decode(&name.replace('+', " "));
+        let value = percent_decode(&value.replace('+', " "));
+
+        parameters
+            .entry(name)
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            })
+            .or_insert(value);
+    }
+
+    parameters
+}
+
+// Decodes `%XX` escapes in `input`. Invalid escapes are left as they are, and invalid UTF-8 is
+// replaced with U+FFFD.
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut position = 0;
+
+    while position < bytes.len() {
+        let escaped = match (bytes[position], bytes.get(position + 1..position + 3)) {
+            (b'%', Some(hex)) if hex.iter().all(u8::is_ascii_hexdigit) => {
+                std::str::from_utf8(hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            }
+            _ => None,
+        };
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                position += 3;
+            }
+            None => {
+                decoded.push(bytes[position]);
+                position += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A parsed `$(NAME{key}|default)` variable reference.
+#[cfg(feature = "variables")]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct VariableReference {
+    pub name: String,
+    pub key: Option<String>,
+    pub default: Option<String>,
+}
+
+#[cfg(feature = "variables")]
+impl VariableReference {
+    pub fn resolve(&self, variables: &Variables) -> Option<Value> {
+        match variables.get(&self.name, self.key.as_deref()) {
+            Some(Value::String(s)) if s.is_empty() && self.default.is_some() => {
+                self.default.clone().map(Value::String)
+            }
+            None => self.default.clone().map(Value::String),
+            value => value,
+        }
+    }
+}
+
+/// Attempts to parse a variable reference at the start of `input`, returning the reference and
+/// the number of bytes it occupies.
+#[cfg(feature = "variables")]
+pub(crate) fn parse_reference(input: &[u8]) -> Option<(VariableReference, usize)> {
+    if !input.starts_with(b"$(") {
+        return None;
+    }
+
+    let mut position = 2;
+    let name_len = input[position..]
+        .iter()
+        .take_while(|c| c.is_ascii_alphanumeric() || **c == b'_')
+        .count();
+    if name_len == 0 {
+        return None;
+    }
+    let name = String::from_utf8(input[position..position + name_len].to_vec()).ok()?;
+    position += name_len;
+
+    let mut key = None;
+    if input.get(position) == Some(&b'{') {
+        let key_len = input[position + 1..].iter().position(|c| *c == b'}')?;
+        let raw_key = &input[position + 1..position + 1 + key_len];
+        let raw_key = match raw_key {
+            [b'\'', inner @ .., b'\''] => inner,
+            _ => raw_key,
+        };
+        key = Some(String::from_utf8(raw_key.to_vec()).ok()?);
+        position += key_len + 2;
+    }
+
+    let mut default = None;
+    if input.get(position) == Some(&b'|') {
+        position += 1;
+        let raw_default = if input.get(position) == Some(&b'\'') {
+            let len = input[position + 1..].iter().position(|c| *c == b'\'')?;
+            let raw_default = &input[position + 1..position + 1 + len];
+            position += len + 2;
+            raw_default
+        } else {
+            let len = input[position..].iter().position(|c| *c == b')')?;
+            let raw_default = &input[position..position + len];
+            position += len;
+            raw_default
+        };
+        default = Some(String::from_utf8(raw_default.to_vec()).ok()?);
+    }
+
+    if input.get(position) != Some(&b')') {
+        return None;
+    }
+
+    Some((VariableReference { name, key, default }, position + 1))
+}
+
+/// Replaces all variable references in `input` with their values. Unset variables are replaced
+/// with an empty string, and anything that isn't a valid reference is left untouched.
+#[cfg(feature = "variables")]
+pub(crate) fn substitute(input: &[u8], variables: &Variables) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut position = 0;
+
+    while position < input.len() {
+        if let Some((reference, len)) = parse_reference(&input[position..]) {
+            if let Some(value) = reference.resolve(variables) {
+                output.extend_from_slice(value.to_string().as_bytes());
+            }
+            position += len;
+        } else {
+            output.push(input[position]);
+            position += 1;
+        }
+    }
+
+    output
+}
+
+// Without the `variables` feature, variable references are left in place.
+#[cfg(not(feature = "variables"))]
+pub(crate) fn substitute(input: &[u8], _variables: &Variables) -> Vec<u8> {
+    input.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_language_is_a_list_of_language_tags() {
+        let headers = [("Accept-Language", "en-GB,en;q=0.8"), ("accept-language", " fr ;q=0.5")];
+        let variables = Variables::from_request(headers, None);
+
+        assert_eq!(
+            variables.iter().find(|(name, _)| *name == "HTTP_ACCEPT_LANGUAGE").unwrap().1,
+            &VariableValue::List(vec!["en-GB".to_string(), "en".to_string(), "fr".to_string()])
+        );
+        assert_eq!(
+            variables.get("HTTP_ACCEPT_LANGUAGE", None),
+            Some(Value::String("en-GB, en, fr".to_string()))
+        );
+    }
+
+    #[test]
+    fn keys_of_lists_test_membership_ignoring_case() {
+        let variables = Variables::from_request([("Accept-Language", "en-GB,en;q=0.8")], None);
+
+        assert_eq!(variables.get("HTTP_ACCEPT_LANGUAGE", Some("en-gb")), Some(Value::Boolean(true)));
+        assert_eq!(variables.get("HTTP_ACCEPT_LANGUAGE", Some("EN")), Some(Value::Boolean(true)));
+        assert_eq!(variables.get("HTTP_ACCEPT_LANGUAGE", Some("en-US")), Some(Value::Boolean(false)));
+        assert_eq!(variables.get("HTTP_ACCEPT_LANGUAGE", Some("en-")), Some(Value::Boolean(false)));
+    }
+
+    #[test]
+    fn cookies_are_a_dictionary_of_decoded_values() {
+        let headers = [("Cookie", "id=1; name=\"J%C3%B6rg%20B\"; id=2"), ("cookie", "theme=dark;; =x; flag")];
+        let variables = Variables::from_request(headers, None);
+        let cookie = |name| variables.get("HTTP_COOKIE", Some(name));
+
+        // The first of the cookies with the same name wins, including across `Cookie` headers
+        assert_eq!(cookie("id"), Some(Value::String("1".to_string())));
+        assert_eq!(cookie("name"), Some(Value::String("J\u{f6}rg B".to_string())));
+        assert_eq!(cookie("theme"), Some(Value::String("dark".to_string())));
+        assert_eq!(cookie("flag"), None);
+        assert_eq!(
+            variables.get("HTTP_COOKIE", None),
+            Some(Value::String("id=1; name=\"J%C3%B6rg%20B\"; id=2; theme=dark;; =x; flag".to_string()))
+        );
+    }
+
+    #[test]
+    fn invalid_percent_escapes_are_left_as_they_are() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz%41"), "%zzA");
+        assert_eq!(percent_decode("%e2%82"), "\u{fffd}");
+        assert_eq!(percent_decode("a+b%2Bc"), "a+b+c");
+    }
+
+    #[test]
+    fn query_strings_are_a_dictionary_of_decoded_parameters() {
+        let variables = Variables::from_request(Vec::new(), Some("q=caf%C3%A9+au+lait&tag=a&tag=b%2Bc&empty&&x%=1"));
+        let parameter = |name| variables.get("QUERY_STRING", Some(name));
+
+        assert_eq!(parameter("q"), Some(Value::String("caf\u{e9} au lait".to_string())));
+        // Repeated parameters are joined
+        assert_eq!(parameter("tag"), Some(Value::String("a, b+c".to_string())));
+        assert_eq!(parameter("empty"), Some(Value::String(String::new())));
+        assert_eq!(parameter("x%"), Some(Value::String("1".to_string())));
+        assert_eq!(parameter("missing"), None);
+        assert_eq!(
+            variables.get("QUERY_STRING", None),
+            Some(Value::String("q=caf%C3%A9+au+lait&tag=a&tag=b%2Bc&empty&&x%=1".to_string()))
+        );
+    }
+
+    #[cfg(feature = "expressions")]
+    #[test]
+    fn list_membership_can_be_tested_in_expressions() {
+        use crate::{expression::evaluate, Functions};
+
+        let mut variables = Variables::new();
+        variables.insert("LANGUAGES", vec!["de".to_string(), "en-GB".to_string()]);
+        let test = |expression| evaluate(expression, &variables, &Functions::default()).unwrap().to_bool();
+
+        assert!(test("$(LANGUAGES{'en-gb'})"));
+        assert!(!test("$(LANGUAGES{'fr'})"));
+        assert!(test("$(LANGUAGES{'de'}) & !$(LANGUAGES{'fr'})"));
+    }
+}
@@ -0,0 +1,64 @@
+//! Callbacks for the output of a document as it is produced, for integrations that stream the
+//! output into their own body types, or that need to know which parts of it came from fragments.
+
+use crate::{ExecutionError, Result};
+use std::{cell::RefCell, io};
+
+/// An event in the output of a document, passed to a `Visitor`.
+#[derive(Debug)]
+pub enum OutputEvent<'e> {
+    /// The next chunk of the output, in document order.
+    Content(&'e [u8]),
+    /// The fragment of an include was received with the given status. The content events that
+    /// follow, up to the next include or error event, are its rendered content.
+    Include { src: &'e str, status: u16 },
+    /// The fragment of an include or `<esi:eval>` couldn't be rendered. The content events that
+    /// follow are its fallback content, if it has any. The error is returned from processing as
+    /// well, unless it is handled by a fallback or `onerror="continue"`.
+    Error { src: &'e str, error: &'e ExecutionError },
+}
+
+/// Receives the output of a document as it is produced.
+///
+/// Include and error events are reported for the includes of the top-level document. Includes
+/// within fragments, `<esi:attempt>` blocks and custom tags are part of the content of whatever
+/// contains them, as that is only output once it is complete.
+pub trait Visitor {
+    /// Handles the next event. Returning an error stops processing the document.
+    fn visit(&mut self, event: OutputEvent<'_>) -> Result<()>;
+}
+
+impl<F> Visitor for F
+where
+    F: FnMut(OutputEvent<'_>) -> Result<()>,
+{
+    fn visit(&mut self, event: OutputEvent<'_>) -> Result<()> {
+        self(event)
+    }
+}
+
+// A visitor that is borrowed for the processing of a document.
+pub(crate) struct VisitorRef<'a, V: ?Sized>(pub(crate) &'a mut V);
+
+impl<V: Visitor + ?Sized> Visitor for VisitorRef<'_, V> {
+    fn visit(&mut self, event: OutputEvent<'_>) -> Result<()> {
+        self.0.visit(event)
+    }
+}
+
+// Passes the output written to it to a visitor as content events. Errors of the visitor are
+// returned as I/O errors, which `output_error` recovers.
+pub(crate) struct VisitorWriter<'a>(pub(crate) &'a RefCell<dyn Visitor + 'a>);
+
+impl io::Write for VisitorWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            self.0.borrow_mut().visit(OutputEvent::Content(buf)).map_err(io::Error::other)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
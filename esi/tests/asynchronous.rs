@@ -0,0 +1,72 @@
+#![cfg(all(feature = "async", feature = "cache"))]
+
+use esi::{
+    transform_esi_string_async, AsyncExecutionContext, CachedResponse, Configuration, FragmentCache, Freshness,
+    Request, Response, Result, Variables,
+};
+use std::{
+    future::Future,
+    pin::pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+// Responds to every request immediately, recording the URLs that were requested.
+#[derive(Default)]
+struct Backend {
+    requested: Mutex<Vec<String>>,
+}
+
+impl AsyncExecutionContext for Backend {
+    fn send_request(&self, req: Request) -> impl Future<Output = Result<Response>> + Send {
+        self.requested.lock().unwrap().push(req.url.clone());
+        std::future::ready(Ok(Response::new(200, "fresh").with_header("Cache-Control", "max-age=60")))
+    }
+}
+
+// Holds a stale response for every request, recording the responses that are stored.
+#[derive(Clone, Default)]
+struct StaleCache {
+    stored: Arc<Mutex<Vec<(String, Freshness)>>>,
+}
+
+impl FragmentCache for StaleCache {
+    fn get(&self, _key: &str) -> Option<CachedResponse> {
+        Some(CachedResponse {
+            response: Response::new(200, "stale"),
+            stale: true,
+        })
+    }
+
+    fn put(&self, _key: &str, response: &Response, freshness: Freshness) {
+        let body = String::from_utf8_lossy(&response.body).into_owned();
+        self.stored.lock().unwrap().push((body, freshness));
+    }
+}
+
+// Polls a future that never waits on anything until it is done.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
+#[test]
+fn stale_fragments_are_refreshed() {
+    let cache = StaleCache::default();
+    let config = Configuration::default().with_fragment_cache(cache.clone());
+    let backend = Backend::default();
+
+    let body = b"<esi:include src=\"/a\"/>";
+    let output = block_on(transform_esi_string_async(body, &backend, &Variables::new(), &config)).unwrap();
+    assert_eq!(output, b"stale");
+    assert_eq!(*backend.requested.lock().unwrap(), ["/a"]);
+    let stored = cache.stored.lock().unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].0, "fresh");
+}
+
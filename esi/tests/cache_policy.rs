@@ -0,0 +1,44 @@
+use esi::{
+    test::MockExecutionContext, transform_esi_string_with_cache_policy, Configuration, IncludeLimitAction, Response,
+    Variables,
+};
+use std::time::Duration;
+
+fn cache_control(body: &str, config: &Configuration) -> String {
+    let fragment = |body| Response::new(200, body).with_header("Cache-Control", "max-age=60");
+    let client = MockExecutionContext::new()
+        .with_response("http://example.com/a", fragment("a"))
+        .with_response("http://example.com/loop", fragment("<esi:include src=\"http://example.com/loop\"/>"))
+        .with_response("http://other.example/b", fragment("b"));
+    let variables = Variables::new();
+    let (_, policy) =
+        transform_esi_string_with_cache_policy(body.as_bytes(), &client, &variables, config, Some("max-age=300"))
+            .unwrap();
+    policy.cache_control()
+}
+
+#[test]
+fn pages_missing_a_fragment_may_not_be_reused() {
+    let config = Configuration::default();
+    let include = "<esi:include src=\"http://example.com/a\"/>";
+    assert_eq!(cache_control(include, &config), "max-age=60");
+
+    // The fragment is replaced by the placeholder
+    let limited = config.clone().with_max_includes(1, IncludeLimitAction::Placeholder("-".to_string()));
+    assert_eq!(cache_control(&include.repeat(2), &limited), "no-cache");
+
+    // The fragment isn't requested from a host that isn't allowed
+    let allowed = config.clone().with_allowed_hosts(["example.com"]);
+    let body = format!("{}<esi:include src=\"http://other.example/b\" onerror=\"continue\"/>", include);
+    assert_eq!(cache_control(&body, &allowed), "no-cache");
+
+    // The fragment includes itself
+    let recursive = config.clone().with_recursion(5);
+    let body = "<esi:include src=\"http://example.com/loop\" onerror=\"continue\"/>";
+    assert_eq!(cache_control(body, &recursive), "no-cache");
+
+    // The include is dropped for its priority
+    let dropping = config.with_low_priority_time_limit(Duration::ZERO);
+    let body = format!("{}<esi:include src=\"http://example.com/a\" priority=\"low\"/>", include);
+    assert_eq!(cache_control(&body, &dropping), "no-cache");
+}
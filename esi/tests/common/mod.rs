@@ -0,0 +1,7 @@
+use esi::{transform_esi_string_with_config, Configuration, ExecutionContext, Variables};
+
+// Processes a document with the given configuration, returning its output as a string.
+pub fn process(body: &str, client: &impl ExecutionContext, config: &Configuration) -> esi::Result<String> {
+    let output = transform_esi_string_with_config(body.as_bytes(), client, &Variables::new(), config)?;
+    Ok(String::from_utf8(output).unwrap())
+}
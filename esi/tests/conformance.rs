@@ -0,0 +1,24 @@
+#![cfg(all(feature = "expressions", feature = "variables"))]
+
+use esi::{
+    test::{conformance_fixtures, parse_fixtures},
+    Configuration,
+};
+
+#[test]
+fn spec_examples_produce_their_expected_output() {
+    let config = Configuration::default();
+    let failures: Vec<String> = conformance_fixtures()
+        .iter()
+        .filter_map(|fixture| fixture.check(&fixture.mock(), &config).err())
+        .map(|failure| failure.to_string())
+        .collect();
+    assert!(failures.is_empty(), "{:#?}", failures);
+}
+
+#[test]
+fn fixtures_without_an_outcome_are_rejected() {
+    let err = parse_fixtures("=== a\n--- document\nx\n=== b\n--- error").unwrap_err();
+    assert_eq!(err.line, 4);
+    assert!(parse_fixtures("--- document\nx").is_err());
+}
@@ -0,0 +1,55 @@
+use esi::{
+    test::MockExecutionContext, transform_esi_string_to_writer, transform_esi_string_with_config, Configuration,
+    ExecutionError, Limits, Observer, Variables,
+};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct Failures(Arc<Mutex<Vec<String>>>);
+
+impl Observer for Failures {
+    fn document_failed(&self, error: &ExecutionError) {
+        self.0.lock().unwrap().push(error.to_string());
+    }
+}
+
+#[test]
+fn documents_that_fail_are_returned_as_they_were_received() {
+    let failures = Failures::default();
+    let config = Configuration::default().with_fail_open().with_observer(failures.clone());
+    let client = MockExecutionContext::new().with_body("/a", "a");
+
+    for body in ["<p><esi:include src=\"/missing\"/></p>", "<esi:remove>unclosed", "<esi:include src=\"/a\"/>"] {
+        let output = transform_esi_string_with_config(body.as_bytes(), &client, &Variables::new(), &config).unwrap();
+        let expected = if body.contains("/a") { "a" } else { body };
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+    assert_eq!(failures.0.lock().unwrap().len(), 2);
+
+    let limits = Limits {
+        max_buffered_bytes: 16,
+        ..Limits::default()
+    };
+    let body = "<esi:include src=\"/a\"/> and more than sixteen bytes";
+    let config = config.with_limits(limits);
+    let output = transform_esi_string_with_config(body.as_bytes(), &client, &Variables::new(), &config).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), body);
+}
+
+#[test]
+fn streamed_documents_fail_open_when_they_fail_to_parse() {
+    let failures = Failures::default();
+    let config = Configuration::default().with_fail_open().with_observer(failures.clone());
+    let client = MockExecutionContext::new();
+
+    let body = "<p><esi:remove>unclosed";
+    let mut output = Vec::new();
+    transform_esi_string_to_writer(body.as_bytes(), &client, &Variables::new(), &config, &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), body);
+
+    // Part of the output has been written by the time the include fails
+    let body = "<p><esi:include src=\"/missing\"/></p>";
+    let result = transform_esi_string_to_writer(body.as_bytes(), &client, &Variables::new(), &config, Vec::new());
+    assert!(result.is_err());
+    assert_eq!(failures.0.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,26 @@
+use esi::{
+    test::MockExecutionContext, transform_esi_string_with_config, transform_esi_string_with_diagnostics, Configuration,
+    Variables, Warning,
+};
+
+#[test]
+fn alt_fallbacks_are_recorded() {
+    let client = MockExecutionContext::new().with_body("/b", "b");
+    let body = b"<esi:include src=\"/a\" alt=\"/missing /b\"/>";
+
+    let (output, warnings) =
+        transform_esi_string_with_diagnostics(&body[..], &client, &Variables::new(), &Configuration::default())
+            .unwrap();
+    assert_eq!(output, b"b");
+    assert!(
+        matches!(&warnings[..], [Warning::AltFetched { src, alt, .. }] if src == "/a" && alt == "/b"),
+        "{:?}",
+        warnings
+    );
+    assert_eq!(client.requested_urls(), ["/a", "/missing", "/b"]);
+
+    let config = Configuration::default().with_debug();
+    let output = transform_esi_string_with_config(&body[..], &client, &Variables::new(), &config).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("include /a => failed, fetched /b instead"), "{}", output);
+}
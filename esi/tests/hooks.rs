@@ -0,0 +1,33 @@
+use esi::{test::MockExecutionContext, transform_esi_string_with_config, Configuration, Request, Response, Variables};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+#[test]
+fn request_hooks_run_once_for_each_include() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = calls.clone();
+    let config = Configuration::default()
+        .with_request_hook(move |req: &mut Request| {
+            let call = counted.fetch_add(1, Ordering::SeqCst);
+            req.set_header("X-Signature", call.to_string());
+        })
+        .with_response_hook(|req: &Request, resp: &mut Response| {
+            // The response is passed along with the request that was sent
+            resp.body.extend_from_slice(req.get_header("X-Signature").unwrap_or_default().as_bytes());
+            Ok(())
+        });
+    let client = MockExecutionContext::new().with_body("/a", "a").with_body("/b", "b");
+
+    let body = "<esi:include src=\"/a\"/><esi:include src=\"/b\"/><esi:include src=\"/a\"/>";
+    let output = transform_esi_string_with_config(body.as_bytes(), &client, &Variables::new(), &config).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "a0b1a2");
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    let signatures: Vec<String> = client
+        .requests()
+        .iter()
+        .map(|req| req.get_header("X-Signature").unwrap().to_string())
+        .collect();
+    assert_eq!(signatures, ["0", "1", "2"]);
+}
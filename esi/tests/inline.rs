@@ -0,0 +1,36 @@
+mod common;
+
+use common::process;
+use esi::{test::MockExecutionContext, Configuration};
+
+#[test]
+fn inline_fragments_are_rendered_where_they_are_included() {
+    let client = MockExecutionContext::new();
+    let body = "<esi:inline name=\"/nav\" fetchable=\"no\">nav</esi:inline>[<esi:include src=\"/nav\"/>]";
+
+    assert_eq!(process(body, &client, &Configuration::default()).unwrap(), "[nav]");
+    assert!(client.requested_urls().is_empty());
+}
+
+#[cfg(feature = "cache")]
+#[test]
+fn fetchable_inline_fragments_are_cached_for_later_documents() {
+    let client = MockExecutionContext::new().with_body("/footer", "fetched footer");
+    let config = Configuration::default()
+        .with_fragment_cache(esi::MemoryCache::new())
+        .with_inline_fragment_ttl(std::time::Duration::from_secs(60));
+
+    let body = "<esi:inline name=\"/nav\" fetchable=\"yes\"><b>nav</b></esi:inline>\
+                <esi:inline name=\"/footer\" fetchable=\"no\">footer</esi:inline>";
+    assert_eq!(process(body, &client, &config).unwrap(), "");
+
+    let body = "<esi:include src=\"/nav\"/> <esi:include src=\"/footer\"/>";
+    assert_eq!(process(body, &client, &config).unwrap(), "<b>nav</b> fetched footer");
+    assert_eq!(client.requested_urls(), ["/footer"]);
+
+    // Without a lifetime for them, inline fragments aren't cached
+    let client = MockExecutionContext::new().with_body("/nav", "fetched nav");
+    let config = Configuration::default().with_fragment_cache(esi::MemoryCache::new());
+    process("<esi:inline name=\"/nav\" fetchable=\"yes\">nav</esi:inline>", &client, &config).unwrap();
+    assert_eq!(process("<esi:include src=\"/nav\"/>", &client, &config).unwrap(), "fetched nav");
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use common::process;
+use esi::{
+    test::MockExecutionContext, CancellationToken, Configuration, ExecutionContext, ExecutionError, IncludeLimitAction,
+    Limits, Request, Response,
+};
+use std::time::{Duration, Instant};
+
+fn client() -> MockExecutionContext {
+    MockExecutionContext::new()
+        .with_body("/a", "a")
+        .with_body("/b", "b")
+        .with_body("/c", "c")
+        .with_body("/nested", "[<esi:include src=\"/a\"/>]")
+}
+
+#[test]
+fn includes_past_the_limit_are_not_requested() {
+    let client = client();
+    let body = "<esi:include src=\"/a\"/><esi:include src=\"/b\"/><esi:include src=\"/c\"/>";
+
+    let config = Configuration::default().with_max_includes(2, IncludeLimitAction::Error);
+    let err = process(body, &client, &config).unwrap_err();
+    assert!(
+        matches!(&err, ExecutionError::AtPosition { source, .. }
+            if matches!(**source, ExecutionError::LimitExceeded("number of includes", 2))),
+        "{:?}",
+        err
+    );
+    assert_eq!(client.requested_urls(), ["/a", "/b"]);
+
+    client.clear_requests();
+    let config = Configuration::default().with_max_includes(2, IncludeLimitAction::Placeholder("-".to_string()));
+    assert_eq!(process(body, &client, &config).unwrap(), "ab-");
+    assert_eq!(client.requested_urls(), ["/a", "/b"]);
+
+    // The includes of fragments count towards the limit of the document
+    client.clear_requests();
+    let config = config.with_recursion(2).with_max_includes(1, IncludeLimitAction::Placeholder("-".to_string()));
+    assert_eq!(process("<esi:include src=\"/nested\"/>", &client, &config).unwrap(), "[-]");
+    assert_eq!(client.requested_urls(), ["/nested"]);
+}
+
+#[test]
+fn fragments_and_output_over_their_size_limits_are_rejected() {
+    let client = client().with_body("/large", "too large");
+    let limits = Limits {
+        max_fragment_bytes: 4,
+        ..Limits::default()
+    };
+    let config = Configuration::default().with_limits(limits);
+    let body = "<esi:include src=\"/large\"/>";
+    let err = process(body, &client, &config).unwrap_err();
+    assert!(
+        matches!(&err, ExecutionError::FragmentFailed { source, .. }
+            if matches!(**source, ExecutionError::LimitExceeded("fragment size", 4))),
+        "{:?}",
+        err
+    );
+    let body = "<esi:include src=\"/large\" onerror=\"continue\"/><esi:include src=\"/a\"/>";
+    assert_eq!(process(body, &client, &config).unwrap(), "a");
+
+    let limits = Limits {
+        max_output_bytes: 3,
+        ..Limits::default()
+    };
+    let config = Configuration::default().with_limits(limits);
+    let body = "<esi:include src=\"/a\"/><esi:include src=\"/b\"/><esi:include src=\"/c\"/>";
+    assert_eq!(process(body, &client, &config).unwrap(), "abc");
+    // Neither text nor fragments may take the output over the limit
+    for extra in ["!", "<esi:include src=\"/a\" onerror=\"continue\"/>"] {
+        let err = process(&format!("{}{}", body, extra), &client, &config).unwrap_err();
+        assert!(matches!(err, ExecutionError::LimitExceeded("output size", 3)), "{:?}", err);
+    }
+}
+
+#[test]
+fn processing_stops_once_the_time_limit_has_passed() {
+    let client = client().with_body("/slow", "slow").with_latency("/slow", Duration::from_millis(100));
+    let config = Configuration::default().with_time_limit(Duration::from_millis(50));
+
+    let started = Instant::now();
+    let body = "<esi:include src=\"/slow\"/><esi:include src=\"/a\"/>";
+    let err = process(body, &client, &config).unwrap_err();
+    let finished = Instant::now();
+    assert!(matches!(err, ExecutionError::TimeLimitExceeded), "{:?}", err);
+    assert_eq!(client.requested_urls(), ["/slow"]);
+    // Requests time out when the time runs out, rather than after the response has been received
+    let deadline = client.requests()[0].deadline.unwrap();
+    assert!(deadline >= started + Duration::from_millis(50) && deadline < finished);
+}
+
+// Cancels the token once a request to `/cancel` is received.
+struct Cancelling {
+    mock: MockExecutionContext,
+    token: CancellationToken,
+}
+
+impl ExecutionContext for Cancelling {
+    fn send_request(&self, req: Request) -> esi::Result<Response> {
+        if req.url == "/cancel" {
+            self.token.cancel();
+        }
+        self.mock.send_request(req)
+    }
+}
+
+#[test]
+fn processing_stops_once_it_is_cancelled() {
+    let client = Cancelling {
+        mock: client().with_body("/cancel", "cancel"),
+        token: CancellationToken::new(),
+    };
+    let config = Configuration::default().with_cancellation(client.token.clone());
+
+    let body = "<esi:include src=\"/a\"/><esi:include src=\"/cancel\"/><esi:include src=\"/b\"/>";
+    let err = process(body, &client, &config).unwrap_err();
+    assert!(matches!(err, ExecutionError::Cancelled), "{:?}", err);
+    assert_eq!(client.mock.requested_urls(), ["/a", "/cancel"]);
+}
@@ -0,0 +1,223 @@
+use esi::{
+    transform_esi_string_with_config, Configuration, ConformanceMode, ExecutionContext, Processor, Request, Response,
+    Tag, TagContext, Variables,
+};
+use std::{
+    panic::{self, AssertUnwindSafe},
+    time::Duration,
+};
+
+// Responds with fragments that are as malformed as the documents that include them.
+struct Fragments;
+
+impl ExecutionContext for Fragments {
+    fn send_request(&self, req: Request) -> esi::Result<Response> {
+        if req.url.contains("fail") {
+            return Err(esi::ExecutionError::RequestFailed(req.url));
+        }
+        // Fragments that evaluate themselves, and ever deeper fragments
+        if req.url.contains("eval") {
+            let body = format!("<esi:eval src=\"{0}\" onerror=\"continue\"/><esi:eval src=\"{0}x\"/>", req.url);
+            return Ok(Response::new(200, body.into_bytes()));
+        }
+        let body = match req.url.len() % 4 {
+            0 => format!("<esi:include src=\"{}x\"/>", req.url),
+            1 => "<esi:choose><esi:when test=\"$(".to_string(),
+            2 => "<esi:vars>$(HTTP_COOKIE{</esi:vars>\u{fffd}".to_string(),
+            _ => "\u{0}<esi:try><esi:attempt>".to_string(),
+        };
+        Ok(Response::new(200, body.into_bytes())
+            .with_header("Content-Type", "text/html; charset=\u{e9}")
+            .with_header("Cache-Control", "max-age=18446744073709551615, stale-while-revalidate=18446744073709551615"))
+    }
+}
+
+const DOCUMENTS: &[&[u8]] = &[
+    b"",
+    b"<",
+    b"</",
+    b"<esi:",
+    b"<esi:include",
+    b"<esi:include src=",
+    b"<esi:include src=\"",
+    b"<esi:include src='/a' src='/b'/>",
+    b"<esi:include src=/a/>",
+    b"<esi:include/>",
+    b"<esi:include src=\"/a\">",
+    b"</esi:include>",
+    b"<esi:include src=\"$(\"/>",
+    b"<esi:include src=\"$(HTTP_COOKIE{\"/>",
+    b"<esi:include src=\"/a\" alt=\"\" maxwait=\"-1\" timeout=\"99999999999999999999\"/>",
+    b"<esi:include src=\"/a\" maxwait=\"18446744073709551615\"/>",
+    b"<esi:include src=\"/a\" method=\"\xff\" headers=\"=;;=a;\xfe=\"/>",
+    b"<esi:include src=\"\xff\xfe\"/>",
+    b"<esi:include src=\"/a\" onerror=\"\xc3\"/>",
+    b"<esi:include src=\"fail\"><esi:include src=\"fail\"/></esi:include>",
+    b"<esi:choose>",
+    b"<esi:choose><esi:when>",
+    b"<esi:choose><esi:when test=\"\">a</esi:when></esi:choose>",
+    b"<esi:choose><esi:when test=\"((((((((((\">a</esi:when></esi:choose>",
+    b"<esi:choose><esi:when test=\"1 == \">a</esi:when><esi:otherwise></esi:choose>",
+    b"<esi:choose><esi:otherwise>a</esi:otherwise><esi:otherwise>b</esi:otherwise></esi:choose>",
+    b"<esi:choose><esi:when test=\"'a' matches '('\">a</esi:when></esi:choose>",
+    b"<esi:choose><esi:when test=\"$substr('abc', 99999999999, -5)\">a</esi:when></esi:choose>",
+    b"<esi:choose><esi:when test=\"$index('\xe9', '')\">a</esi:when></esi:choose>",
+    b"<esi:when test=\"1\">a</esi:when>",
+    b"</esi:choose>",
+    b"<esi:try>",
+    b"<esi:try><esi:except>a</esi:except></esi:try>",
+    b"<esi:try><esi:attempt><esi:attempt></esi:attempt></esi:try>",
+    b"<esi:vars>$(",
+    b"<esi:vars>$(a{'</esi:vars>",
+    b"<esi:vars>$(HTTP_COOKIE{\xff})</esi:vars>",
+    b"<esi:vars>$(QUERY_STRING{a}|'</esi:vars>",
+    b"<esi:assign name=\"a\"/>",
+    b"<esi:assign name=\"a\" value=\"[1,2\"/>",
+    b"<esi:assign name=\"a\" value=\"{'a':\"/><esi:vars>$(a{a})</esi:vars>",
+    b"<esi:assign name=\"\" value=\"1\"/>",
+    b"<esi:assign name=\"a\">",
+    b"<esi:eval/>",
+    b"<esi:eval src=\"/a\">",
+    b"<esi:eval src=\"/eval\"/>",
+    b"<esi:eval src=\"/eval\" onerror=\"continue\"/><esi:include src=\"/eval\"/>",
+    b"<esi:inline>a</esi:inline>",
+    b"<esi:inline name=\"/a\">a",
+    b"<esi:inline name=\"/a\"><esi:include src=\"/a\"/></esi:inline><esi:include src=\"/a\"/>",
+    b"<esi:text>",
+    b"<esi:text></esi:text></esi:text>",
+    b"<esi:remove>",
+    b"<esi:remove></esi:remove></esi:remove>",
+    b"<esi:comment text=\"",
+    b"<esi:comment>",
+    b"<esi:debug>",
+    b"<esi:unknown/>",
+    b"<esi:unknown>",
+    b"<!--esi",
+    b"<!--esi <esi:include src=\"/a\"/>",
+    b"<!--esi-->",
+    b"<!--esi <!--esi <esi:vars>$(a)</esi:vars> --> -->",
+    b"<![CDATA[<esi:include src=\"/a\"/>",
+    b"<script><esi:include src=\"/a\"/>",
+    b"<style>",
+    b"<?xml",
+    b"<!DOCTYPE [",
+    b"<x xmlns:e=\"http://www.edge-delivery.org/esi/1.0\"><e:include src=\"/a\"/>",
+    b"<x xmlns:e=\"http://www.edge-delivery.org/esi/1.0\"><e:include src=\"/a\"></esi:include>",
+    b"<esi:include src=\"/a\"/>\xff\xfe\xfd<esi:vars>\xc3\x28</esi:vars>",
+    b"<app:widget>",
+    b"<app:widget></app:widget></app:widget>",
+    b"<app:widget a=\"\xff\"><esi:include src=\"fail\"/></app:widget>",
+    b"&amp;&#xFFFFFFFF;&#0;&bogus;<esi:include src=\"&#xFFFFFFFF;\"/>",
+];
+
+// Seed documents that are mutated byte by byte, covering each of the constructs.
+const SEEDS: &[&[u8]] = &[
+    b"<p><esi:include src=\"/a\" alt=\"/b\" onerror=\"continue\">fb</esi:include></p>",
+    b"<esi:choose><esi:when test=\"$(HTTP_COOKIE{a}) == 'b' && !(1 > 2)\">x</esi:when><esi:otherwise>y</esi:otherwise></esi:choose>",
+    b"<esi:try><esi:attempt><esi:include src=\"fail\"/></esi:attempt><esi:except>e</esi:except></esi:try>",
+    b"<esi:assign name=\"l\" value=\"[1, 'a', {'k': 2}]\"/><esi:vars>$(l{2}) $(QUERY_STRING{q}|'d')</esi:vars>",
+    b"<!--esi <esi:inline name=\"/i\">in</esi:inline><esi:include src=\"/i\"/> --><esi:remove>r</esi:remove>",
+    b"<esi:text><esi:include src=\"/a\"/></esi:text><esi:comment text=\"c\"/><app:widget n=\"1\">w</app:widget>",
+];
+
+fn configurations() -> Vec<Configuration> {
+    let widget = |_: &Tag, context: &TagContext<'_>| Ok(context.content().to_vec());
+    let cached = Configuration::default();
+    #[cfg(feature = "cache")]
+    let cached = cached.with_fragment_cache(esi::MemoryCache::new());
+    vec![
+        Configuration::default(),
+        Configuration::default().with_recursion(3),
+        Configuration::default().with_conformance(ConformanceMode::Strict),
+        Configuration::default().with_html_parsing().with_unquoted_attributes(),
+        Configuration::default()
+            .with_html_parsing()
+            .with_esi_in_scripts()
+            .with_whitespace_trimming()
+            .with_auto_close()
+            .with_recursion(2),
+        cached
+            .with_time_limit(Duration::MAX)
+            .with_low_priority_time_limit(Duration::MAX)
+            .with_default_timeout(Duration::MAX),
+        Configuration::default()
+            .with_tag_handler("app:widget", widget)
+            .with_max_nesting_depth(2)
+            .with_debug(),
+    ]
+}
+
+// Processes the document whole and in chunks, with each configuration, failing with the document
+// if processing panics. Errors are expected, and ignored.
+fn process(document: &[u8], configurations: &[Configuration]) {
+    let mut variables = Variables::from_request(vec![("Cookie", "a=b; \u{e9}=\u{0}")], Some("q=%FF%&&=a"));
+    variables.set_document_url("https://example.com/dir/page");
+
+    for config in configurations {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = transform_esi_string_with_config(document, &Fragments, &variables, config);
+            for chunk_size in [1, 7] {
+                let mut processor = Processor::new(&Fragments, &variables, config, Vec::new());
+                let fed = document.chunks(chunk_size).try_for_each(|chunk| processor.feed(chunk));
+                if fed.is_ok() {
+                    let _ = processor.finish();
+                }
+            }
+        }));
+
+        assert!(
+            result.is_ok(),
+            "processing panicked on {:?} with {:?}",
+            String::from_utf8_lossy(document),
+            config
+        );
+    }
+}
+
+#[test]
+fn malformed_documents_do_not_panic() {
+    let configurations = configurations();
+    for document in DOCUMENTS {
+        process(document, &configurations);
+    }
+}
+
+#[test]
+fn malformed_documents_do_not_panic_when_nested() {
+    let configurations = configurations();
+    for document in DOCUMENTS {
+        let nested = [b"<esi:vars><esi:try><esi:attempt>".as_ref(), document].concat();
+        process(&nested, &configurations);
+    }
+}
+
+#[test]
+fn mutated_documents_do_not_panic() {
+    let configurations = configurations();
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let bytes = b"<>/\"'=:${}()[]|&!-\x00\xff esi";
+
+    for seed in SEEDS {
+        for _ in 0..150 {
+            let mut document = seed.to_vec();
+            for _ in 0..1 + random() % 4 {
+                let position = (random() % (document.len() as u64 + 1)) as usize;
+                let byte = bytes[(random() % bytes.len() as u64) as usize];
+                match random() % 3 {
+                    0 if position < document.len() => document[position] = byte,
+                    1 if position < document.len() => {
+                        document.remove(position);
+                    }
+                    _ => document.insert(position, byte),
+                }
+            }
+            process(&document, &configurations);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use esi::{
+    transform_esi_string, transform_esi_string_with_config, Configuration, ExecutionContext, Processor, Request, Response,
+    Variables,
+};
+
+struct Fragments;
+
+impl ExecutionContext for Fragments {
+    fn send_request(&self, req: Request) -> esi::Result<Response> {
+        Ok(Response {
+            body: format!("<p>{}</p>", req.url).into_bytes(),
+            status_code: 200,
+            headers: Vec::new(),
+        })
+    }
+}
+
+const DOCUMENT: &str = r#"<?xml version="1.0"  encoding='UTF-8' standalone="yes" ?>
+<?xml-stylesheet type="text/xsl" href="/style.xsl"?>
+<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Strict//EN"
+  "http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd">
+<html xmlns="http://www.w3.org/1999/xhtml"><esi:include src="/header"/><?php echo 'hi'; ?></html>"#;
+
+const EXPECTED: &str = r#"<?xml version="1.0"  encoding='UTF-8' standalone="yes" ?>
+<?xml-stylesheet type="text/xsl" href="/style.xsl"?>
+<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Strict//EN"
+  "http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd">
+<html xmlns="http://www.w3.org/1999/xhtml"><p>/header</p><?php echo 'hi'; ?></html>"#;
+
+#[test]
+fn prolog_is_preserved() {
+    let output = transform_esi_string(DOCUMENT.as_bytes(), &Fragments, &Default::default()).unwrap();
+
+    assert_eq!(String::from_utf8(output).unwrap(), EXPECTED);
+}
+
+#[test]
+fn prolog_is_preserved_when_parsing_html() {
+    let config = Configuration::default().with_html_parsing();
+    let output = transform_esi_string_with_config(DOCUMENT.as_bytes(), &Fragments, &Default::default(), &config).unwrap();
+
+    assert_eq!(String::from_utf8(output).unwrap(), EXPECTED);
+}
+
+#[test]
+fn prolog_is_preserved_when_streaming() {
+    let config = Configuration::default();
+    let variables = Variables::default();
+    let mut processor = Processor::new(&Fragments, &variables, &config, Vec::new());
+    for chunk in DOCUMENT.as_bytes().chunks(3) {
+        processor.feed(chunk).unwrap();
+    }
+    let output = processor.finish().unwrap();
+
+    assert_eq!(String::from_utf8(output).unwrap(), EXPECTED);
+}
+
+#[test]
+fn doctype_with_internal_subset_is_preserved() {
+    let document = "<!DOCTYPE note [\n  <!ENTITY writer \"Writer: Donald Duck.\">\n  <!-- comment -->\n]>\n<note>&writer;</note>";
+    let output = transform_esi_string(document.as_bytes(), &Fragments, &Default::default()).unwrap();
+
+    assert_eq!(String::from_utf8(output).unwrap(), document);
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use common::process;
+use esi::{test::MockExecutionContext, Configuration, ExecutionError};
+
+// Returns the error that caused a fragment, or the fragments it was nested within, to fail.
+fn root_cause(err: &ExecutionError) -> &ExecutionError {
+    match err {
+        ExecutionError::FragmentFailed { source, .. } | ExecutionError::AtPosition { source, .. } => root_cause(source),
+        err => err,
+    }
+}
+
+#[test]
+fn fragments_that_include_themselves_fail() {
+    let client = MockExecutionContext::new()
+        .with_body("/a", "a<esi:include src=\"/b\"/>")
+        .with_body("/b", "b<esi:include src=\"/a\"/>");
+    let config = Configuration::default().with_recursion(8);
+
+    let err = process("<esi:include src=\"/a\"/>", &client, &config).unwrap_err();
+    assert!(matches!(root_cause(&err), ExecutionError::IncludeCycle(cycle) if cycle == "/a -> /b -> /a"));
+    assert_eq!(client.requested_urls(), ["/a", "/b"]);
+}
+
+#[test]
+fn fragments_that_evaluate_themselves_fail() {
+    let client = MockExecutionContext::new().with_body("/e", "<esi:assign name=\"x\" value=\"'1'\"/><esi:eval src=\"/e\"/>");
+
+    for config in [Configuration::default(), Configuration::default().with_recursion(8)] {
+        let err = process("<esi:eval src=\"/e\"/>", &client, &config).unwrap_err();
+        assert!(matches!(root_cause(&err), ExecutionError::IncludeCycle(cycle) if cycle == "/e -> /e"));
+
+        // The variables assigned before the cycle was detected are kept
+        #[cfg(feature = "expressions")]
+        {
+            let body = "<esi:eval src=\"/e\" onerror=\"continue\"/><esi:vars>$(x)</esi:vars>";
+            assert_eq!(process(body, &client, &config).unwrap(), "1");
+        }
+    }
+
+    // Fragments that evaluate ever deeper fragments stop at the recursion depth
+    let client = MockExecutionContext::new()
+        .with_body("/d", "<esi:eval src=\"/d/d\"/>")
+        .with_body("/d/d", "<esi:eval src=\"/d/d/d\"/>")
+        .with_body("/d/d/d", "<esi:eval src=\"/d/d/d/d\"/>");
+    let err = process("<esi:eval src=\"/d\"/>", &client, &Configuration::default().with_recursion(2)).unwrap_err();
+    assert!(matches!(root_cause(&err), ExecutionError::LimitExceeded("fragment depth", 2)));
+    assert_eq!(client.requested_urls(), ["/d", "/d/d"]);
+}
@@ -0,0 +1,67 @@
+use esi::{transform_esi_string_with_config, Configuration, ExecutionContext, Processor, Request, Response, Variables};
+
+struct Fragments;
+
+impl ExecutionContext for Fragments {
+    fn send_request(&self, req: Request) -> esi::Result<Response> {
+        Ok(Response {
+            body: format!("[{}]", req.url).into_bytes(),
+            status_code: 200,
+            headers: Vec::new(),
+        })
+    }
+}
+
+fn transform(document: &str, config: &Configuration) -> String {
+    let output = transform_esi_string_with_config(document.as_bytes(), &Fragments, &Variables::default(), config).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+fn transform_streaming(document: &str, config: &Configuration) -> String {
+    let variables = Variables::default();
+    let mut processor = Processor::new(&Fragments, &variables, config, Vec::new());
+    for chunk in document.as_bytes().chunks(2) {
+        processor.feed(chunk).unwrap();
+    }
+    String::from_utf8(processor.finish().unwrap()).unwrap()
+}
+
+#[test]
+fn self_closing_remove_is_dropped() {
+    let config = Configuration::default();
+
+    assert_eq!(transform("<p>a<esi:remove/>b</p>", &config), "<p>ab</p>");
+    assert_eq!(transform("<p>a<esi:remove />b</p>", &config), "<p>ab</p>");
+}
+
+#[test]
+fn self_closing_remove_keeps_following_content() {
+    let document = r#"<p><esi:remove/><esi:include src="/a"/>after</p>"#;
+
+    assert_eq!(transform(document, &Configuration::default()), "<p>[/a]after</p>");
+}
+
+#[test]
+fn mixed_remove_forms() {
+    let document = r#"<div><esi:remove/>one<esi:remove></esi:remove>two<esi:remove><a href="/a">fallback</a></esi:remove>three<esi:remove /></div>"#;
+    let expected = "<div>onetwothree</div>";
+
+    assert_eq!(transform(document, &Configuration::default()), expected);
+    assert_eq!(transform(document, &Configuration::default().with_html_parsing()), expected);
+    assert_eq!(transform_streaming(document, &Configuration::default()), expected);
+}
+
+#[test]
+fn self_closing_remove_within_remove_block() {
+    let document = "<p>a<esi:remove>b<esi:remove/>c</esi:remove>d</p>";
+
+    assert_eq!(transform(document, &Configuration::default()), "<p>ad</p>");
+}
+
+#[test]
+fn self_closing_remove_line_is_trimmed() {
+    let document = "<ul>\n  <esi:remove/>\n  <li>a</li>\n</ul>";
+    let config = Configuration::default().with_whitespace_trimming();
+
+    assert_eq!(transform(document, &config), "<ul>\n  <li>a</li>\n</ul>");
+}
@@ -0,0 +1,232 @@
+mod common;
+
+use common::process;
+use esi::{
+    test::MockExecutionContext, transform_esi_string_with_config, CoalescingContext, Configuration, ExecutionContext,
+    PendingRequest, Request, Response, Result, RetryPolicy, Variables,
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Sends requests to a mock as soon as they are started, keeping track of when they were sent and
+// of the number of requests that have been started but not yet waited for.
+#[derive(Default)]
+struct Backend {
+    mock: MockExecutionContext,
+    sent: Mutex<Vec<Instant>>,
+    pending: AtomicUsize,
+    max_pending: AtomicUsize,
+}
+
+impl Backend {
+    fn new(mock: MockExecutionContext) -> Self {
+        Self {
+            mock,
+            ..Self::default()
+        }
+    }
+
+    fn process(&self, body: &str, config: &Configuration) -> String {
+        process(body, self, config).unwrap()
+    }
+}
+
+impl ExecutionContext for Backend {
+    fn send_request(&self, req: Request) -> Result<Response> {
+        self.sent.lock().unwrap().push(Instant::now());
+        self.mock.send_request(req)
+    }
+
+    fn send_request_async(&self, req: Request) -> PendingRequest<'_> {
+        let pending = self.pending.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_pending.fetch_max(pending, Ordering::SeqCst);
+        let result = self.send_request(req);
+        PendingRequest::new(move || {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            result
+        })
+    }
+}
+
+fn includes(urls: &[&str]) -> String {
+    urls.iter().map(|url| format!("<esi:include src=\"{}\"/>", url)).collect()
+}
+
+#[test]
+fn independent_includes_are_requested_concurrently() {
+    let mock = ["/1", "/2", "/3", "/4", "/5"]
+        .iter()
+        .fold(MockExecutionContext::new(), |mock, url| mock.with_body(*url, &url[1..]));
+    let body = includes(&["/1", "/2", "/3", "/4", "/5"]);
+
+    let backend = Backend::new(mock);
+    assert_eq!(backend.process(&body, &Configuration::default()), "12345");
+    assert_eq!(backend.max_pending.load(Ordering::SeqCst), 5);
+    assert_eq!(backend.mock.requested_urls(), ["/1", "/2", "/3", "/4", "/5"]);
+
+    let backend = Backend::new(backend.mock);
+    backend.mock.clear_requests();
+    let config = Configuration::default().with_max_concurrent_requests(2);
+    assert_eq!(backend.process(&body, &config), "12345");
+    assert_eq!(backend.max_pending.load(Ordering::SeqCst), 2);
+    assert_eq!(backend.mock.requested_urls(), ["/1", "/2", "/3", "/4", "/5"]);
+
+}
+
+#[cfg(feature = "variables")]
+#[test]
+fn includes_after_an_assignment_are_requested_once_it_has_been_executed() {
+    let backend = Backend::new(MockExecutionContext::new().with_body("/1", "1").with_body("/2", "2"));
+    let body = "<esi:include src=\"/1\"/><esi:assign name=\"n\" value=\"2\"/><esi:include src=\"/$(n)\"/>";
+    assert_eq!(backend.process(body, &Configuration::default()), "12");
+    assert_eq!(backend.max_pending.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn repeated_includes_are_only_requested_once() {
+    let backend = Backend::new(MockExecutionContext::new().with_body("/item", "i"));
+    let body = format!("{}<esi:include src=\"/item\" method=\"POST\"/>", includes(&["/item"; 3]));
+
+    assert_eq!(backend.process(&body, &Configuration::default()), "iiii");
+    let methods: Vec<_> = backend.mock.requests().into_iter().map(|req| req.method).collect();
+    assert_eq!(methods, ["GET", "POST"]);
+
+    backend.mock.clear_requests();
+    let config = Configuration::default().without_include_deduplication();
+    assert_eq!(backend.process(&body, &config), "iiii");
+    assert_eq!(backend.mock.requested_urls(), ["/item"; 4]);
+}
+
+#[test]
+fn failed_requests_are_retried_with_backoff() {
+    let mock = MockExecutionContext::new()
+        .with_response("/unavailable", Response::new(503, "unavailable"))
+        .with_response("/gone", Response::new(404, "gone"));
+    let backend = Backend::new(mock);
+    let config = Configuration::default().with_retry_policy(RetryPolicy {
+        max_attempts: 3,
+        backoff: Duration::from_millis(20),
+        ..RetryPolicy::default()
+    });
+
+    let body = "<esi:include src=\"/unavailable\" onerror=\"continue\"/>";
+    assert_eq!(backend.process(body, &config), "");
+    assert_eq!(backend.mock.requested_urls(), ["/unavailable"; 3]);
+    // The backoff doubles for each retry
+    let sent = backend.sent.lock().unwrap().clone();
+    assert!(sent[1] - sent[0] >= Duration::from_millis(20));
+    assert!(sent[2] - sent[1] >= Duration::from_millis(40));
+
+    // Statuses that aren't in the policy aren't retried
+    backend.mock.clear_requests();
+    assert_eq!(backend.process("<esi:include src=\"/gone\" onerror=\"continue\"/>", &config), "");
+    assert_eq!(backend.mock.requested_urls(), ["/gone"]);
+
+    // Nor are requests whose deadline would pass during the backoff
+    backend.mock.clear_requests();
+    let body = "<esi:include src=\"/unavailable\" timeout=\"10\" onerror=\"continue\"/>";
+    assert_eq!(backend.process(body, &config), "");
+    assert_eq!(backend.mock.requested_urls(), ["/unavailable"]);
+}
+
+#[test]
+fn identical_requests_of_concurrent_documents_are_coalesced() {
+    let mock = MockExecutionContext::new()
+        .with_body("/slow", "slow")
+        .with_latency("/slow", Duration::from_millis(200));
+    let client = CoalescingContext::new(mock);
+    let config = Configuration::default();
+
+    let outputs = thread::scope(|scope| {
+        let first = scope.spawn(|| process("<esi:include src=\"/slow\"/>", &client, &config));
+        // The second document requests the fragment while the first is waiting for it
+        thread::sleep(Duration::from_millis(50));
+        let second = scope.spawn(|| process("<esi:include src=\"/slow\"/>", &client, &config));
+        [first.join().unwrap().unwrap(), second.join().unwrap().unwrap()]
+    });
+    assert_eq!(outputs, ["slow", "slow"]);
+    assert_eq!(client.inner().requested_urls(), ["/slow"]);
+
+    // Requests are sent afresh once the response has been received
+    assert_eq!(process("<esi:include src=\"/slow\"/>", &client, &config).unwrap(), "slow");
+    assert_eq!(client.inner().requested_urls(), ["/slow"; 2]);
+}
+
+#[test]
+fn includes_are_requested_by_priority() {
+    let mock = MockExecutionContext::new().with_body("/a", "a").with_body("/b", "b").with_body("/c", "c");
+    let backend = Backend::new(mock);
+    let body = "<esi:include src=\"/a\"/>\
+                <esi:include src=\"/b\" priority=\"low\"/>\
+                <esi:include src=\"/c\" priority=\"high\"/>";
+
+    assert_eq!(backend.process(body, &Configuration::default()), "abc");
+    assert_eq!(backend.mock.requested_urls(), ["/c", "/a", "/b"]);
+
+    // Low-priority includes aren't requested once their time limit has passed
+    backend.mock.clear_requests();
+    let config = Configuration::default().with_low_priority_time_limit(Duration::ZERO);
+    assert_eq!(backend.process(body, &config), "ac");
+    assert_eq!(backend.mock.requested_urls(), ["/c", "/a"]);
+}
+
+#[cfg(feature = "expressions")]
+#[test]
+fn only_the_includes_of_the_selected_branch_are_requested() {
+    let mock = MockExecutionContext::new().with_body("/a", "a").with_body("/b", "b").with_body("/c", "c");
+    let backend = Backend::new(mock);
+    let body = "<esi:include src=\"/a\"/>\
+                <esi:choose>\
+                    <esi:when test=\"1 == 2\"><esi:include src=\"/b\"/></esi:when>\
+                    <esi:otherwise><esi:include src=\"/c\"/></esi:otherwise>\
+                </esi:choose>";
+
+    assert_eq!(backend.process(body, &Configuration::default()), "ac");
+    assert_eq!(backend.mock.requested_urls(), ["/a", "/c"]);
+    // The branch is selected ahead of executing the block, so its include is requested alongside
+    // the one before it
+    assert_eq!(backend.max_pending.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn relative_urls_are_resolved_against_the_document_url() {
+    let client = MockExecutionContext::new()
+        .with_body("https://example.com/dir/nested", "<esi:include src=\"c\"/>")
+        .with_body("https://example.com/dir/c", "c");
+    let mut variables = Variables::new();
+    variables.set_document_url("https://example.com/dir/page?q=1");
+    let body = "<esi:include src=\"/a\" alt=\"b\" onerror=\"continue\"/>\
+                <esi:include src=\"../d\" onerror=\"continue\"/>\
+                <esi:include src=\"//cdn.example.com/e\" onerror=\"continue\"/>\
+                <esi:include src=\"http://other.example/f\" onerror=\"continue\"/>\
+                <esi:include src=\"nested\" onerror=\"continue\"/>";
+
+    let config = Configuration::default().with_recursion(2);
+    let output = transform_esi_string_with_config(body.as_bytes(), &client, &variables, &config).unwrap();
+    assert_eq!(output, b"c");
+    assert_eq!(
+        client.requested_urls(),
+        [
+            "https://example.com/a",
+            "https://example.com/dir/b",
+            "https://example.com/d",
+            "https://cdn.example.com/e",
+            "http://other.example/f",
+            "https://example.com/dir/nested",
+            // The includes of fragments are resolved against the document URL as well
+            "https://example.com/dir/c",
+        ]
+    );
+
+    // Without a document URL, URLs are requested as they are
+    client.clear_requests();
+    let output = transform_esi_string_with_config(body.as_bytes(), &client, &Variables::new(), &config).unwrap();
+    assert_eq!(output, b"");
+    assert_eq!(client.requested_urls(), ["/a", "b", "../d", "//cdn.example.com/e", "http://other.example/f", "nested"]);
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use common::process;
+use esi::{
+    test::MockExecutionContext, transform_esi_string_with_config, Configuration, ExecutionError, HeaderForwarding, Variables,
+};
+
+#[test]
+fn requests_to_hosts_that_are_not_allowed_are_not_sent() {
+    let client = MockExecutionContext::new()
+        .with_body("http://www.example.com/a", "a")
+        .with_body("http://WWW.example.com:8080/a", "a")
+        .with_body("http://example.com/b", "b")
+        .with_body("http://169.254.169.254/latest/meta-data", "secret");
+    let config = Configuration::default().with_allowed_hosts(["*.example.com"]);
+
+    assert_eq!(process("<esi:include src=\"http://WWW.example.com:8080/a\"/>", &client, &config).unwrap(), "a");
+
+    for src in [
+        "http://example.com/b",
+        "http://169.254.169.254/latest/meta-data",
+        "/relative",
+        // WHATWG URL parsers treat `\` as a path delimiter, and request these from 169.254.169.254
+        "http://169.254.169.254\\@www.example.com/latest",
+        "http://169.254.169.254\\.www.example.com/",
+    ] {
+        let err = process(&format!("<esi:include src=\"{}\"/>", src), &client, &config).unwrap_err();
+        assert!(matches!(err, ExecutionError::FragmentFailed { source, .. } if matches!(*source, ExecutionError::UrlNotAllowed(_))));
+    }
+    let body = "<esi:include src=\"http://169.254.169.254/latest/meta-data\" alt=\"http://www.example.com/a\"/>";
+    assert_eq!(process(body, &client, &config).unwrap(), "a");
+    assert_eq!(client.requested_urls(), ["http://WWW.example.com:8080/a", "http://www.example.com/a"]);
+}
+
+#[test]
+fn tags_and_methods_that_are_not_allowed_are_not_honored() {
+    let client = MockExecutionContext::new().with_body("/a", "a").with_body("/eval", "<esi:assign name=\"x\" value=\"1\"/>");
+    let config = Configuration::default()
+        .with_allowed_tags(["include", "remove"])
+        .with_allowed_methods(["GET"]);
+
+    let body = "<esi:include src=\"/a\"/><esi:remove>r</esi:remove><esi:eval src=\"/eval\"/>";
+    assert_eq!(process(body, &client, &config).unwrap(), "a");
+    let body = "<esi:include src=\"/a\" method=\"POST\" onerror=\"continue\"/>";
+    assert_eq!(process(body, &client, &config).unwrap(), "");
+    assert_eq!(client.requested_urls(), ["/a"]);
+}
+
+#[test]
+fn only_forwarded_headers_are_copied_onto_fragment_requests() {
+    let client = MockExecutionContext::new().with_body("/a", "a");
+    let variables = Variables::from_request(
+        vec![("Cookie", "id=1"), ("Authorization", "Bearer secret"), ("Accept-Language", "en"), ("X-Debug", "1")],
+        None,
+    );
+    let headers = |config: &Configuration| {
+        client.clear_requests();
+        let body = "<esi:include src=\"/a\" headers=\"X-Fragment=1\"/>";
+        transform_esi_string_with_config(body.as_bytes(), &client, &variables, config).unwrap();
+        let requests = client.requests();
+        requests[0].headers.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>()
+    };
+
+    assert_eq!(headers(&Configuration::default()), ["Cookie", "Accept-Language", "X-Debug", "X-Fragment"]);
+    let only = HeaderForwarding::Only(vec!["cookie".to_string(), "accept-language".to_string()]);
+    let config = Configuration::default().with_header_forwarding(only);
+    assert_eq!(headers(&config), ["Cookie", "Accept-Language", "X-Fragment"]);
+    let config = Configuration::default().with_header_forwarding(HeaderForwarding::None);
+    assert_eq!(headers(&config), ["X-Fragment"]);
+}
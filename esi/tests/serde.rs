@@ -0,0 +1,45 @@
+#![cfg(feature = "serde")]
+
+use esi::{Configuration, ExecutionContext, Request, Response, TagEntry, Template, Variables};
+
+struct Fragments;
+
+impl ExecutionContext for Fragments {
+    fn send_request(&self, req: Request) -> esi::Result<Response> {
+        Ok(Response::new(200, format!("[{}]", req.url)))
+    }
+}
+
+#[test]
+fn configuration_is_loaded_from_json() {
+    let config: Configuration = serde_json::from_str(
+        r#"{ "conformance": "strict", "limits": { "max_tags": 1 }, "default_timeout": { "secs": 2, "nanos": 0 } }"#,
+    )
+    .unwrap();
+
+    let template = Template::compile_with_config("<esi:include src=\"/a\"/>".as_bytes(), &config).unwrap();
+    assert_eq!(template.execute(&Fragments, &Variables::new()).unwrap(), b"[/a]");
+    let two_tags = "<esi:include src=\"/a\"/><esi:include src=\"/b\"/>";
+    assert!(Template::compile_with_config(two_tags.as_bytes(), &config).is_err());
+    assert!(Template::compile_with_config("<esi:bogus/>".as_bytes(), &config).is_err());
+
+    // Options that weren't set keep their defaults
+    let defaults: Configuration = serde_json::from_str("{}").unwrap();
+    assert_eq!(serde_json::to_value(&defaults).unwrap(), serde_json::to_value(Configuration::default()).unwrap());
+}
+
+#[test]
+fn parsed_documents_round_trip() {
+    let source = "<p \u{e9}><esi:include src=\"/a\" alt=\"/b\">fallback</esi:include><esi:choose>\
+        <esi:when test=\"1 == 1\"><esi:vars>$(HTTP_HOST)</esi:vars></esi:when></esi:choose></p>";
+    let template = Template::compile(source.as_bytes()).unwrap();
+
+    let json = serde_json::to_string(template.entries()).unwrap();
+    assert!(json.contains(r#""attributes":{"alt":"/b","src":"/a"}"#), "{}", json);
+    let entries: Vec<TagEntry> = serde_json::from_str(&json).unwrap();
+
+    let variables = Variables::from_request(vec![("Host", "example.com")], None);
+    let expected = template.execute(&Fragments, &variables).unwrap();
+    let restored = Template::from_entries(entries, &Configuration::default());
+    assert_eq!(restored.execute(&Fragments, &variables).unwrap(), expected);
+}
@@ -0,0 +1,122 @@
+use esi::{test::MockExecutionContext, transform_esi_string_with_config, Configuration, Processor, Variables};
+
+const DOCUMENT: &str = r#"<html><esi:comment text="a comment"/>
+<esi:include src="/fragment?user=$(HTTP_COOKIE{user})" alt="/alt" onerror="continue"/>
+<esi:vars><p title="$(HTTP_COOKIE{user})">Hello, $(HTTP_COOKIE{user})!</p></esi:vars>
+<!--esi <esi:include src="/b"/> -->
+<esi:remove><esi:include src="/removed"/></esi:remove>
+<esi:try><esi:attempt><esi:include src="/missing"/></esi:attempt><esi:except>except</esi:except></esi:try>
+</html>"#;
+
+// Isn't well-formed XML, so it can only be parsed as HTML
+const HTML_DOCUMENT: &str = r#"<!doctype html><p class=intro>Unclosed<br>
+<script>if (a < b && c) {}</script>
+<esi:include src="/fragment?user=$(HTTP_COOKIE{user})" onerror="continue"/>
+<!--esi <esi:vars>$(HTTP_COOKIE{user})</esi:vars> --><!-- an HTML comment -->
+"#;
+
+fn client() -> MockExecutionContext {
+    MockExecutionContext::new()
+        .with_body("/fragment?user=jane", "<b>jane's fragment</b>")
+        .with_body("/alt", "alt")
+        .with_body("/b", "b")
+        .with_body("/nested", "<esi:include src=\"/b\"/>")
+}
+
+fn variables() -> Variables {
+    Variables::from_request(vec![("Cookie", "user=jane")], None)
+}
+
+// Processes the document in one go.
+fn process(body: &str, config: &Configuration) -> String {
+    let output = transform_esi_string_with_config(body.as_bytes(), &client(), &variables(), config).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+// Processes the document with a `Processor`, feeding it the given chunks.
+fn stream<'a>(chunks: impl IntoIterator<Item = &'a [u8]>, config: &Configuration) -> String {
+    let client = client();
+    let mut processor = Processor::new(&client, &variables(), config, Vec::new());
+    for chunk in chunks {
+        processor.feed(chunk).unwrap();
+    }
+    String::from_utf8(processor.finish().unwrap()).unwrap()
+}
+
+// Checks that the document is processed the same way however its input is split, one byte at a
+// time or in two chunks split at any point, such as within a tag, an attribute or a variable
+// reference.
+fn check_splits(body: &str, config: &Configuration) {
+    let expected = process(body, config);
+    assert_eq!(stream(body.as_bytes().chunks(1), config), expected);
+
+    let body = body.as_bytes();
+    for split in 0..=body.len() {
+        let (first, second) = body.split_at(split);
+        assert_eq!(stream([first, second], config), expected, "split at {}", split);
+    }
+}
+
+#[test]
+fn documents_are_processed_the_same_however_their_input_is_split() {
+    let config = Configuration::default();
+    check_splits(DOCUMENT, &config);
+
+    #[cfg(feature = "variables")]
+    assert_eq!(
+        process(DOCUMENT, &config),
+        "<html>\n<b>jane's fragment</b>\n<p title=\"jane\">Hello, jane!</p>\n b \n\nexcept\n</html>"
+    );
+}
+
+#[test]
+fn html_documents_are_processed_the_same_however_their_input_is_split() {
+    let config = Configuration::default().with_html_parsing();
+    check_splits(DOCUMENT, &config);
+    check_splits(HTML_DOCUMENT, &config);
+
+    #[cfg(feature = "variables")]
+    assert_eq!(
+        process(HTML_DOCUMENT, &config),
+        "<!doctype html><p class=intro>Unclosed<br>\n\
+         <script>if (a < b && c) {}</script>\n\
+         <b>jane's fragment</b>\n jane <!-- an HTML comment -->\n"
+    );
+}
+
+#[test]
+fn tags_with_other_prefixes_are_processed_the_same_however_their_input_is_split() {
+    let body = "<html xmlns:e=\"http://www.edge-delivery.org/esi/1.0\">\
+                <myesi:include src=\"/b\"/><e:include src=\"/alt\"/><esi:include src=\"/b\"/></html>";
+    let config = Configuration::default().with_namespace_prefixes(["myesi"]);
+    check_splits(body, &config);
+    assert_eq!(
+        process(body, &config),
+        "<html xmlns:e=\"http://www.edge-delivery.org/esi/1.0\">balt<esi:include src=\"/b\"/></html>"
+    );
+
+    let body = "<p>Unclosed<myesi:include src=\"/b\"/>";
+    let config = config.with_html_parsing();
+    check_splits(body, &config);
+    assert_eq!(process(body, &config), "<p>Unclosedb");
+}
+
+#[test]
+fn escaped_fragments_are_streamed_the_same_however_their_input_is_split() {
+    let body = "<esi:include src=\"/fragment?user=jane\"/>\
+                <esi:include src=\"/fragment?user=jane\" escape=\"html\"/>\
+                <esi:include src=\"/nested\" escape=\"none\"/>";
+    let config = Configuration::default().with_recursion(2);
+    check_splits(body, &config);
+    assert_eq!(process(body, &config), "<b>jane's fragment</b>&lt;b&gt;jane&apos;s fragment&lt;/b&gt;b");
+
+    // Escaped fragments aren't processed as ESI
+    let config = config.with_fragment_escaping();
+    let body = format!("{}<esi:include src=\"/nested\"/>", body);
+    check_splits(&body, &config);
+    assert_eq!(
+        process(&body, &config),
+        "&lt;b&gt;jane&apos;s fragment&lt;/b&gt;&lt;b&gt;jane&apos;s fragment&lt;/b&gt;b\
+         &lt;esi:include src=&quot;/b&quot;/&gt;"
+    );
+}
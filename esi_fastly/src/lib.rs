@@ -1,57 +1,136 @@
-use std::str::FromStr;
+use std::{convert::TryFrom, io::Read, str::FromStr, time::Instant};
 
-use esi::{ExecutionContext, transform_esi_string, ExecutionError};
-use fastly::{Request, Response, http::{Url, header}};
+use esi::{surrogate::SurrogateControl, transform_esi_string_with_headers, Configuration, ExecutionContext, ExecutionError, PendingRequest, Processor, Variables};
+use fastly::{Request, Response, handle::{BodyHandle, PendingRequestHandle}, http::{Method, Url, header}};
 
-/// A request handler that, given a `fastly::Request`, will route requests to a backend matching
-/// the hostname of the request URL.
-pub struct FastlyRequestHandler {
-    original_req: Request
-}
-
-impl FastlyRequestHandler {
-    fn from_request(req: Request) -> FastlyRequestHandler {
-        FastlyRequestHandler {
-            original_req: req
-        }
-    }
-}
+/// A request handler that will route requests to a backend matching the hostname of the request
+/// URL. Requests are sent with only the headers of the `esi::Request`, which include the headers
+/// of the client request that the configured `esi::HeaderForwarding` forwards.
+pub struct FastlyRequestHandler;
 
 impl ExecutionContext for FastlyRequestHandler {
     fn send_request(&self, req: esi::Request) -> Result<esi::Response, ExecutionError> {
+        self.send_request_async(req).wait()
+    }
+
+    fn send_request_async(&self, req: esi::Request) -> PendingRequest<'_> {
         println!("Sending request: {:?}", req);
 
-        let mut bereq = self.original_req.clone_without_body().with_url(&req.url);
+        let (bereq, backend) = match self.backend_request(&req) {
+            Ok(request) => request,
+            Err(err) => return PendingRequest::ready(Err(err)),
+        };
+
+        // Requests are sent through their handles, so that they can be waited for with a timeout
+        let (req_handle, body) = bereq.into_handles();
+        let pending = match req_handle.send_async(body.unwrap_or_else(BodyHandle::new), backend.as_str()) {
+            Ok(pending) => pending,
+            Err(err) => return PendingRequest::ready(Err(ExecutionError::request(req.url, err))),
+        };
+
+        PendingRequest::new(move || {
+            if req.deadline.is_some_and(|deadline| !ready_before(&pending, deadline)) {
+                return Err(ExecutionError::Timeout(req.url));
+            }
+
+            let mut beresp = match pending.wait() {
+                Ok((resp_handle, body_handle)) => match Response::from_handles(resp_handle, body_handle) {
+                    Ok(resp) => resp,
+                    Err(err) => return Err(ExecutionError::request(req.url, err)),
+                },
+                Err(err) => return Err(ExecutionError::request(req.url, err)),
+            };
+
+            println!("Received response: {}", beresp.get_status().as_u16());
+
+            if beresp.get_status().as_u16() < 200 || beresp.get_status().as_u16() > 299 {
+                return Err(ExecutionError::UnexpectedStatus(req.url, beresp.get_status().as_u16()));
+            }
 
+            let headers = beresp
+                .get_headers()
+                .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+                .collect();
+
+            let resp = esi::Response {
+                body: beresp.take_body_bytes(),
+                status_code: beresp.get_status().as_u16(),
+                headers
+            };
+
+            println!("Response passed to esi processor");
+            Ok(resp)
+        })
+    }
+}
+
+impl FastlyRequestHandler {
+    // Builds the backend request for an ESI request, along with the name of the backend to send it
+    // to.
+    fn backend_request(&self, req: &esi::Request) -> Result<(Request, String), ExecutionError> {
+        let url = match Url::from_str(&req.url) {
+            Ok(url) => url,
+            Err(err) => return Err(ExecutionError::request(&req.url, err)),
+        };
         // assume that backend name == host
-        let parsed_url = Url::from_str(&req.url).unwrap();
-        let backend = parsed_url.host_str().unwrap();
-        bereq.set_header(header::HOST, backend);
+        let backend = match url.host_str() {
+            Some(host) => host.to_string(),
+            None => return Err(ExecutionError::RequestFailed(format!("fragment url `{}` has no host", req.url))),
+        };
 
-        let mut beresp = match bereq.send(backend) {
-            Ok(resp) => resp,
-            Err(_) => panic!("Error sending ESI include request to backend {}", backend)
+        let mut bereq = match Method::from_bytes(req.method.as_bytes()) {
+            Ok(method) => Request::new(method, url),
+            Err(err) => return Err(ExecutionError::request(&req.url, err)),
         };
+        if let Some(body) = &req.body {
+            bereq.set_body(body.as_slice());
+        }
+        for (name, value) in &req.headers {
+            bereq.append_header(name.as_str(), value.as_str());
+        }
 
-        println!("Received response: {}", beresp.get_status().as_u16());
+        bereq.set_header(header::HOST, backend.as_str());
 
-        if beresp.get_status().as_u16() < 200 || beresp.get_status().as_u16() > 299 {
-            // TODO: handle bad status codes
-            return Err(ExecutionError::Unknown);
-        }
+        Ok((bereq, backend))
+    }
+}
 
-        let resp = esi::Response {
-            body: beresp.take_body_bytes(),
-            status_code: beresp.get_status().as_u16()
-        };
+// Blocks until the response to a request is ready or the deadline passes, returning whether the
+// response is ready. The host wakes the instance up for whichever happens first, so the request
+// isn't busy-polled. If the host can't wait for it, the response is waited for without a timeout.
+fn ready_before(pending: &PendingRequestHandle, deadline: Instant) -> bool {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    // A timeout of zero waits indefinitely
+    let timeout_ms = u32::try_from(remaining.as_millis()).unwrap_or(u32::MAX).max(1);
+    let mut done_index = u32::MAX;
+    // SAFETY: the handle is a live pending request, and `done_index` outlives the call
+    let status = unsafe { fastly_sys::fastly_async_io::select(&pending.as_u32(), 1, timeout_ms, &mut done_index) };
+    status.is_err() || done_index != u32::MAX
+}
+
+/// Builds the standard ESI variables from the headers and query string of a `fastly::Request`,
+/// along with the URL of the document. The headers are kept to be forwarded to fragment requests.
+pub fn build_variables(req: &Request) -> Variables {
+    let headers = req.get_header_names_str().into_iter().flat_map(|name| {
+        req.get_header_all_str(name).into_iter().map(move |value| (name, value))
+    });
+
+    let mut variables = Variables::from_request(headers, req.get_query_str());
+    variables.set_document_url(req.get_url_str());
+    variables
+}
 
-        println!("Response passed to esi processor");
-        Ok(resp)
+/// Adds the `Surrogate-Capability` header for the configured device token to a request before it
+/// is sent to the origin, so that the origin knows ESI will be processed. Does nothing if no device
+/// token is configured.
+pub fn advertise_surrogate_capability(req: &mut Request, config: &Configuration) {
+    if let Some(capability) = config.surrogate_capability_header(req.get_header_str("surrogate-capability")) {
+        req.set_header("surrogate-capability", capability);
     }
 }
 
 /// Processes the body of a `fastly::Response` and returns an updated Response after executing
-/// all found ESI instructions.
+/// all found ESI instructions. ESI variables are populated from the given `fastly::Request`.
 ///
 /// # Examples
 /// ```no_run
@@ -60,17 +139,101 @@ impl ExecutionContext for FastlyRequestHandler {
 ///
 /// #[fastly::main]
 /// fn main(req: Request) -> Result<Response, Error> {
-///     let beresp = req.send("backend")?;
-///     process_esi(req, beresp);
+///     let beresp = req.clone_without_body().send("backend")?;
+///     process_esi(req, beresp)
 /// }
 /// ```
-pub fn process_esi(req: Request, mut response: Response) -> Result<Response, fastly::Error> {
-    let req_handler = FastlyRequestHandler::from_request(req);
+pub fn process_esi(req: Request, response: Response) -> Result<Response, fastly::Error> {
+    process_esi_with_config(req, response, &Configuration::default())
+}
+
+/// Processes the body of a `fastly::Response` using the given `esi::Configuration`.
+///
+/// The response is returned untouched if the configuration requires a `Surrogate-Control` header
+/// that the response doesn't have, or if processing is delegated to a downstream surrogate that
+/// advertised ESI capability on the request. Otherwise, the `ESI/1.0` content token is removed from the
+/// response's `Surrogate-Control` header once processing is complete. The fragment headers named by
+/// `Configuration::with_aggregated_headers` are added to the response, alongside its own.
+pub fn process_esi_with_config(
+    req: Request,
+    mut response: Response,
+    config: &Configuration,
+) -> Result<Response, fastly::Error> {
+    if !should_process(&req, &response, config) {
+        return Ok(response);
+    }
+
+    let variables = build_variables(&req);
+    let req_handler = FastlyRequestHandler;
 
-    match transform_esi_string(response.take_body(), &req_handler) {
-        Ok(body) => response.set_body(body),
+    match transform_esi_string_with_headers(response.take_body(), &req_handler, &variables, config) {
+        Ok((body, headers)) => {
+            response.set_body(body);
+            for (name, value) in headers {
+                response.append_header(name, value);
+            }
+        }
         Err(err) => return Err(fastly::Error::from(err)),
     }
 
+    remove_esi_content_token(&mut response);
+
     Ok(response)
 }
+
+/// Processes the body of a `fastly::Response` using the given `esi::Configuration`, streaming the
+/// result to the client as it is produced. The body is processed as it arrives from the backend,
+/// and content preceding each include is sent before the fragment is requested, which reduces the
+/// time to first byte for large documents. See `esi::Processor` for the limitations of streamed
+/// processing.
+///
+/// As the response headers have already been sent by the time an error occurs, errors result in
+/// a truncated response body.
+pub fn stream_esi_with_config(req: Request, mut response: Response, config: &Configuration) -> Result<(), fastly::Error> {
+    if !should_process(&req, &response, config) {
+        response.send_to_client();
+        return Ok(());
+    }
+
+    let variables = build_variables(&req);
+    let req_handler = FastlyRequestHandler;
+
+    let mut body = response.take_body();
+    remove_esi_content_token(&mut response);
+    response.remove_header(header::CONTENT_LENGTH);
+
+    let mut processor = Processor::new(&req_handler, &variables, config, response.stream_to_client());
+    let mut chunk = [0; 8192];
+    loop {
+        match body.read(&mut chunk)? {
+            0 => break,
+            len => processor.feed(&chunk[..len])?,
+        }
+    }
+    processor.finish()?;
+
+    Ok(())
+}
+
+// Returns whether the response should be processed, according to its `Surrogate-Control` header
+// and any `Surrogate-Capability` advertised on the request.
+fn should_process(req: &Request, response: &Response, config: &Configuration) -> bool {
+    config.should_process(response.get_header_str("surrogate-control"))
+        && !config.should_delegate(req.get_header_str("surrogate-capability"))
+}
+
+// Removes the `ESI/1.0` content token from the response's `Surrogate-Control` header, removing the
+// header entirely if nothing else remains.
+fn remove_esi_content_token(response: &mut Response) {
+    let surrogate_control = match response.get_header_str("surrogate-control") {
+        Some(surrogate_control) => SurrogateControl::parse(surrogate_control),
+        None => return,
+    };
+
+    match surrogate_control.without_esi(None) {
+        Some(header) => response.set_header("surrogate-control", header),
+        None => {
+            response.remove_header("surrogate-control");
+        }
+    }
+}